@@ -1,16 +1,27 @@
 //! Hooks into finding and running command-line applications
 
-use crate::{Defaults, PlatformId, Repository, VariationId};
+use crate::interrupt::status_interruptible;
+use crate::util::{available_space, display_command, run_logged, run_stage};
+use crate::{
+    interrupted, ContainerRuntime, Defaults, MountLabel, PlatformChoice, PlatformId, Repository,
+    S4Error, Stage, VariationId,
+};
 use anyhow::{bail, format_err, Result};
-use reqwest::blocking::get;
+use reqwest::blocking::Client;
+use std::cell::RefCell;
 use std::collections::{BTreeMap, BTreeSet};
 use std::env::{current_dir, var};
 use std::ffi::OsStr;
-use std::fs::OpenOptions;
-use std::io::copy;
+use std::fmt;
+use std::fs::{create_dir_all, remove_file, rename, OpenOptions};
+use std::io::{copy, stdin, IsTerminal};
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus, Stdio};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
 use users::{get_current_username, get_effective_gid, get_effective_uid};
 
 /// Wrapper around command line apps called by s4
@@ -24,17 +35,19 @@ pub struct Apps<'d> {
     docker_impl: DockerImpl,
     /// Path to mq.sh
     machine_queue: Option<PathBuf>,
+    /// Keep containers that exit unsuccessfully
+    keep_on_failure: bool,
+    /// Print commands that would change anything rather than running them
+    dry_run: bool,
+    /// Containers run without automatic removal that have not yet been cleaned up
+    kept_containers: RefCell<Vec<String>>,
 }
 
 impl<'d> Apps<'d> {
     /// Try and find all dependent apps
     pub fn try_new(defaults: &'d Defaults) -> Result<Self> {
-        let repo = find_or_download("repo", defaults.repo_url())?;
-        let docker = find_app_path("podman")
-            .or(find_app_path("docker"))
-            .ok_or(format_err!(
-                "podman, docker, or podman-docker must be installed"
-            ))?;
+        let repo = find_or_download(defaults, "repo", defaults.repo_url())?;
+        let docker = find_container_runtime(defaults.container_runtime(), &var("PATH")?)?;
 
         let docker_version = Command::new(&docker).arg("--version").output()?.stdout;
         let docker_version = String::from_utf8(docker_version)?;
@@ -52,16 +65,286 @@ impl<'d> Apps<'d> {
             docker,
             docker_impl,
             machine_queue,
+            keep_on_failure: false,
+            dry_run: false,
+            kept_containers: RefCell::default(),
         })
     }
 
+    /// Apps using fixed paths that do not need to exist
+    #[cfg(test)]
+    pub(crate) fn mock(defaults: &'d Defaults, docker_impl: DockerImpl) -> Self {
+        Apps {
+            defaults,
+            repo: "repo".into(),
+            docker: docker_impl.to_string().into(),
+            docker_impl,
+            machine_queue: Some("mq.sh".into()),
+            keep_on_failure: false,
+            dry_run: false,
+            kept_containers: RefCell::default(),
+        }
+    }
+
+    /// Use a different machine queue script
+    #[cfg(test)]
+    pub(crate) fn set_machine_queue(&mut self, machine_queue: impl Into<PathBuf>) {
+        self.machine_queue = Some(machine_queue.into());
+    }
+
+    /// Keep containers that fail for debugging rather than removing them
+    pub fn set_keep_on_failure(&mut self, keep: bool) {
+        self.keep_on_failure = keep;
+    }
+
+    /// Print the commands that would be run instead of running them
+    ///
+    /// Commands that only query state (such as listing machine queue systems) are still run.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Whether commands are only being printed
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Print a command before it is run
+    ///
+    /// Nothing is printed in a dry run as [`Apps::status`] prints the command instead.
+    pub fn echo(&self, command: &Command) {
+        if !self.dry_run {
+            println!("{}", display_command(command));
+        }
+    }
+
+    /// Run a command to completion, or only print it in a dry run
+    ///
+    /// If an interrupt is received, the command is terminated and an error is returned. Containers
+    /// kept for debugging are handled as for `run_stage`.
+    pub fn status(&self, command: &mut Command) -> Result<ExitStatus> {
+        if self.dry_run {
+            self.kept_containers.take();
+            println!("{}", display_command(command));
+            return Ok(ExitStatus::from_raw(0));
+        }
+        let status = status_interruptible(command);
+        self.finish_kept_containers(status.as_ref().is_ok_and(ExitStatus::success))?;
+        status
+    }
+
+    /// Run a command to completion, appending its output to a log, or only print it in a dry run
+    ///
+    /// Interrupts and containers kept for debugging are handled as for `status`.
+    pub fn status_logged(&self, command: &mut Command, log: &Path) -> Result<ExitStatus> {
+        if self.dry_run {
+            self.kept_containers.take();
+            println!("{}", display_command(command));
+            return Ok(ExitStatus::from_raw(0));
+        }
+        let status = run_logged(command, log);
+        self.finish_kept_containers(status.as_ref().is_ok_and(ExitStatus::success))?;
+        status
+    }
+
+    /// Run a command to completion even after an interrupt, or only print it in a dry run
+    ///
+    /// This is used to clean up after other commands.
+    fn cleanup_status(&self, command: &mut Command) -> Result<ExitStatus> {
+        if self.dry_run {
+            println!("{}", display_command(command));
+            return Ok(ExitStatus::from_raw(0));
+        }
+        Ok(command.status()?)
+    }
+
+    /// Stop and remove containers
+    fn discard_containers(&self, containers: Vec<String>) -> Result<()> {
+        for container in containers {
+            self.stop_container(&container)?;
+            self.remove_container(&container)?;
+        }
+        Ok(())
+    }
+
+    /// Run a containerised command for a stage of a build
+    ///
+    /// Containers kept for debugging are removed if the stage succeeds or is interrupted.
+    pub fn run_stage(&self, stage: Stage, command: &mut Command) -> Result<()> {
+        if self.dry_run {
+            self.kept_containers.take();
+            println!("{}", display_command(command));
+            return Ok(());
+        }
+
+        let result = run_stage(stage, command);
+        self.finish_kept_containers(result.is_ok())?;
+        result
+    }
+
+    /// Remove or report the containers kept for debugging once the command using them has exited
+    ///
+    /// Containers are removed if the command succeeded or was interrupted; otherwise they are kept
+    /// and the user is told how to remove them.
+    fn finish_kept_containers(&self, succeeded: bool) -> Result<()> {
+        let kept = self.kept_containers.take();
+
+        if interrupted() {
+            self.discard_containers(kept)?;
+        } else if succeeded {
+            for container in kept {
+                self.remove_container(&container)?;
+            }
+        } else {
+            for container in kept {
+                eprintln!(
+                    "Container {} kept for debugging; remove it with `{} rm {}`",
+                    container, self.docker_impl, container
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the docker image provides the tools needed to configure and build
+    pub fn check_image_tools(&self) -> Result<()> {
+        let script = format!(
+            "for tool in {}; do command -v $tool >/dev/null || echo $tool; done",
+            REQUIRED_IMAGE_TOOLS.join(" ")
+        );
+        let output = self
+            .docker()?
+            .keep_on_failure(false)
+            .tty(false)
+            .run("sh")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "Could not run docker image {} to check for build tools",
+                self.defaults.docker_image()
+            );
+        }
+        check_missing_tools(
+            self.defaults.docker_image(),
+            &String::from_utf8(output.stdout)?,
+        )
+    }
+
+    /// Find the digest of the docker image as pulled on this machine (`image@sha256:...`)
+    ///
+    /// Returns `None` if the image has not been pulled or has no registry digest (such as an image
+    /// that was built locally).
+    pub fn resolve_image_digest(&self) -> Result<Option<String>> {
+        let output = Command::new(&self.docker)
+            .arg("image")
+            .arg("inspect")
+            .arg("--format")
+            .arg("{{index .RepoDigests 0}}")
+            .arg(self.defaults.docker_image())
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let digest = String::from_utf8(output.stdout)?.trim().to_owned();
+        Ok(Some(digest).filter(|digest| !digest.is_empty()))
+    }
+
+    /// Stop a running container
+    pub fn stop_container(&self, container: &str) -> Result<()> {
+        let status = Command::new(&self.docker)
+            .arg("stop")
+            .arg(container)
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            bail!("Failed to stop container {}", container);
+        }
+        Ok(())
+    }
+
+    /// Remove a stopped container
+    pub fn remove_container(&self, container: &str) -> Result<()> {
+        let status = Command::new(&self.docker)
+            .arg("rm")
+            .arg(container)
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            bail!("Failed to remove container {}", container);
+        }
+        Ok(())
+    }
+
+    /// Default configuration used by the apps
+    pub fn defaults(&self) -> &Defaults {
+        self.defaults
+    }
+
+    /// Warn if the disk holding a directory has less free space than `min-free-space`
+    pub fn check_free_space(&self, path: &Path) -> Result<()> {
+        if let Some(warning) = self.free_space_warning(path)? {
+            eprintln!("Warning: {}", warning);
+        }
+        Ok(())
+    }
+
+    fn free_space_warning(&self, path: &Path) -> Result<Option<String>> {
+        let required = match self.defaults.min_free_space() {
+            Some(required) => required,
+            None => return Ok(None),
+        };
+        let available = available_space(path)
+            .map_err(|e| format_err!("Could not check free space of {}: {}", path.display(), e))?;
+        if available >= required {
+            return Ok(None);
+        }
+
+        const GIB: f64 = (1024 * 1024 * 1024) as f64;
+        Ok(Some(format!(
+            "only {:.1} GiB is free for {} (min-free-space is {:.0} GiB); running out of disk space causes confusing errors",
+            available as f64 / GIB,
+            path.display(),
+            required as f64 / GIB
+        )))
+    }
+
     /// Create an invocation of the repo command
     pub fn repo(&self) -> Command {
         Command::new(&self.repo)
     }
 
+    /// Create a new invocation of the repo sync command
+    pub fn repo_sync(&self) -> Command {
+        let mut repo = self.repo();
+        repo.arg("sync");
+        repo
+    }
+
     /// Create a new invocation of the repo init command
-    pub fn repo_init(&self, project: &Repository) -> Result<ExitStatus> {
+    ///
+    /// If `groups` is empty, the default groups of the manifest are synced.
+    pub fn repo_init(
+        &self,
+        project: &Repository,
+        groups: &[String],
+        extra_args: &[String],
+    ) -> Result<ExitStatus> {
+        self.status(&mut self.repo_init_command(project, groups, extra_args))
+    }
+
+    fn repo_init_command(
+        &self,
+        project: &Repository,
+        groups: &[String],
+        extra_args: &[String],
+    ) -> Command {
         let mut repo = self.repo();
 
         let url = self.defaults.git_repo_url(project);
@@ -77,7 +360,32 @@ impl<'d> Apps<'d> {
             repo.arg("--manifest-name").arg(manifest);
         }
 
-        Ok(repo.status()?)
+        if !groups.is_empty() {
+            repo.arg("-g").arg(groups.join(","));
+        }
+
+        repo.args(extra_args);
+        repo
+    }
+
+    /// Check that extra repo init arguments do not set any of the options managed by s4
+    pub fn check_repo_init_args(extra_args: &[String]) -> Result<()> {
+        for arg in extra_args {
+            let managed = REPO_INIT_MANAGED_ARGS.iter().any(|option| {
+                if option.starts_with("--") {
+                    arg == option || arg.starts_with(&format!("{}=", option))
+                } else {
+                    arg.starts_with(option)
+                }
+            });
+            if managed {
+                bail!(
+                    "The repo init argument {} is set by s4; use repo-branch, repo-manifest, or repo-groups instead",
+                    arg
+                );
+            }
+        }
+        Ok(())
     }
 
     /// Create an invocation of the docker command
@@ -90,6 +398,21 @@ impl<'d> Apps<'d> {
         self.docker_impl
     }
 
+    /// Path to the repo executable
+    pub fn repo_path(&self) -> &Path {
+        &self.repo
+    }
+
+    /// Path to the docker (or podman) executable
+    pub fn docker_path(&self) -> &Path {
+        &self.docker
+    }
+
+    /// Path to mq.sh (if available)
+    pub fn machine_queue_path(&self) -> Option<&Path> {
+        self.machine_queue.as_deref()
+    }
+
     pub fn machine_queue_available(&self) -> bool {
         self.machine_queue.is_some()
     }
@@ -107,6 +430,69 @@ impl<'d> Apps<'d> {
         Ok(command)
     }
 
+    /// Lock key used to reserve machine queue systems
+    fn machine_queue_lock_key(&self) -> String {
+        let username = get_current_username()
+            .and_then(|username| username.into_string().ok())
+            .unwrap_or_else(|| get_effective_uid().to_string());
+        format!("s4-{}", username)
+    }
+
+    fn machine_queue_acquire_command(&self, system: &str) -> Result<Command> {
+        let mut command = self.machine_queue()?;
+        command.args(["sem", "-wait", system, "-k"]);
+        command.arg(self.machine_queue_lock_key());
+        Ok(command)
+    }
+
+    fn machine_queue_release_command(&self, system: &str) -> Result<Command> {
+        let mut command = self.machine_queue()?;
+        command.args(["sem", "-signal", system, "-k"]);
+        command.arg(self.machine_queue_lock_key());
+        Ok(command)
+    }
+
+    fn machine_queue_console_command(&self, system: &str) -> Result<Command> {
+        let mut command = self.machine_queue()?;
+        command.args(["console", "-s", system]);
+        Ok(command)
+    }
+
+    /// Reserve a system in the machine queue, waiting until it is available
+    pub fn machine_queue_acquire(&self, system: &str) -> Result<()> {
+        if !self
+            .status(&mut self.machine_queue_acquire_command(system)?)?
+            .success()
+        {
+            bail!("Failed to reserve system {}", system);
+        }
+        Ok(())
+    }
+
+    /// Release a system reserved in the machine queue
+    pub fn machine_queue_release(&self, system: &str) -> Result<()> {
+        if !self
+            .cleanup_status(&mut self.machine_queue_release_command(system)?)?
+            .success()
+        {
+            bail!("Failed to release system {}", system);
+        }
+        Ok(())
+    }
+
+    /// Reserve a system and attach to its serial console, releasing it when the console exits
+    pub fn machine_queue_console(&self, system: &str) -> Result<()> {
+        self.machine_queue_acquire(system)?;
+        let console = self
+            .machine_queue_console_command(system)
+            .and_then(|mut command| self.status(&mut command));
+        self.machine_queue_release(system)?;
+        if !console?.success() {
+            bail!("Console for system {} exited unsuccessfully", system);
+        }
+        Ok(())
+    }
+
     /// Get the systems from the machine queue
     pub fn machine_queue_systems(
         &self,
@@ -115,32 +501,36 @@ impl<'d> Apps<'d> {
         command.stdout(Stdio::piped());
         command.stdin(Stdio::null());
         let stdout = String::from_utf8(command.arg("system-tsv").output()?.stdout)?;
+        parse_system_tsv(&stdout)
+    }
 
-        let mut lines = stdout.split('\n');
-        let headings = lines
-            .next()
-            .ok_or(format_err!("Invalid output from mq.sh systems list"))?;
-
-        let mut systems = BTreeMap::new();
-
-        for line in lines {
-            let fields = headings
-                .split('\t')
-                .zip(line.split('\t'))
-                .collect::<BTreeMap<_, _>>();
-
-            if let (Some(name), Some(plat)) = (fields.get("name"), fields.get("sel4_plat")) {
-                let (platform, variation) = if let Some(index) = plat.find(':') {
-                    (plat[..index].into(), Some(plat[index + 1..].into()))
-                } else {
-                    ((*plat).into(), None)
-                };
-
-                systems.insert((*name).into(), (platform, variation));
+    /// Choose the platform of a build that will run on a system
+    ///
+    /// An explicit choice is checked against the platform of the system; otherwise the platform
+    /// and variation of the system are used.
+    pub fn machine_queue_platform(
+        &self,
+        system: &str,
+        explicit: Option<PlatformChoice>,
+    ) -> Result<PlatformChoice> {
+        let systems = self.machine_queue_systems()?;
+        match explicit {
+            Some(choice) => {
+                check_system_platform(&systems, system, choice.platform(), choice.variation())?;
+                Ok(choice)
             }
+            None => system_platform(&systems, system),
         }
+    }
 
-        Ok(systems)
+    /// Check that a system can run builds of a platform and variation
+    pub fn machine_queue_check_system(
+        &self,
+        system: &str,
+        platform: &PlatformId,
+        variation: Option<&VariationId>,
+    ) -> Result<()> {
+        check_system_platform(&self.machine_queue_systems()?, system, platform, variation)
     }
 
     pub fn machine_queue_pools(&self) -> Result<BTreeMap<String, BTreeSet<String>>> {
@@ -148,20 +538,7 @@ impl<'d> Apps<'d> {
         command.stdout(Stdio::piped());
         command.stdin(Stdio::null());
         let stdout = String::from_utf8(command.arg("pool-tsv").output()?.stdout)?;
-
-        let mut pools = BTreeMap::new();
-
-        for pool in stdout.trim().split("\n") {
-            let mut pool = pool.trim().split("\t");
-            if let Some(name) = pool.next() {
-                pools.insert(
-                    name.to_owned(),
-                    pool.map(|system| system.to_owned()).collect(),
-                );
-            }
-        }
-
-        Ok(pools)
+        Ok(parse_pool_tsv(&stdout))
     }
 
     pub fn machine_queue_match_system(
@@ -169,35 +546,27 @@ impl<'d> Apps<'d> {
         platform: &PlatformId,
         variation: Option<&VariationId>,
     ) -> Result<Vec<String>> {
-        let pools = self.machine_queue_pools()?;
-        let mut systems = Vec::new();
-        for (name, (sys_platform, sys_variation)) in self.machine_queue_systems()? {
-            if platform == &sys_platform
-                && (variation.is_none() || variation == sys_variation.as_ref())
-            {
-                systems.push(name);
-            }
-        }
-
-        let systems_set = systems.iter().cloned().collect();
-
-        for (name, _) in pools
-            .into_iter()
-            .filter(|(_, pool)| pool.is_subset(&systems_set))
-        {
-            systems.insert(0, name);
-        }
+        match_systems(
+            &self.machine_queue_systems()?,
+            self.machine_queue_pools()?,
+            platform,
+            variation,
+        )
+    }
+}
 
-        if systems.len() > 0 {
-            Ok(systems)
-        } else if let Some(variation) = variation {
-            bail!(
-                "No matching system found for {}:{}",
-                platform.as_ref(),
-                variation.as_ref()
-            );
-        } else {
-            bail!("No matching system found for {}", platform.as_ref());
+impl fmt::Display for Apps<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "docker: {} ({})",
+            self.docker.display(),
+            self.docker_impl
+        )?;
+        writeln!(f, "repo: {}", self.repo.display())?;
+        match &self.machine_queue {
+            Some(machine_queue) => write!(f, "mq.sh: {}", machine_queue.display()),
+            None => write!(f, "mq.sh: not found"),
         }
     }
 }
@@ -209,11 +578,12 @@ pub enum DockerImpl {
 }
 pub use DockerImpl::*;
 
-/// Find an app in the path or maybe download a copy of the script
-fn find_or_maybe_download(app: impl AsRef<Path>, url: Option<&str>) -> Result<Option<PathBuf>> {
-    match url {
-        Some(url) => find_or_download(app, url).map(Some),
-        None => Ok(find_app_path(app)),
+impl fmt::Display for DockerImpl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Docker => write!(f, "docker"),
+            Podman => write!(f, "podman"),
+        }
     }
 }
 
@@ -224,8 +594,23 @@ pub struct Docker<'a> {
     mounts: BTreeMap<PathBuf, PathBuf>,
     /// The path to the working directory relative to the host directory
     work_dir: PathBuf,
+    /// Keep the container after it exits
+    keep: bool,
+    /// Allocate a pseudo-TTY for the container
+    tty: bool,
+    /// Description included in the names of kept containers
+    description: Option<String>,
+    /// Environment variables to set in the container
+    env: BTreeMap<String, String>,
+    /// Environment variables forwarded from the host, whose values are kept out of the arguments
+    host_env: BTreeMap<String, String>,
+    /// SELinux label applied to mounted directories
+    mount_label: MountLabel,
 }
 
+/// Counter used to give kept containers unique names
+static CONTAINER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 impl<'a> Docker<'a> {
     const HOST_DIR: &'static str = "/host";
 
@@ -237,8 +622,18 @@ impl<'a> Docker<'a> {
             apps,
             mounts,
             work_dir: Self::HOST_DIR.into(),
+            keep: apps.keep_on_failure,
+            tty: stdin().is_terminal(),
+            description: None,
+            env: BTreeMap::new(),
+            host_env: BTreeMap::new(),
+            mount_label: apps.defaults.mount_label(),
         };
-        Ok(docker)
+        Ok(apps
+            .defaults
+            .docker_env()
+            .iter()
+            .fold(docker, |docker, name| docker.env_from_host(name)))
     }
 
     /// Set the host path for the command
@@ -260,14 +655,84 @@ impl<'a> Docker<'a> {
         Ok(self)
     }
 
+    /// Keep the container after it exits rather than removing it
+    pub fn keep_on_failure(mut self, keep: bool) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Set an environment variable in the container
+    pub fn env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        self.host_env.remove(&name);
+        self.env.insert(name, value.into());
+        self
+    }
+
+    /// Forward an environment variable from the host to the container (if it is set)
+    ///
+    /// Only the name is passed to docker as an argument; the value is passed through the
+    /// environment of the docker command so that it does not appear in process listings or
+    /// printed commands.
+    pub fn env_from_host(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        if let Ok(value) = var(&name) {
+            self.env.remove(&name);
+            self.host_env.insert(name, value);
+        }
+        self
+    }
+
+    /// Describe what the container is run for in the names of kept containers
+    pub fn describe(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Set the SELinux label applied to mounted directories (the default is `mount-label`)
+    pub fn mount_label(mut self, label: MountLabel) -> Self {
+        self.mount_label = label;
+        self
+    }
+
+    /// Allocate a pseudo-TTY for the container
+    ///
+    /// By default a TTY is only allocated if standard input is a terminal.
+    pub fn tty(mut self, tty: bool) -> Self {
+        self.tty = tty;
+        self
+    }
+
     /// Run a command in an image
     pub fn run(self, program: impl AsRef<OsStr>) -> Command {
         let mut command = self.command();
+        command.arg("run").arg(if self.tty { "-it" } else { "-i" });
+        if self.keep {
+            let mut container = String::from("s4-");
+            if let Some(description) = &self.description {
+                // Container names may only contain [a-zA-Z0-9_.-]
+                container.extend(description.chars().map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                        c
+                    } else {
+                        '-'
+                    }
+                }));
+                container.push('-');
+            }
+            container.push_str(&format!(
+                "{}-{}",
+                process::id(),
+                CONTAINER_COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            command.arg("--name").arg(&container);
+            self.apps.kept_containers.borrow_mut().push(container);
+        } else {
+            command.arg("--rm");
+        }
         command
-            .arg("run")
-            .args(&["-it", "--rm"])
-            .args(&["--hostname", "s4"])
-            .args(&["--volume", "/etc/localtime:/etc/localtime:ro"]);
+            .args(["--hostname", "s4"])
+            .args(["--volume", "/etc/localtime:/etc/localtime:ro"]);
         match self.apps.docker_impl {
             Podman => command.arg("--userns=keep-id"),
             Docker => command.args(&[
@@ -276,11 +741,23 @@ impl<'a> Docker<'a> {
             ]),
         };
         for (internal, external) in self.mounts.into_iter() {
-            command
-                .arg("--volume")
-                .arg(format!("{}:{}:z", external.display(), internal.display()));
+            command.arg("--volume").arg(format!(
+                "{}:{}{}",
+                external.display(),
+                internal.display(),
+                self.mount_label.volume_suffix()
+            ));
+        }
+        for (name, value) in &self.env {
+            command.arg("--env").arg(format!("{}={}", name, value));
+        }
+        for (name, value) in &self.host_env {
+            command.arg("--env").arg(name).env(name, value);
         }
         command.arg("--workdir").arg(Self::host_path(self.work_dir));
+        if let Some(entrypoint) = self.apps.defaults.docker_entrypoint() {
+            command.arg("--entrypoint").arg(entrypoint);
+        }
         command.arg(self.apps.defaults.docker_image());
         command.arg(program);
         command
@@ -289,12 +766,8 @@ impl<'a> Docker<'a> {
     /// Update the docker image
     pub fn update(self) -> Result<()> {
         let mut command = self.command();
-        if !command
-            .arg("pull")
-            .arg(self.apps.defaults.docker_image())
-            .status()?
-            .success()
-        {
+        command.arg("pull").arg(self.apps.defaults.docker_image());
+        if !self.apps.status(&mut command)?.success() {
             bail!(
                 "Failued to update docker image: {}",
                 self.apps.defaults.docker_image()
@@ -319,8 +792,11 @@ impl<'a> Docker<'a> {
 
 /// Find a app somewhere in the current app path
 fn find_app_path(app: impl AsRef<Path>) -> Option<PathBuf> {
-    let path = var("PATH").ok()?;
+    find_app_in(app, &var("PATH").ok()?)
+}
 
+/// Find an app in a colon-separated list of directories
+fn find_app_in(app: impl AsRef<Path>, path: &str) -> Option<PathBuf> {
     // We assume if we find a matching application that it is executable
     path.split(':')
         .map(Path::new)
@@ -329,38 +805,384 @@ fn find_app_path(app: impl AsRef<Path>) -> Option<PathBuf> {
             path.push(app.as_ref());
             path
         })
-        .filter(|path| path.exists())
+        .find(|path| path.exists())
+}
+
+/// Tools that must be available in the docker image
+const REQUIRED_IMAGE_TOOLS: &[&str] = &["cmake", "ninja"];
+
+/// Options of repo init that are set from the configuration and can not be passed as extra
+/// arguments
+const REPO_INIT_MANAGED_ARGS: &[&str] = &[
+    "-u",
+    "--manifest-url",
+    "-b",
+    "--manifest-branch",
+    "-m",
+    "--manifest-name",
+    "-g",
+    "--groups",
+];
+
+/// Report the tools listed as missing (one per line) from a docker image
+fn check_missing_tools(image: &str, missing: &str) -> Result<()> {
+    let missing = missing
+        .lines()
+        .map(str::trim)
+        .filter(|tool| !tool.is_empty())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!(
+            "Docker image {} is missing required tools: {}",
+            image,
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Parse the table of systems listed by mq.sh
+///
+/// Systems with a platform that can not be parsed are skipped with a warning.
+fn parse_system_tsv(tsv: &str) -> Result<BTreeMap<String, (PlatformId, Option<VariationId>)>> {
+    let mut lines = tsv.split('\n');
+    let headings = lines
         .next()
+        .ok_or(format_err!("Invalid output from mq.sh systems list"))?;
+
+    let mut systems = BTreeMap::new();
+
+    for line in lines {
+        let fields = headings
+            .split('\t')
+            .zip(line.split('\t'))
+            .collect::<BTreeMap<_, _>>();
+
+        if let (Some(name), Some(plat)) = (fields.get("name"), fields.get("sel4_plat")) {
+            let name = name.trim();
+            match parse_sel4_plat(plat) {
+                Ok(Some(plat)) => {
+                    systems.insert(name.into(), plat);
+                }
+                Ok(None) => {}
+                Err(error) => eprintln!(
+                    "Warning: skipping system {} with an invalid platform: {}",
+                    name, error
+                ),
+            }
+        }
+    }
+
+    Ok(systems)
+}
+
+/// Parse the pools listed by mq.sh (the name of each pool followed by its systems)
+fn parse_pool_tsv(tsv: &str) -> BTreeMap<String, BTreeSet<String>> {
+    let mut pools = BTreeMap::new();
+
+    for pool in tsv.trim().split('\n') {
+        let mut pool = pool.trim().split('\t');
+        if let Some(name) = pool.next().filter(|name| !name.is_empty()) {
+            pools.insert(
+                name.to_owned(),
+                pool.map(|system| system.to_owned()).collect(),
+            );
+        }
+    }
+
+    pools
+}
+
+/// Systems and pools that can run builds of a platform and variation
+///
+/// Pools made up only of matching systems are listed before the systems themselves.
+fn match_systems(
+    systems: &BTreeMap<String, (PlatformId, Option<VariationId>)>,
+    pools: BTreeMap<String, BTreeSet<String>>,
+    platform: &PlatformId,
+    variation: Option<&VariationId>,
+) -> Result<Vec<String>> {
+    if systems.is_empty() {
+        bail!("The machine queue listed no systems; check that mq.sh can reach the queue");
+    }
+
+    let mut matching = Vec::new();
+    for (name, (sys_platform, sys_variation)) in systems {
+        if platform == sys_platform && (variation.is_none() || variation == sys_variation.as_ref())
+        {
+            matching.push(name.clone());
+        }
+    }
+
+    let matching_set = matching.iter().cloned().collect();
+
+    for (name, _) in pools
+        .into_iter()
+        .filter(|(_, pool)| !pool.is_empty() && pool.is_subset(&matching_set))
+    {
+        matching.insert(0, name);
+    }
+
+    if matching.is_empty() {
+        let platform = match variation {
+            Some(variation) => format!("{}:{}", platform.as_ref(), variation.as_ref()),
+            None => platform.as_ref().to_owned(),
+        };
+        return Err(S4Error::NoMatchingSystem { platform }.into());
+    }
+
+    Ok(matching)
+}
+
+/// The platform and variation of a system as listed by mq.sh
+fn system_platform(
+    systems: &BTreeMap<String, (PlatformId, Option<VariationId>)>,
+    system: &str,
+) -> Result<PlatformChoice> {
+    match systems.get(system) {
+        Some((platform, None)) => Ok(PlatformChoice::ChoosePlatform(platform.clone())),
+        Some((platform, Some(variation))) => Ok(PlatformChoice::ChooseVariation(
+            platform.clone(),
+            variation.clone(),
+        )),
+        None => bail!("System {} has no platform listed by mq.sh", system),
+    }
+}
+
+/// Check that a system can run builds of a platform and variation
+///
+/// This uses the same rule as `Apps::machine_queue_match_system`: the platforms must be the same
+/// and a build of a variation needs a system of that variation. Names that are not listed as
+/// systems (such as pools) are not checked.
+fn check_system_platform(
+    systems: &BTreeMap<String, (PlatformId, Option<VariationId>)>,
+    system: &str,
+    platform: &PlatformId,
+    variation: Option<&VariationId>,
+) -> Result<()> {
+    let (sys_platform, sys_variation) = match systems.get(system) {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    if sys_platform == platform
+        && variation.is_none_or(|variation| Some(variation) == sys_variation.as_ref())
+    {
+        return Ok(());
+    }
+
+    let build = match variation {
+        Some(variation) => format!("{}:{}", platform.as_ref(), variation.as_ref()),
+        None => platform.as_ref().to_owned(),
+    };
+    bail!(
+        "System {} runs {} but the build is for {}",
+        system,
+        system_platform(systems, system)?,
+        build
+    );
+}
+
+/// Parse the seL4 platform of a system as listed by mq.sh
+///
+/// The platform is optionally followed by a variation and further qualifiers, separated by `:`.
+/// Further qualifiers are ignored. Whitespace is only allowed around each part. Systems with no
+/// platform give `None`.
+fn parse_sel4_plat(plat: &str) -> Result<Option<(PlatformId, Option<VariationId>)>> {
+    fn is_identifier(part: &str) -> bool {
+        part.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    }
+
+    let plat = plat.trim();
+    if plat.is_empty() {
+        return Ok(None);
+    }
+
+    let mut parts = plat.split(':').map(str::trim);
+    let platform = parts.next().unwrap_or_default();
+    let variation = parts.next().filter(|variation| !variation.is_empty());
+
+    if platform.is_empty() || !is_identifier(platform) || !variation.is_none_or(is_identifier) {
+        bail!("could not parse '{}'", plat);
+    }
+
+    Ok(Some((platform.into(), variation.map(VariationId::from))))
+}
+
+/// Find the executable for a container runtime in a colon-separated list of directories
+fn find_container_runtime(runtime: ContainerRuntime, path: &str) -> Result<PathBuf> {
+    match runtime {
+        ContainerRuntime::Auto => find_app_in("podman", path)
+            .or_else(|| find_app_in("docker", path))
+            .ok_or(format_err!(
+                "podman, docker, or podman-docker must be installed"
+            )),
+        ContainerRuntime::Docker => find_app_in("docker", path).ok_or(format_err!(
+            "docker is selected as the container runtime but is not installed"
+        )),
+        ContainerRuntime::Podman => find_app_in("podman", path).ok_or(format_err!(
+            "podman is selected as the container runtime but is not installed"
+        )),
+    }
 }
 
 /// Find an app somewhere in the path or download a script from a URL
-fn find_or_download(app: impl AsRef<Path>, url: &str) -> Result<PathBuf> {
+fn find_or_download(defaults: &Defaults, app: impl AsRef<Path>, url: &str) -> Result<PathBuf> {
     if let Some(path) = find_app_path(&app) {
         Ok(path)
     } else {
-        let path = tmp_app_path(&app)?;
+        let path = downloaded_app_path(defaults, &app)?;
         if !path.exists() {
-            let mut binary = get(url)?;
-            if !binary.status().is_success() {
-                bail!(
-                    "Could not download {} from {}: {}",
-                    app.as_ref().display(),
-                    url,
-                    binary.status()
-                );
-            }
-            let mut dest = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .mode(0o755)
-                .open(&path)?;
-            copy(&mut binary, &mut dest)?;
+            let client = http_client(
+                defaults.download_connect_timeout(),
+                defaults.download_timeout(),
+            )?;
+            download(
+                &client,
+                url,
+                &path,
+                DOWNLOAD_ATTEMPTS,
+                DOWNLOAD_BACKOFF,
+                interrupted,
+            )?;
         }
         Ok(path)
     }
 }
 
+/// Number of attempts made to download a script
+const DOWNLOAD_ATTEMPTS: u32 = 4;
+
+/// Delay before retrying a failed download, doubled after each retry
+const DOWNLOAD_BACKOFF: Duration = Duration::from_millis(500);
+
+/// HTTP client used to download scripts
+///
+/// `timeout` limits the whole request, including reading the response.
+fn http_client(connect_timeout: Duration, timeout: Duration) -> Result<Client> {
+    Ok(Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(timeout)
+        .build()?)
+}
+
+/// Download an executable script, retrying with backoff if the download fails
+///
+/// The script is written to a temporary file that is renamed into place once complete. No further
+/// attempts are made once `interrupted` returns true.
+fn download(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    attempts: u32,
+    backoff: Duration,
+    interrupted: impl Fn() -> bool,
+) -> Result<()> {
+    let mut delay = backoff;
+    let mut last_error = String::new();
+
+    for attempt in 0..attempts {
+        if attempt > 0 {
+            sleep(delay);
+            delay *= 2;
+        }
+        if interrupted() {
+            break;
+        }
+
+        let mut response = match client.get(url).send() {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                last_error = format!("HTTP status {}", response.status());
+                continue;
+            }
+            Err(error) if error.is_timeout() => {
+                last_error = "the server did not respond in time".to_owned();
+                continue;
+            }
+            Err(error) => {
+                last_error = error.to_string();
+                continue;
+            }
+        };
+
+        let partial = path.with_extension("partial");
+        let mut dest = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .mode(0o755)
+            .open(&partial)?;
+        if let Err(error) = copy(&mut response, &mut dest) {
+            let _ = remove_file(&partial);
+            last_error = error.to_string();
+            continue;
+        }
+        rename(&partial, path)?;
+        return Ok(());
+    }
+
+    if interrupted() {
+        return Err(S4Error::DownloadInterrupted {
+            url: url.to_owned(),
+        }
+        .into());
+    }
+    bail!(
+        "Could not download {} from {} after {} attempts: {}",
+        path.display(),
+        url,
+        attempts,
+        last_error
+    );
+}
+
+/// Apps that s4 downloads if they are not installed
+const DOWNLOADED_APPS: &[&str] = &["repo"];
+
+/// The path for a downloaded copy of a script
+///
+/// Scripts are kept in the tool cache directory, falling back to a temporary path if there is no
+/// usable cache directory.
+fn downloaded_app_path(defaults: &Defaults, app: impl AsRef<Path>) -> Result<PathBuf> {
+    if let Some(cache) = defaults.tool_cache_dir() {
+        if create_dir_all(&cache).is_ok() {
+            let filename = app
+                .as_ref()
+                .file_name()
+                .ok_or(format_err!("Invalid app name: {}", app.as_ref().display()))?;
+            return Ok(cache.join(filename));
+        }
+    }
+    tmp_app_path(app)
+}
+
+/// Remove all downloaded copies of scripts, returning the paths that were removed
+///
+/// Only the downloaded scripts (and any partial downloads) are removed; the tool cache directory
+/// itself is left in place as it may be shared with other files.
+pub fn clean_tools(defaults: &Defaults) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+
+    for app in DOWNLOADED_APPS {
+        let mut paths = vec![tmp_app_path(app)?];
+        if let Some(cache) = defaults.tool_cache_dir() {
+            paths.push(cache.join(app));
+        }
+
+        for path in paths {
+            for path in [path.with_extension("partial"), path].iter() {
+                if path.is_file() {
+                    remove_file(path)?;
+                    removed.push(path.clone());
+                }
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
 /// A path for a temporary copy of a script
 fn tmp_app_path(app: impl AsRef<Path>) -> Result<PathBuf> {
     let mut path = PathBuf::new();
@@ -378,3 +1200,548 @@ fn tmp_app_path(app: impl AsRef<Path>) -> Result<PathBuf> {
     }
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn machine_queue_reservation_commands() {
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, Docker);
+        let key = apps.machine_queue_lock_key();
+
+        let acquire = apps.machine_queue_acquire_command("odroidc2").unwrap();
+        assert_eq!(acquire.get_program(), "mq.sh");
+        assert_eq!(args(&acquire), ["sem", "-wait", "odroidc2", "-k", &key]);
+
+        let release = apps.machine_queue_release_command("odroidc2").unwrap();
+        assert_eq!(args(&release), ["sem", "-signal", "odroidc2", "-k", &key]);
+
+        let console = apps.machine_queue_console_command("odroidc2").unwrap();
+        assert_eq!(args(&console), ["console", "-s", "odroidc2"]);
+    }
+
+    #[test]
+    fn repo_init_groups() {
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, Docker);
+        let repository = "seL4/sel4test-manifest".parse().unwrap();
+
+        let init = apps.repo_init_command(&repository, &[], &[]);
+        assert!(!args(&init).contains(&"-g".to_owned()));
+
+        let groups = ["default".to_owned(), "haskell".to_owned()];
+        let init = apps.repo_init_command(&repository, &groups, &[]);
+        assert!(args(&init).ends_with(&["-g".to_owned(), "default,haskell".to_owned()]));
+    }
+
+    #[test]
+    fn repo_init_extra_args() {
+        let defaults: Defaults = toml::from_str(
+            r#"repo-init-args = [ "--reference", "/srv/mirror", "--no-clone-bundle" ]"#,
+        )
+        .unwrap();
+        let apps = Apps::mock(&defaults, Docker);
+        let repository = "seL4/sel4test-manifest".parse().unwrap();
+
+        let groups = ["default".to_owned()];
+        let init = apps.repo_init_command(&repository, &groups, defaults.repo_init_args());
+        assert!(args(&init).ends_with(&[
+            "-g".to_owned(),
+            "default".to_owned(),
+            "--reference".to_owned(),
+            "/srv/mirror".to_owned(),
+            "--no-clone-bundle".to_owned(),
+        ]));
+        Apps::check_repo_init_args(defaults.repo_init_args()).unwrap();
+
+        for managed in &["-b", "--manifest-branch=main", "--groups", "-gall"] {
+            let error = Apps::check_repo_init_args(&[managed.to_string()]).unwrap_err();
+            assert!(error.to_string().contains("is set by s4"), "{}", error);
+        }
+        Apps::check_repo_init_args(&["--manifest-depth=1".to_owned()]).unwrap();
+    }
+
+    #[test]
+    fn low_free_space_warns() {
+        let directory = crate::util::test_dir("free-space");
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, Docker);
+        assert_eq!(apps.free_space_warning(&directory).unwrap(), None);
+
+        let defaults: Defaults = toml::from_str("min-free-space = 0").unwrap();
+        let apps = Apps::mock(&defaults, Docker);
+        assert_eq!(apps.free_space_warning(&directory).unwrap(), None);
+
+        let defaults: Defaults = toml::from_str("min-free-space = 1000000000").unwrap();
+        let apps = Apps::mock(&defaults, Docker);
+        let warning = apps.free_space_warning(&directory).unwrap().unwrap();
+        assert!(
+            warning.contains("min-free-space is 1000000000 GiB"),
+            "{}",
+            warning
+        );
+        apps.check_free_space(&directory).unwrap();
+
+        assert!(apps.free_space_warning(&directory.join("missing")).is_err());
+    }
+
+    #[test]
+    fn run_without_tty() {
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, Docker);
+
+        let command = apps.docker().unwrap().tty(true).run("ninja");
+        assert_eq!(args(&command)[..2], ["run", "-it"]);
+
+        let command = apps.docker().unwrap().tty(false).run("ninja");
+        assert_eq!(args(&command)[..2], ["run", "-i"]);
+    }
+
+    #[test]
+    fn entrypoint_override() {
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, Docker);
+        let command = apps.docker().unwrap().run("cmake");
+        assert!(!args(&command).contains(&"--entrypoint".to_owned()));
+
+        let defaults: Defaults = toml::from_str(r#"docker-entrypoint = """#).unwrap();
+        let apps = Apps::mock(&defaults, Docker);
+        let command = apps.docker().unwrap().run("cmake");
+        assert!(args(&command).ends_with(&[
+            "--entrypoint".to_owned(),
+            String::new(),
+            defaults.docker_image().to_owned(),
+            "cmake".to_owned(),
+        ]));
+    }
+
+    #[test]
+    fn download_retries_after_failure() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let responses = [
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\n#!/bin/sh\n",
+            ];
+            for response in &responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while request.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let path = crate::util::test_dir("download").join("repo");
+        let client = http_client(Duration::from_secs(10), Duration::from_secs(10)).unwrap();
+        download(&client, &url, &path, 3, Duration::from_millis(10), || false).unwrap();
+        server.join().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "#!/bin/sh\n");
+        assert!(!path.with_extension("partial").exists());
+    }
+
+    #[test]
+    fn download_times_out() {
+        use std::net::TcpListener;
+
+        // Accept connections but never respond
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let streams = listener.incoming().take(2).collect::<Vec<_>>();
+            sleep(Duration::from_secs(5));
+            drop(streams);
+        });
+
+        let path = crate::util::test_dir("download-timeout").join("repo");
+        let client = http_client(Duration::from_secs(1), Duration::from_millis(200)).unwrap();
+        let error = download(&client, &url, &path, 2, Duration::from_millis(10), || false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.ends_with("after 2 attempts: the server did not respond in time"),
+            "{}",
+            error
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn interrupted_download_is_not_retried() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::sync::atomic::AtomicBool;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo", listener.local_addr().unwrap());
+        let interrupt = AtomicBool::new(false);
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while request.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+        });
+
+        let path = crate::util::test_dir("download-interrupted").join("repo");
+        let client = http_client(Duration::from_secs(10), Duration::from_secs(10)).unwrap();
+        let error = download(&client, &url, &path, 3, Duration::from_millis(10), || {
+            interrupt.swap(true, Ordering::SeqCst)
+        })
+        .unwrap_err();
+        server.join().unwrap();
+        assert_eq!(
+            error.downcast::<S4Error>().unwrap(),
+            S4Error::DownloadInterrupted { url }
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn downloads_use_tool_cache() {
+        let cache = crate::util::test_dir("tool-cache").join("tools");
+        let defaults: Defaults =
+            toml::from_str(&format!("tool-cache-dir = \"{}\"", cache.display())).unwrap();
+
+        let path = downloaded_app_path(&defaults, "repo").unwrap();
+        assert_eq!(path, cache.join("repo"));
+        assert!(cache.is_dir());
+
+        std::fs::write(&path, "").unwrap();
+        std::fs::write(cache.join("repo.partial"), "").unwrap();
+        std::fs::write(cache.join("unrelated"), "").unwrap();
+        let removed = clean_tools(&defaults).unwrap();
+        assert!(removed.contains(&path));
+        assert!(removed.contains(&cache.join("repo.partial")));
+        assert!(!path.exists());
+        assert!(cache.join("unrelated").exists());
+    }
+
+    #[test]
+    fn missing_image_tools() {
+        check_missing_tools("example/image", "\n").unwrap();
+
+        let error = check_missing_tools("example/image", "ninja\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Docker image example/image is missing required tools: ninja"
+        );
+
+        let error = check_missing_tools("example/image", "cmake\nninja\n").unwrap_err();
+        assert!(error.to_string().ends_with("cmake, ninja"));
+    }
+
+    #[test]
+    fn sel4_plat_formats() {
+        let plat = |platform: &str, variation: Option<&str>| {
+            Some((PlatformId::from(platform), variation.map(VariationId::from)))
+        };
+
+        assert_eq!(parse_sel4_plat("odroidc2").unwrap(), plat("odroidc2", None));
+        assert_eq!(
+            parse_sel4_plat(" tx2:smp ").unwrap(),
+            plat("tx2", Some("smp"))
+        );
+        assert_eq!(
+            parse_sel4_plat("imx8mq-evk:smp4:hyp").unwrap(),
+            plat("imx8mq-evk", Some("smp4"))
+        );
+        assert_eq!(
+            parse_sel4_plat("pc99 : haswell").unwrap(),
+            plat("pc99", Some("haswell"))
+        );
+        assert!(parse_sel4_plat("pc99 x86_64").is_err());
+        assert!(parse_sel4_plat("pc99:has well").is_err());
+        assert_eq!(parse_sel4_plat("sabre:").unwrap(), plat("sabre", None));
+        assert_eq!(parse_sel4_plat("  ").unwrap(), None);
+        assert!(parse_sel4_plat(":smp").is_err());
+        assert!(parse_sel4_plat("zynq(mp)").is_err());
+    }
+
+    #[test]
+    fn empty_queue_and_unmatched_platform_differ() {
+        let odroidc2 = PlatformId::from("odroidc2");
+        for output in &["", "name\tsel4_plat\n"] {
+            let systems = parse_system_tsv(output).unwrap();
+            let error = match_systems(&systems, parse_pool_tsv(""), &odroidc2, None).unwrap_err();
+            assert!(error
+                .to_string()
+                .starts_with("The machine queue listed no systems"));
+        }
+
+        let systems = parse_system_tsv("name\tsel4_plat\nhaswell2\tpc99:haswell\n").unwrap();
+        let error = match_systems(&systems, parse_pool_tsv(""), &odroidc2, None).unwrap_err();
+        assert_eq!(error.to_string(), "No matching system found for odroidc2");
+
+        let systems =
+            parse_system_tsv("name\tsel4_plat\nodroidc2-1\todroidc2\nodroidc2-2\todroidc2\n")
+                .unwrap();
+        let pools = parse_pool_tsv("odroids\todroidc2-1\todroidc2-2\n");
+        assert_eq!(
+            match_systems(&systems, pools, &odroidc2, None).unwrap(),
+            ["odroids", "odroidc2-1", "odroidc2-2"]
+        );
+    }
+
+    #[test]
+    fn system_tsv_with_unparseable_platform() {
+        let tsv = "name\tsel4_plat\nodroidc2\todroidc2\nhaswell2\tpc99:haswell\nfpga\t\n";
+        let systems = parse_system_tsv(tsv).unwrap();
+        assert_eq!(systems.len(), 2);
+        assert_eq!(
+            systems["haswell2"],
+            (PlatformId::from("pc99"), Some(VariationId::from("haswell")))
+        );
+
+        let tsv = "name\tsel4_plat\nbroken\t??\nspaced\tpc99 x86_64\nodroidc2\todroidc2\n";
+        let systems = parse_system_tsv(tsv).unwrap();
+        assert_eq!(systems.keys().collect::<Vec<_>>(), ["odroidc2"]);
+    }
+
+    #[test]
+    fn system_platform_is_checked() {
+        let tsv = "name\tsel4_plat\nodroidc2-1\todroidc2\nhaswell2\tpc99:haswell\n";
+        let systems = parse_system_tsv(tsv).unwrap();
+        let pc99 = PlatformId::from("pc99");
+        let haswell = VariationId::from("haswell");
+        let nehalem = VariationId::from("nehalem");
+
+        check_system_platform(&systems, "haswell2", &pc99, None).unwrap();
+        check_system_platform(&systems, "haswell2", &pc99, Some(&haswell)).unwrap();
+        check_system_platform(&systems, "pool", &pc99, Some(&nehalem)).unwrap();
+
+        let error = check_system_platform(&systems, "odroidc2-1", &pc99, Some(&haswell))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "System odroidc2-1 runs odroidc2 but the build is for pc99:haswell"
+        );
+        assert!(check_system_platform(&systems, "haswell2", &pc99, Some(&nehalem)).is_err());
+
+        assert_eq!(
+            system_platform(&systems, "haswell2").unwrap(),
+            "pc99:haswell".parse().unwrap()
+        );
+        assert!(system_platform(&systems, "pool").is_err());
+    }
+
+    #[test]
+    fn forced_container_runtime() {
+        let both = crate::util::test_dir("runtime-both");
+        std::fs::write(both.join("docker"), "").unwrap();
+        std::fs::write(both.join("podman"), "").unwrap();
+        let docker_only = crate::util::test_dir("runtime-docker");
+        std::fs::write(docker_only.join("docker"), "").unwrap();
+        let path = format!("{}:{}", docker_only.display(), both.display());
+
+        let defaults: Defaults = toml::from_str("container-runtime = \"podman\"").unwrap();
+        assert_eq!(
+            find_container_runtime(defaults.container_runtime(), &path).unwrap(),
+            both.join("podman")
+        );
+        assert_eq!(
+            find_container_runtime(ContainerRuntime::Docker, &path).unwrap(),
+            docker_only.join("docker")
+        );
+        assert!(
+            find_container_runtime(ContainerRuntime::Podman, docker_only.to_str().unwrap())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn configured_mount_label() {
+        let volumes = |defaults: &Defaults| {
+            let apps = Apps::mock(defaults, Docker);
+            let command = apps.docker().unwrap().run("ninja");
+            let args = args(&command);
+            args.iter()
+                .zip(args.iter().skip(1))
+                .filter(|(option, _)| *option == "--volume")
+                .map(|(_, volume)| volume.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let host = volumes(&Defaults::default());
+        assert!(host.iter().any(|volume| volume.ends_with(":/host:z")));
+
+        let private: Defaults = toml::from_str("mount-label = \"Z\"").unwrap();
+        assert!(volumes(&private)
+            .iter()
+            .any(|volume| volume.ends_with(":/host:Z")));
+
+        let none: Defaults = toml::from_str("mount-label = \"none\"").unwrap();
+        assert!(volumes(&none)
+            .iter()
+            .any(|volume| volume.ends_with(":/host")));
+
+        assert!(toml::from_str::<Defaults>("mount-label = \"x\"").is_err());
+    }
+
+    #[test]
+    fn host_environment_is_forwarded() {
+        std::env::set_var("S4_TEST_FORWARDED", "forwarded");
+        std::env::remove_var("S4_TEST_UNSET");
+        let defaults: Defaults =
+            toml::from_str(r#"docker-env = [ "S4_TEST_UNSET", "S4_TEST_FORWARDED" ]"#).unwrap();
+        let apps = Apps::mock(&defaults, Docker);
+
+        let command = apps
+            .docker()
+            .unwrap()
+            .env("S4_TEST_EXPLICIT", "explicit")
+            .run("ninja");
+        let envs = args(&command)
+            .windows(2)
+            .filter(|pair| pair[0] == "--env")
+            .map(|pair| pair[1].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(envs, ["S4_TEST_EXPLICIT=explicit", "S4_TEST_FORWARDED"]);
+        assert!(command
+            .get_envs()
+            .any(|(name, value)| name == "S4_TEST_FORWARDED"
+                && value == Some(OsStr::new("forwarded"))));
+        assert!(!display_command(&command).contains("=forwarded"));
+    }
+
+    #[test]
+    fn keep_on_failure_removes_rm() {
+        let defaults = Defaults::default();
+        let mut apps = Apps::mock(&defaults, Docker);
+
+        let command = apps.docker().unwrap().run("ninja");
+        assert!(args(&command).contains(&"--rm".to_owned()));
+        assert!(apps.kept_containers.borrow().is_empty());
+
+        apps.set_keep_on_failure(true);
+        let command = apps.docker().unwrap().run("ninja");
+        let args = args(&command);
+        assert!(!args.contains(&"--rm".to_owned()));
+        let name = &args[args.iter().position(|arg| arg == "--name").unwrap() + 1];
+        assert_eq!(*apps.kept_containers.borrow(), vec![name.clone()]);
+
+        let command = apps
+            .docker()
+            .unwrap()
+            .describe("sel4test/odroidc2:smp4/aarch64@0123abcd")
+            .run("ninja");
+        let args = self::args(&command);
+        let name = &args[args.iter().position(|arg| arg == "--name").unwrap() + 1];
+        assert!(name.starts_with("s4-sel4test-odroidc2-smp4-aarch64-0123abcd-"));
+    }
+
+    #[test]
+    fn kept_containers_are_removed_after_success() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = crate::util::test_dir("kept-containers");
+        let calls = root.join("calls");
+        let docker = root.join("docker");
+        std::fs::write(
+            &docker,
+            format!(
+                "#!/bin/sh\necho \"$*\" >> {}\nfor arg; do [ \"$arg\" = false ] && exit 1; done\nexit 0\n",
+                calls.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&docker, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let defaults = Defaults::default();
+        let mut apps = Apps::mock(&defaults, Docker);
+        apps.docker = docker;
+        apps.set_keep_on_failure(true);
+        let name = |command: &Command| {
+            let args = args(command);
+            args[args.iter().position(|arg| arg == "--name").unwrap() + 1].clone()
+        };
+
+        let mut command = apps.docker().unwrap().run("true");
+        let succeeded = name(&command);
+        assert!(apps.status(&mut command).unwrap().success());
+        let mut command = apps.docker().unwrap().run("false");
+        let failed = name(&command);
+        assert!(!apps.status(&mut command).unwrap().success());
+        assert!(apps.kept_containers.borrow().is_empty());
+
+        // Queries never keep their containers
+        apps.check_image_tools().unwrap();
+        assert!(apps.kept_containers.borrow().is_empty());
+
+        let calls = std::fs::read_to_string(calls).unwrap();
+        let calls = calls.lines().collect::<Vec<_>>();
+        assert!(calls.contains(&format!("rm {}", succeeded).as_str()));
+        assert!(!calls.contains(&format!("rm {}", failed).as_str()));
+        assert!(calls.last().unwrap().contains("--rm"));
+    }
+
+    #[test]
+    fn image_digest_from_inspect() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = crate::util::test_dir("image-digest");
+        let docker = root.join("docker");
+        std::fs::write(
+            &docker,
+            concat!(
+                "#!/bin/sh\n",
+                "[ \"$*\" = 'image inspect --format {{index .RepoDigests 0}} pinned/image' ] &&\n",
+                "echo pinned/image@sha256:abc\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&docker, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let defaults: Defaults = toml::from_str(r#"docker-image = "pinned/image""#).unwrap();
+        let mut apps = Apps::mock(&defaults, Docker);
+        apps.docker = docker.clone();
+        assert_eq!(
+            apps.resolve_image_digest().unwrap().as_deref(),
+            Some("pinned/image@sha256:abc")
+        );
+
+        let defaults: Defaults = toml::from_str(r#"docker-image = "missing/image""#).unwrap();
+        let mut apps = Apps::mock(&defaults, Docker);
+        apps.docker = docker;
+        assert_eq!(apps.resolve_image_digest().unwrap(), None);
+    }
+
+    #[test]
+    fn dry_run_does_not_run_commands() {
+        let defaults = Defaults::default();
+        let mut apps = Apps::mock(&defaults, Docker);
+        apps.set_keep_on_failure(true);
+        apps.set_dry_run(true);
+
+        // None of these programs exist, so running any of them would fail
+        let mut command = apps.docker().unwrap().run("false");
+        apps.run_stage(Stage::Build, &mut command).unwrap();
+        assert!(apps.kept_containers.borrow().is_empty());
+        assert!(apps.machine_queue_acquire("odroidc2").is_ok());
+        assert!(apps.machine_queue_console("odroidc2").is_ok());
+        assert!(apps.status(&mut apps.repo_sync()).unwrap().success());
+
+        apps.set_dry_run(false);
+        assert!(apps.status(&mut apps.repo_sync()).is_err());
+    }
+}
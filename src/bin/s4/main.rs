@@ -1,56 +1,563 @@
-use anyhow::Result;
+use anyhow::{bail, format_err, Result};
 use s4::{
-    Apps, BuildContext, Config, Context, PlatformId, ProjectId, Setting, VariationId,
-    WorkspaceContext,
+    find_context, Apps, BuildContext, Config, Context, MatrixSpec, Merge, PlatformChoice,
+    Sel4Architecture, Setting, WorkspaceContext, WorkspaceExport,
 };
+use std::fs::{read_to_string, write, File};
+use std::io::{copy, stdout, Write};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+use structopt::StructOpt;
+
+mod opts;
+
+use opts::*;
 
 fn main() -> Result<()> {
-    let mut config = Config::load()?;
-
-    // println!("{:#?}", config);
-
-    let project_id: ProjectId = "sel4test".into();
-    let platform_id: PlatformId = "odroidc2".into();
-    let arch = s4::AArch64;
-
-    let mut setting = Setting::default();
-    setting.set_bool("mcs", true);
-    // setting.set_bool("arm-hyp", true);
-    println!("{}", setting);
-
-    // let context = WorkspaceContext::create(project_id, "sel4test")?;
-    let context = WorkspaceContext::load("sel4test")?;
-    let easy_settings = context.easy_settings()?;
-    let cmdline_flags = easy_settings
-        .all()
-        .map(|flag| flag.name().clone())
-        .collect::<Vec<_>>();
-    config.add_flags(easy_settings);
-    println!("{:?}", cmdline_flags);
-    let project = config.project(&project_id);
+    let options = Options::from_args();
+    s4::install_interrupt_handler();
 
-    let apps = Apps::try_new(config.defaults())?;
-    // apps.docker()?.update()?;
-    // project.init(context.workspace_root(), &apps)?;
-    #[cfg(not)]
-    let context = BuildContext::load(&context, "sel4test-odroidc2");
-    let context = BuildContext::create(
-        &config,
-        &context,
-        platform_id,
-        None,
+    let mut config = match &options.config {
+        Some(path) => Config::load_exact(path)?,
+        None if options.strict_config => Config::load_strict_config()?,
+        None => Config::load()?,
+    };
+    config.set_strict(options.strict);
+
+    let context = find_context(&config)?;
+    if let Some(context) = &context {
+        let flags = context.easy_settings(&config)?;
+        config.add_flags(flags);
+    }
+
+    match options.command {
+        Command::Version => version(&config),
+        Command::Env => env(require(context)?),
+        Command::Init(init) => self::init(&config, options.run, init),
+        Command::Sync => {
+            let apps = apps(&config, options.run)?;
+            require(context)?.workspace().sync(&apps)
+        }
+        Command::Export { output } => {
+            let export = toml::to_string(&require(context)?.workspace().export()?)?;
+            match output {
+                Some(path) => write(&path, export)?,
+                None => print!("{}", export),
+            }
+            Ok(())
+        }
+        Command::Import(import) => self::import(&config, options.run, import),
+        Command::List(List::Platforms) => list_platforms(&config),
+        Command::List(List::Builds(list)) => list_builds(&config, require(context)?, list),
+        Command::Platform(PlatformCommand::Show { platform }) => {
+            println!("{}", config.find_platform(&platform)?);
+            Ok(())
+        }
+        Command::Flags { sources } => list_flags(&config, sources),
+        Command::Build(build) => self::build(&config, options.run, require(context)?, build),
+        Command::Matrix(spec) => matrix(&config, options.run, require(context)?, spec),
+        Command::BuildAll { stale_only } => {
+            let apps = apps(&config, options.run)?;
+            build_all(&config, &apps, require(context)?, stale_only)
+        }
+        Command::Run {
+            build_dir,
+            script: Some(script),
+            args,
+            ..
+        } => {
+            let apps = apps(&config, options.run)?;
+            let context = require(context)?;
+            let build = match (&build_dir.build_dir, context.maybe_build_root()) {
+                (None, None) => None,
+                _ => Some(load_build(&config, &*context, &build_dir)?),
+            };
+            let context = build
+                .as_ref()
+                .map_or(&*context, |build| build as &dyn Context);
+            config
+                .find_script(&script)?
+                .run(&apps, &script, context, &args)
+        }
+        Command::Run {
+            build_dir, system, ..
+        } => {
+            let apps = apps(&config, options.run)?;
+            let context = require(context)?;
+            let build = load_build(&config, &*context, &build_dir)?;
+            let project = config.find_project(build.project())?;
+            project.run(&build, &config, &apps, system.as_deref())
+        }
+        Command::Board(BoardCommand::Console { system }) => {
+            apps(&config, options.run)?.machine_queue_console(&system)
+        }
+        Command::Doctor => doctor(&config, context),
+        Command::CleanTools => {
+            for path in s4::clean_tools(config.defaults())? {
+                println!("Removed {}", path.display());
+            }
+            Ok(())
+        }
+        Command::Config(ConfigCommand::SetProject { project }) => {
+            let path = Config::user_config_path()
+                .ok_or_else(|| format_err!("Could not find the home directory"))?;
+            config.save_default_project(&project, &path)?;
+            println!(
+                "Default project set to {} in {}",
+                project.as_ref(),
+                path.display()
+            );
+            Ok(())
+        }
+        Command::Config(ConfigCommand::Dump { project, platform }) => {
+            print!("{}", config.dump(project.as_ref(), platform.as_ref())?);
+            Ok(())
+        }
+        Command::Config(ConfigCommand::Defaults) => {
+            let defaults = config.defaults();
+            let values = [
+                (
+                    "git-server",
+                    defaults.raw_git_server(),
+                    defaults.git_server(),
+                ),
+                (
+                    "docker-image",
+                    defaults.raw_docker_image(),
+                    defaults.docker_image(),
+                ),
+                ("repo-url", defaults.raw_repo_url(), defaults.repo_url()),
+                (
+                    "exit-phrase",
+                    defaults.raw_exit_phrase(),
+                    defaults.exit_phrase(),
+                ),
+            ];
+            println!("{:<14} {:<20} effective", "default", "configured");
+            for (name, raw, effective) in values {
+                println!("{:<14} {:<20} {}", name, raw.unwrap_or("-"), effective);
+            }
+            Ok(())
+        }
+        Command::Config(ConfigCommand::Describe { name }) => {
+            let definitions = config.describe(&name);
+            if definitions.is_empty() {
+                bail!("No flag, platform, project, or script named {}", name);
+            }
+            for (kind, source) in definitions {
+                println!("{} {}: {}", kind, name, source);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Ensure that s4 is being run within a workspace
+fn require(context: Option<Box<dyn Context>>) -> Result<Box<dyn Context>> {
+    context.ok_or_else(|| format_err!("Not in a workspace or build directory"))
+}
+
+/// Find the apps used to run build stages
+fn apps(config: &Config, run: RunOptions) -> Result<Apps<'_>> {
+    let mut apps = Apps::try_new(config.defaults())?;
+    apps.set_keep_on_failure(run.keep_on_failure);
+    apps.set_dry_run(run.dry_run);
+    Ok(apps)
+}
+
+/// Copy a log to stdout, waiting for more output until interrupted if following it
+fn print_log(mut log: File, follow: bool) -> Result<()> {
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+    loop {
+        copy(&mut log, &mut stdout)?;
+        if !follow || s4::interrupted() {
+            return Ok(());
+        }
+        stdout.flush()?;
+        sleep(Duration::from_millis(250));
+    }
+}
+
+/// Load the selected build directory or the build directory containing the current directory
+fn load_build(
+    config: &Config,
+    context: &dyn Context,
+    build_dir: &BuildDir,
+) -> Result<BuildContext> {
+    let mut build = match build_dir
+        .build_dir
+        .as_deref()
+        .or_else(|| context.maybe_build_root())
+    {
+        Some(path) => BuildContext::load(context.workspace(), path)?,
+        None => bail!("Not in a build directory; use --build-dir to select one"),
+    };
+    build.migrate(config)?;
+    Ok(build)
+}
+
+fn version(config: &Config) -> Result<()> {
+    println!("s4 {}", s4::version());
+    println!("{}", Apps::try_new(config.defaults())?);
+    Ok(())
+}
+
+fn env(context: Box<dyn Context>) -> Result<()> {
+    let workspace = context.workspace();
+    println!("workspace: {}", workspace.workspace_root().display());
+    println!("project: {}", workspace.project().as_ref());
+    match workspace.sel4_version()? {
+        Some(version) => println!("seL4: {}", version),
+        None => println!("seL4: not synced"),
+    }
+    if let Some(build_root) = context.maybe_build_root() {
+        println!("build: {}", build_root.display());
+    }
+    if let Some(description) = context.maybe_describe() {
+        println!("configuration: {}", description);
+    }
+    Ok(())
+}
+
+fn init(config: &Config, run: RunOptions, init: Init) -> Result<()> {
+    let project_id = init
+        .project
+        .or_else(|| config.defaults().default_project().cloned())
+        .ok_or_else(|| {
+            format_err!("No project specified; use --project or run s4 config set-project")
+        })?;
+    let project = config.find_project(&project_id)?;
+
+    let workspace =
+        WorkspaceContext::create(project_id.clone(), &init.path, init.force, init.gitignore)?;
+    if !init.no_sync {
+        let apps = apps(config, run)?;
+        project.init(workspace.workspace_root(), &apps)?;
+    }
+    Ok(())
+}
+
+fn import(config: &Config, run: RunOptions, import: Import) -> Result<()> {
+    let export: WorkspaceExport = toml::from_str(&read_to_string(&import.file)?)
+        .map_err(|e| format_err!("Could not load {}: {}", import.file.display(), e))?;
+    let workspace = WorkspaceContext::import(config, &export, &import.path)?;
+    if !import.no_sync {
+        let project = config.find_project(workspace.project())?;
+        project.init(workspace.workspace_root(), &apps(config, run)?)?;
+    }
+    Ok(())
+}
+
+fn list_platforms(config: &Config) -> Result<()> {
+    let platforms = config.platforms().collect::<Vec<_>>();
+    let width = platforms
+        .iter()
+        .map(|platform| platform.name().as_ref().len())
+        .max()
+        .unwrap_or(0);
+    for platform in platforms {
+        let line = format!(
+            "{:width$}  {}",
+            platform.name().as_ref(),
+            platform.description().unwrap_or_default(),
+            width = width
+        );
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// List flags with their descriptions, and optionally where they were defined
+fn list_flags(config: &Config, sources: bool) -> Result<()> {
+    let flags = config.flag_sources().collect::<Vec<_>>();
+    let name_width = flags
+        .iter()
+        .map(|(flag, _)| flag.name().as_ref().len())
+        .max()
+        .unwrap_or(0);
+    let source_width = flags
+        .iter()
+        .map(|(_, source)| source.to_string().len())
+        .max()
+        .unwrap_or(0);
+    for (flag, source) in flags {
+        let mut line = format!("{:width$}  ", flag.name().as_ref(), width = name_width);
+        if sources {
+            line.push_str(&format!(
+                "{:width$}  ",
+                source.to_string(),
+                width = source_width
+            ));
+        }
+        line.push_str(flag.description());
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// List builds sorted by path with their platform, architecture, and whether they are built
+///
+/// Builds whose directory no longer exists are listed as missing unless filtering by tag.
+fn list_builds(config: &Config, context: Box<dyn Context>, list: ListBuilds) -> Result<()> {
+    let workspace = context.workspace();
+    let project = config.project(workspace.project());
+
+    let mut rows = Vec::new();
+    for build in workspace.registered_builds() {
+        let row = match build? {
+            (_, Some(build)) if list.tag.as_deref().is_some_and(|tag| !build.has_tag(tag)) => {
+                continue
+            }
+            (path, Some(build)) => {
+                let mut platform = build.platform().as_ref().to_owned();
+                if let Some(variation) = build.variation() {
+                    platform.push(':');
+                    platform.push_str(variation.as_ref());
+                }
+                let status = if project.images_built(&build) {
+                    "built"
+                } else {
+                    "not built"
+                };
+                [
+                    path.display().to_string(),
+                    platform,
+                    build.architecture().to_string(),
+                    status.to_owned(),
+                ]
+            }
+            (_, None) if list.tag.is_some() => continue,
+            (path, None) => [
+                path.display().to_string(),
+                "-".to_owned(),
+                "-".to_owned(),
+                "missing".to_owned(),
+            ],
+        };
+        rows.push(row);
+    }
+
+    let mut widths = [0; 3];
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+    for [path, platform, architecture, status] in rows {
+        println!(
+            "{:path_width$}  {:platform_width$}  {:architecture_width$}  {}",
+            path,
+            platform,
+            architecture,
+            status,
+            path_width = widths[0],
+            platform_width = widths[1],
+            architecture_width = widths[2]
+        );
+    }
+    Ok(())
+}
+
+fn build(config: &Config, run: RunOptions, context: Box<dyn Context>, build: Build) -> Result<()> {
+    let Build { build_dir, command } = build;
+    match command {
+        None => load_build(config, &*context, &build_dir)?.build(&apps(config, run)?, config),
+        Some(BuildCommand::Create(create)) => self::create(config, run, context, create),
+        Some(BuildCommand::Adopt { path }) => {
+            let build = BuildContext::adopt(config, context.workspace(), path)?;
+            println!("Adopted {}", build.describe());
+            Ok(())
+        }
+        Some(BuildCommand::Configure { force, fresh }) => {
+            let current = load_build(config, &*context, &build_dir)?;
+            let project = config.find_project(current.project())?;
+            let apps = apps(config, run)?;
+            if fresh {
+                project.fresh_build(&current, &apps, config)
+            } else {
+                project.update_build(&current, &apps, config, force)
+            }
+        }
+        Some(BuildCommand::Show { cmake_args: true }) => {
+            let current = load_build(config, &*context, &build_dir)?;
+            let mut setting = current.configure_setting()?;
+            config.auto_enable(&mut setting);
+            for arg in config.cmake_arg_strings(&setting) {
+                println!("{}", arg);
+            }
+            Ok(())
+        }
+        Some(BuildCommand::Show { cmake_args: false }) => {
+            let current = load_build(config, &*context, &build_dir)?;
+            println!("{}", current.describe());
+            print!("{}", config.display_setting(current.setting()));
+            Ok(())
+        }
+        Some(BuildCommand::Verify) => {
+            let report = load_build(config, &*context, &build_dir)?.verify_against(config)?;
+            println!("{}", report);
+            if !report.is_ok() {
+                bail!("Build directory verification failed");
+            }
+            Ok(())
+        }
+        Some(BuildCommand::Targets) => {
+            for target in
+                load_build(config, &*context, &build_dir)?.ninja_targets(&apps(config, run)?)?
+            {
+                println!("{}", target);
+            }
+            Ok(())
+        }
+        Some(BuildCommand::List(list)) => list_builds(config, context, list),
+        Some(BuildCommand::Rm { tag, paths }) => remove(context, tag, paths),
+        Some(BuildCommand::Logs { follow }) => {
+            let (_, log) = load_build(config, &*context, &build_dir)?.open_serial_log()?;
+            print_log(log, follow)
+        }
+        Some(BuildCommand::Compare { paths }) => {
+            let load = |path: &PathBuf| {
+                let build_dir = BuildDir {
+                    build_dir: Some(path.clone()),
+                };
+                load_build(config, &*context, &build_dir)
+            };
+            let (first, second) = match paths.as_slice() {
+                [second] => (load_build(config, &*context, &build_dir)?, second),
+                [first, second] => (load(first)?, second),
+                _ => bail!("Give one or two build directories to compare"),
+            };
+            let second = load(second)?;
+            println!("{}", first.compare(&second));
+            Ok(())
+        }
+    }
+}
+
+fn create(
+    config: &Config,
+    run: RunOptions,
+    context: Box<dyn Context>,
+    create: Create,
+) -> Result<()> {
+    let platform = match &create.system {
+        Some(system) => {
+            Apps::try_new(config.defaults())?.machine_queue_platform(system, create.platform)?
+        }
+        None => PlatformChoice::or_env(create.platform)?,
+    };
+    let arch = Sel4Architecture::or_env(create.arch)?;
+    let mut setting = match &create.cmake_args {
+        Some(path) => Setting::from_cmake_args(&read_to_string(path)?, config)?,
+        None => Setting::default(),
+    };
+    setting.merge(Assignment::project_setting(
+        &create.set,
+        config,
+        context.workspace().project(),
+    )?);
+
+    let mut build = context.create_build(
+        config,
+        &create.path,
+        platform.platform().clone(),
+        platform.variation().cloned(),
         arch,
         setting,
-        "sel4test-odroidc2",
-    );
-    let context = context?;
-    project.init_build(&context, &apps, &config)?;
-    context.ninja(&apps)?.status()?;
-    project.mq_run(&context, &config, &apps, None)?;
+        create.force,
+    )?;
+    for tag in create.tag {
+        build.add_tag(tag);
+    }
+    build.save()?;
+
+    if !create.no_configure {
+        let project = config.find_project(build.project())?;
+        let apps = apps(config, run)?;
+        project.init_build(&build, &apps, config)?;
+        build.check_image_digest(&apps)?;
+    }
+    println!("Created {}", build.describe());
+    Ok(())
+}
+
+fn matrix(
+    config: &Config,
+    run: RunOptions,
+    context: Box<dyn Context>,
+    matrix: Matrix,
+) -> Result<()> {
+    let spec = MatrixSpec::new(matrix.platforms, matrix.architectures, matrix.presets);
+    for mut build in context.workspace().create_matrix(config, &spec)? {
+        for tag in &matrix.tag {
+            build.add_tag(tag.clone());
+        }
+        build.save()?;
+
+        if !matrix.no_configure {
+            let project = config.find_project(build.project())?;
+            let apps = apps(config, run)?;
+            project.init_build(&build, &apps, config)?;
+            build.check_image_digest(&apps)?;
+        }
+        println!("{}  {}", build.build_root().display(), build.describe());
+    }
+    Ok(())
+}
+
+fn remove(context: Box<dyn Context>, tag: Option<String>, paths: Vec<PathBuf>) -> Result<()> {
+    if tag.is_none() && paths.is_empty() {
+        bail!("No builds to remove; give build directories or --tag");
+    }
 
-    // apps.repo().arg("init").arg("--help").status()?;
-    // let context = context.builds().next().unwrap()?;
-    // context.docker(&apps)?.run("/bin/bash").status()?;
+    let mut workspace = context.workspace().clone();
+    if let Some(tag) = tag {
+        for path in workspace.remove_tagged(&tag)? {
+            println!("Removed {}", path.display());
+        }
+    }
+    for path in paths {
+        let build = BuildContext::load(&workspace, &path)?;
+        workspace.remove_build(&build)?;
+        println!("Removed {}", path.display());
+    }
+    Ok(())
+}
+
+fn build_all(
+    config: &Config,
+    apps: &Apps,
+    context: Box<dyn Context>,
+    stale_only: bool,
+) -> Result<()> {
+    let workspace = context.workspace();
+    let builds = if stale_only {
+        workspace.stale_builds(config)?
+    } else {
+        workspace.builds_parallel()?
+    };
+    for mut build in builds {
+        println!("Building {}", build.describe());
+        build.build(apps, config)?;
+    }
+    Ok(())
+}
+
+fn doctor(config: &Config, context: Option<Box<dyn Context>>) -> Result<()> {
+    let apps = Apps::try_new(config.defaults())?;
+    println!("{}", apps);
+
+    apps.check_image_tools()?;
+    println!(
+        "Docker image {} provides the required build tools",
+        config.defaults().docker_image()
+    );
 
+    if let Some(context) = context {
+        context.workspace().check_synced()?;
+        println!("Workspace {} is synced", context.workspace_root().display());
+    }
     Ok(())
 }
@@ -0,0 +1,515 @@
+//! Command line options
+
+use anyhow::{bail, Error, Result};
+use s4::{
+    Config, FlagId, PlatformChoice, PlatformId, ProjectId, ScriptId, Sel4Architecture, Setting,
+    Value,
+};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// The sel4 build management tool
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// Load only the builtin configuration and this configuration file
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Treat warnings about settings (such as deprecated flags) as errors
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Fail if two configuration files set the same default to different values
+    ///
+    /// This can not be combined with --config, which only loads a single file.
+    #[structopt(long, conflicts_with = "config")]
+    pub strict_config: bool,
+
+    #[structopt(flatten)]
+    pub run: RunOptions,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+/// Options controlling how repo, docker, and the machine queue are run
+#[derive(Debug, Clone, Copy, StructOpt)]
+pub struct RunOptions {
+    /// Keep containers that fail for debugging rather than removing them
+    #[structopt(long)]
+    pub keep_on_failure: bool,
+
+    /// Print the repo, docker, and machine queue commands that would be run instead of running them
+    ///
+    /// Only external commands are skipped. The files s4 manages itself are still written, so `init`
+    /// and `build create` still create the workspace and build directories with their
+    /// .s4-workspace.toml and .s4-build.toml files.
+    #[structopt(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Show the version of s4 and the tools it has found
+    Version,
+    /// Show the workspace and build of the current directory
+    Env,
+    /// Create a new workspace and sync the project sources
+    Init(Init),
+    /// Re-sync the sources of the current workspace
+    Sync,
+    /// Write the definition of the current workspace and its builds as TOML
+    Export {
+        /// File to write the definition to (instead of printing it)
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Create a workspace and its builds from an exported definition
+    Import(Import),
+    /// List platforms or builds
+    List(List),
+    /// Show the definition of a platform
+    Platform(PlatformCommand),
+    /// List the flags that can be set for builds
+    Flags {
+        /// Show whether each flag was defined by the configuration or the easy settings
+        #[structopt(long)]
+        sources: bool,
+    },
+    /// Build the current build directory or manage build directories
+    Build(Build),
+    /// Create a build for every combination of platforms, architectures, and presets
+    Matrix(Matrix),
+    /// Build every build directory in the workspace
+    BuildAll {
+        /// Only build directories whose sources changed since they were last built
+        #[structopt(long)]
+        stale_only: bool,
+    },
+    /// Run a build in simulation or on hardware, or run a configured script
+    Run {
+        #[structopt(flatten)]
+        build_dir: BuildDir,
+
+        /// Machine queue system to run on
+        #[structopt(long)]
+        system: Option<String>,
+
+        /// Script to run instead of the build
+        #[structopt(parse(from_str))]
+        script: Option<ScriptId>,
+
+        /// Arguments passed to the script
+        args: Vec<String>,
+    },
+    /// Use systems in the machine queue
+    Board(BoardCommand),
+    /// Check that the tools needed by s4 are available
+    Doctor,
+    /// Remove all downloaded copies of tools
+    CleanTools,
+    /// Manage the user configuration
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Init {
+    /// Project to create the workspace for (defaults to the default project)
+    #[structopt(short, long, parse(from_str))]
+    pub project: Option<ProjectId>,
+
+    /// Create the workspace even if the directory is not empty
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Add the cache and build directories to the .gitignore of the workspace
+    #[structopt(long)]
+    pub gitignore: bool,
+
+    /// Only create the workspace without fetching the project sources
+    #[structopt(long)]
+    pub no_sync: bool,
+
+    /// Directory of the workspace
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Import {
+    /// Only create the workspace and builds without fetching the project sources
+    #[structopt(long)]
+    pub no_sync: bool,
+
+    /// Exported workspace definition
+    #[structopt(parse(from_os_str))]
+    pub file: PathBuf,
+
+    /// Directory of the new workspace
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum List {
+    /// List all known platforms
+    Platforms,
+    /// List the builds of the current workspace
+    Builds(ListBuilds),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ListBuilds {
+    /// Only list builds with a tag
+    #[structopt(long)]
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum PlatformCommand {
+    /// Show a platform with its variations and the flags they set
+    Show {
+        #[structopt(parse(from_str))]
+        platform: PlatformId,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum BoardCommand {
+    /// Reserve a system and attach to its serial console, releasing it on exit
+    Console {
+        /// Machine queue system to attach to
+        #[structopt(short, long)]
+        system: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Build {
+    #[structopt(flatten)]
+    pub build_dir: BuildDir,
+
+    #[structopt(subcommand)]
+    pub command: Option<BuildCommand>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BuildDir {
+    /// Build directory to use instead of the current build directory
+    #[structopt(short = "B", long, parse(from_os_str))]
+    pub build_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum BuildCommand {
+    /// Create and configure a new build directory
+    Create(Create),
+    /// Add an existing build directory that was configured without s4
+    Adopt {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Re-run CMake for the build directory
+    #[structopt(alias = "reconfigure")]
+    Configure {
+        /// Run CMake even if the setting has not changed
+        #[structopt(long)]
+        force: bool,
+
+        /// Discard the CMake cache and configure from scratch, keeping build outputs
+        #[structopt(long)]
+        fresh: bool,
+    },
+    /// Show the configuration of the build directory
+    Show {
+        /// Print the CMake arguments used to configure the build instead
+        #[structopt(long)]
+        cmake_args: bool,
+    },
+    /// Check the consistency of the build directory
+    Verify,
+    /// List the targets that can be built
+    Targets,
+    /// List the builds of the current workspace
+    List(ListBuilds),
+    /// Remove build directories from the workspace
+    Rm {
+        /// Remove every build with a tag
+        #[structopt(long)]
+        tag: Option<String>,
+
+        /// Build directories to remove
+        #[structopt(parse(from_os_str))]
+        paths: Vec<PathBuf>,
+    },
+    /// Print the most recent serial log captured when the build was run
+    Logs {
+        /// Keep printing output as it is added to the log
+        #[structopt(short, long)]
+        follow: bool,
+    },
+    /// Compare the configuration of two build directories
+    ///
+    /// If only one build directory is given, it is compared with the current build directory.
+    Compare {
+        #[structopt(parse(from_os_str), required = true, min_values = 1, max_values = 2)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Create {
+    /// Platform to build for, optionally with a variation (`platform:variation`)
+    #[structopt(short, long)]
+    pub platform: Option<PlatformChoice>,
+
+    /// Architecture to build for
+    #[structopt(short, long)]
+    pub arch: Option<Sel4Architecture>,
+
+    /// Machine queue system the build will run on, used as the platform if none is given
+    #[structopt(long)]
+    pub system: Option<String>,
+
+    /// Set a flag for the build (`flag=value`)
+    #[structopt(short, long, number_of_values = 1)]
+    pub set: Vec<Assignment>,
+
+    /// Set flags from a file of CMake arguments (`-DVar=Value` lines), before any --set
+    #[structopt(long, parse(from_os_str))]
+    pub cmake_args: Option<PathBuf>,
+
+    /// Tag the build
+    #[structopt(short, long, number_of_values = 1)]
+    pub tag: Vec<String>,
+
+    /// Create the build even if the directory is not empty
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Only create the build directory without running CMake
+    #[structopt(long)]
+    pub no_configure: bool,
+
+    /// Directory of the build
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Matrix {
+    /// Platforms to build for, optionally with a variation (`platform:variation`)
+    #[structopt(short, long = "platform", number_of_values = 1, required = true)]
+    pub platforms: Vec<PlatformChoice>,
+
+    /// Architectures to build for
+    #[structopt(short, long = "arch", number_of_values = 1, required = true)]
+    pub architectures: Vec<Sel4Architecture>,
+
+    /// Presets to build with (each combination is built once without a preset if none are given)
+    #[structopt(long = "preset", number_of_values = 1)]
+    pub presets: Vec<String>,
+
+    /// Tag the builds
+    #[structopt(short, long, number_of_values = 1)]
+    pub tag: Vec<String>,
+
+    /// Only create the build directories without running CMake
+    #[structopt(long)]
+    pub no_configure: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Save the project used when no project is given
+    SetProject {
+        #[structopt(parse(from_str))]
+        project: ProjectId,
+    },
+    /// Show the fully merged configuration as TOML
+    Dump {
+        /// Only show the definition of a project
+        #[structopt(long, parse(from_str))]
+        project: Option<ProjectId>,
+
+        /// Only show the definition of a platform
+        #[structopt(long, parse(from_str))]
+        platform: Option<PlatformId>,
+    },
+    /// Show which configuration file defined a flag, platform, project, or script
+    Describe { name: String },
+    /// Show the configured value of each default next to the value that is used
+    Defaults,
+}
+
+/// Assignment of a value to a flag on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment(FlagId, Value);
+
+impl Assignment {
+    /// Combine assignments into a setting
+    pub fn setting(assignments: &[Assignment]) -> Setting {
+        assignments
+            .iter()
+            .map(|Assignment(flag, value)| (flag.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Combine assignments into a setting, allowing only the command line flags of a project
+    pub fn project_setting(
+        assignments: &[Assignment],
+        config: &Config,
+        project: &ProjectId,
+    ) -> Result<Setting> {
+        let available = config.command_line_flags(project);
+        for Assignment(flag, _) in assignments {
+            if !available.contains(flag) {
+                bail!(
+                    "Flag {} can not be set on the command line for project {} (available: {})",
+                    flag,
+                    project.as_ref(),
+                    available
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        Ok(Self::setting(assignments))
+    }
+}
+
+impl FromStr for Assignment {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        let (flag, value) = match string.split_once('=') {
+            Some((flag, value)) if !flag.is_empty() => (flag, value),
+            _ => bail!(
+                "Malformed flag assignment (expected flag=value): {}",
+                string
+            ),
+        };
+        let value = match value {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            // Only text that is exactly a number is a number ("013" and "+5" stay as text)
+            text => match text.parse::<i64>() {
+                Ok(number) if number.to_string() == text => Value::Number(number),
+                _ => Value::Text(text.to_owned()),
+            },
+        };
+        Ok(Assignment(flag.into(), value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_create_options() {
+        let options = Options::from_iter_safe(&[
+            "s4",
+            "build",
+            "create",
+            "--platform",
+            "odroidc2:smp4",
+            "--arch",
+            "aarch64",
+            "--set",
+            "mcs=true",
+            "--set",
+            "kernel-opt-level=-O2",
+            "--set",
+            "root-cnode-bits=13",
+            "mybuild",
+        ])
+        .unwrap();
+
+        let create = match options.command {
+            Command::Build(Build {
+                command: Some(BuildCommand::Create(create)),
+                ..
+            }) => create,
+            command => panic!("Unexpected command: {:?}", command),
+        };
+        assert_eq!(create.platform, Some("odroidc2:smp4".parse().unwrap()));
+        assert_eq!(create.arch, Some(s4::AArch64));
+        assert_eq!(create.path, PathBuf::from("mybuild"));
+
+        let setting = Assignment::setting(&create.set);
+        assert_eq!(setting.flag(&"mcs".into()), Value::Boolean(true));
+        assert_eq!(
+            setting.flag(&"kernel-opt-level".into()),
+            Value::Text("-O2".into())
+        );
+        assert_eq!(setting.flag(&"root-cnode-bits".into()), Value::Number(13));
+
+        for text in &["013", "+5", "-0"] {
+            let assignment: Assignment = format!("text={}", text).parse().unwrap();
+            assert_eq!(assignment.1, Value::Text((*text).to_owned()));
+        }
+        let assignment: Assignment = "offset=-5".parse().unwrap();
+        assert_eq!(assignment.1, Value::Number(-5));
+
+        assert!("=true".parse::<Assignment>().is_err());
+        assert!("mcs".parse::<Assignment>().is_err());
+    }
+
+    #[test]
+    fn strict_config_needs_every_config_file() {
+        assert!(Options::from_iter_safe(&["s4", "--strict-config", "version"]).is_ok());
+        assert!(Options::from_iter_safe(&["s4", "--config", "s4.toml", "version"]).is_ok());
+        assert!(Options::from_iter_safe(&[
+            "s4",
+            "--config",
+            "s4.toml",
+            "--strict-config",
+            "version"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn compare_takes_one_or_two_builds() {
+        let paths = |args: &[&str]| -> Result<Vec<PathBuf>> {
+            let options =
+                Options::from_iter_safe(["s4", "build", "compare"].iter().chain(args.iter()))?;
+            match options.command {
+                Command::Build(Build {
+                    command: Some(BuildCommand::Compare { paths }),
+                    ..
+                }) => Ok(paths),
+                command => panic!("Unexpected command: {:?}", command),
+            }
+        };
+
+        assert_eq!(paths(&["b"]).unwrap(), [PathBuf::from("b")]);
+        assert_eq!(
+            paths(&["a", "b"]).unwrap(),
+            [PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert!(paths(&[]).is_err());
+        assert!(paths(&["a", "b", "c"]).is_err());
+    }
+
+    #[test]
+    fn only_command_line_flags_can_be_set() {
+        let config = Config::builtin().unwrap();
+        let project = "sel4test".into();
+
+        let assignments = ["mcs=true".parse().unwrap(), "smp=false".parse().unwrap()];
+        let setting = Assignment::project_setting(&assignments, &config, &project).unwrap();
+        assert_eq!(setting.flag(&"mcs".into()), Value::Boolean(true));
+
+        let assignments = ["kernel-opt-level=-O2".parse().unwrap()];
+        let error = Assignment::project_setting(&assignments, &config, &project)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("kernel-opt-level"));
+        assert!(error.contains("mcs"));
+    }
+}
@@ -1,9 +1,11 @@
 //! Wrapper for invocations of CMake
 
-use crate::{Merge, MergeId, NameRef, Named};
-use anyhow::{bail, Result};
+use crate::{Config, Merge, MergeId, NameRef, Named, NamedMap};
+use anyhow::{bail, format_err, Result};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::iter::FromIterator;
 use std::process::Command;
@@ -16,16 +18,32 @@ pub struct Flag {
     /// Flag is passed to CMake
     #[serde(default)]
     variable: Option<String>,
+    /// Flag is passed to the build tools as an environment variable
     #[serde(default)]
+    env: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_requires")]
     requires: BTreeSet<BTreeMap<FlagId, Requirement>>,
-    #[serde(default)]
+    #[serde(default, rename = "type")]
     type_: Option<Type>,
+    /// Values the flag may be set to (any value if empty)
+    #[serde(default)]
+    values: BTreeSet<Value>,
+    /// Enable required boolean flags automatically when this flag is set
+    #[serde(default)]
+    auto_enable: bool,
+    /// Message explaining the deprecation of the flag (if it is deprecated)
+    #[serde(default)]
+    deprecated: Option<String>,
 }
 
 impl Merge for Flag {
     fn merge(&mut self, other: Self) {
         self.variable.merge(other.variable);
+        self.env.merge(other.env);
         self.requires.merge(other.requires);
+        self.values.merge(other.values);
+        self.auto_enable |= other.auto_enable;
+        self.deprecated.merge(other.deprecated);
     }
 }
 
@@ -42,19 +60,33 @@ impl Flag {
         let description = description.as_ref().to_owned();
         let variable = variable.map(|var| var.as_ref().to_owned());
         let requires = BTreeSet::new();
-        let type_ = type_;
+        let values = BTreeSet::new();
 
         Flag {
             description,
             variable,
+            env: None,
             requires,
             type_,
+            values,
+            auto_enable: false,
+            deprecated: None,
         }
     }
 
     /// Check that a flag can be set to the given value
     pub fn validate(self_ref: NameRef<Self>, setting: &Setting, value: &Value) -> Result<()> {
-        if self_ref.requires.len() > 0 {
+        if !self_ref.values.is_empty() && !self_ref.values.iter().any(|v| value.matches(v)) {
+            let allowed: Vec<_> = self_ref.values.iter().map(Value::to_string).collect();
+            bail!(
+                "Flag {} can not be set to {} (expected one of: {})",
+                self_ref.name(),
+                value,
+                allowed.join(", ")
+            );
+        }
+
+        if !self_ref.requires.is_empty() {
             match value {
                 Value::Boolean(true) => Self::check_requirements(self_ref, setting),
                 Value::Boolean(false) => Ok(()),
@@ -71,31 +103,149 @@ impl Flag {
         }
     }
 
-    /// Check that requirements are met in a given setting for the flag to be set to true
-    fn check_requirements(self_ref: NameRef<Self>, setting: &Setting) -> Result<()> {
-        let satisfied = self_ref.requires.iter().any(|required| {
+    /// Check if any of the requirement sets are satisfied by a setting
+    fn requirements_met(&self, setting: &Setting) -> bool {
+        self.requires.iter().any(|required| {
             required
                 .iter()
-                .all(|(flag, requirement)| requirement.check(&setting.flag(flag)))
-        });
+                .all(|(flag, requirement)| requirement.check(setting.get(flag)))
+        })
+    }
 
-        if !satisfied {
+    /// Check that requirements are met in a given setting for the flag to be set to true
+    fn check_requirements(self_ref: NameRef<Self>, setting: &Setting) -> Result<()> {
+        if !self_ref.requirements_met(setting) {
+            let unmet: Vec<_> = self_ref
+                .unmet_requirements(setting)
+                .into_iter()
+                .map(|required| {
+                    required
+                        .into_iter()
+                        .map(|(_, requirement)| requirement)
+                        .collect::<Vec<_>>()
+                        .join(" and ")
+                })
+                .collect();
             bail!(
-                "None of the requirement sets for the flag {} could be satisfied",
-                self_ref.name()
+                "None of the requirement sets for the flag {} could be satisfied (needs {})",
+                self_ref.name(),
+                unmet.join(" or ")
             );
         } else {
             Ok(())
         }
     }
 
+    /// The requirements a setting does not meet in each of the requirement sets of the flag
+    ///
+    /// Each requirement is given with the flag it constrains. Nothing is returned if the flag has
+    /// no requirements or any requirement set is met.
+    pub fn unmet_requirements(&self, setting: &Setting) -> Vec<Vec<(&FlagId, String)>> {
+        if self.requires.is_empty() || self.requirements_met(setting) {
+            return Vec::new();
+        }
+
+        self.requires
+            .iter()
+            .map(|required| {
+                required
+                    .iter()
+                    .filter(|(flag, requirement)| !requirement.check(setting.get(flag)))
+                    .map(|(flag, requirement)| (flag, requirement.describe(flag)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Boolean flags that can be enabled to satisfy the requirements of the flag
+    ///
+    /// Only flags that have not been explicitly set are enabled. Returns `None` if the
+    /// requirements are already satisfied or can not be satisfied by enabling flags.
+    pub fn flags_to_enable(self_ref: NameRef<Self>, setting: &Setting) -> Option<Vec<FlagId>> {
+        if self_ref.requires.is_empty() || self_ref.requirements_met(setting) {
+            return None;
+        }
+
+        self_ref.requires.iter().find_map(|required| {
+            let mut enable = Vec::new();
+            for (flag, requirement) in required {
+                if requirement.check(setting.get(flag)) {
+                    continue;
+                }
+                if setting.contains(flag) || !requirement.check(Some(&Value::Boolean(true))) {
+                    return None;
+                }
+                enable.push(flag.clone());
+            }
+            Some(enable)
+        })
+    }
+
+    /// Flags to set so that the requirements of the flag are met
+    ///
+    /// Values are chosen for the first requirement set that can be satisfied, and only flags that
+    /// do not already meet their requirement are included. Returns `None` if no requirement set
+    /// can be satisfied.
+    pub fn satisfying_setting(&self, setting: &Setting) -> Option<Setting> {
+        if self.requires.is_empty() || self.requirements_met(setting) {
+            return Some(Setting::default());
+        }
+
+        self.requires.iter().find_map(|required| {
+            required
+                .iter()
+                .filter(|(flag, requirement)| !requirement.check(setting.get(flag)))
+                .map(|(flag, requirement)| Some((flag.clone(), requirement.satisfying_value()?)))
+                .collect()
+        })
+    }
+
+    /// Whether required boolean flags are enabled automatically when this flag is set
+    pub fn auto_enable(&self) -> bool {
+        self.auto_enable
+    }
+
     /// Set the CMake flag for a build directory
     pub fn cmake_flag(&self, command: &mut Command, value: &Value) {
-        if let Some(variable) = &self.variable {
-            command.arg(format!("-D{}={}", variable, value.cmake_str()));
+        if let Some(arg) = self.cmake_arg(value) {
+            command.arg(arg);
         }
     }
 
+    /// The CMake command line argument that sets the flag (if the flag is passed to CMake)
+    pub fn cmake_arg(&self, value: &Value) -> Option<String> {
+        self.variable
+            .as_ref()
+            .map(|variable| format!("-D{}={}", variable, value.cmake_str()))
+    }
+
+    /// The environment variable set by the flag as a name and value (if the flag is passed in the
+    /// environment)
+    pub fn env_var(&self, value: &Value) -> Option<(String, String)> {
+        self.env
+            .as_ref()
+            .map(|name| (name.clone(), value.cmake_str().into_owned()))
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// The CMake variable set by the flag (if the flag is passed to CMake)
+    pub fn variable(&self) -> Option<&str> {
+        self.variable.as_deref()
+    }
+
+    /// Whether the flag can only be enabled if requirements are met
+    pub fn has_requirements(&self) -> bool {
+        !self.requires.is_empty()
+    }
+
+    /// Message explaining the deprecation of the flag (if it is deprecated)
+    pub fn deprecated(&self) -> Option<&str> {
+        self.deprecated.as_deref()
+    }
+
     pub fn ty(&self) -> Option<Type> {
         self.type_
     }
@@ -131,19 +281,64 @@ impl AsRef<str> for FlagId {
 }
 
 /// A required setting for a particular flag
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum Requirement {
     /// Requires that a flag be set to a specific value
     Single(Value),
     /// Requires that a flag be set to any of a set of values
     Any(BTreeSet<Value>),
+    /// Requires that a flag not be set to a specific value
+    Not(Value),
+    /// Requires that a flag not be set to any of a set of values
+    NotAny(BTreeSet<Value>),
 }
 
 impl Requirement {
-    fn check(&self, value: &Value) -> bool {
+    /// Check whether the value of a flag (or `None` if it is unset) meets the requirement
+    ///
+    /// An unset flag has no value, so it never meets a requirement for a value (even `false`) but
+    /// always meets a requirement not to be set to a value.
+    fn check(&self, value: Option<&Value>) -> bool {
+        match (self, value) {
+            (Requirement::Single(_), None) | (Requirement::Any(_), None) => false,
+            (Requirement::Not(_), None) | (Requirement::NotAny(_), None) => true,
+            (Requirement::Single(required), Some(value)) => value.matches(required),
+            (Requirement::Any(requirement), Some(value)) => {
+                requirement.iter().any(|v| value.matches(v))
+            }
+            (Requirement::Not(forbidden), Some(value)) => !value.matches(forbidden),
+            (Requirement::NotAny(forbidden), Some(value)) => {
+                !forbidden.iter().any(|v| value.matches(v))
+            }
+        }
+    }
+
+    /// Describe the requirement as a constraint on a flag
+    fn describe(&self, flag: &FlagId) -> String {
+        fn list(values: &BTreeSet<Value>) -> String {
+            let values: Vec<_> = values.iter().map(Value::to_string).collect();
+            values.join(", ")
+        }
+
         match self {
-            Requirement::Single(required) => value == required,
-            Requirement::Any(requirement) => requirement.contains(value),
+            Requirement::Single(required) => format!("{} = {}", flag, required),
+            Requirement::Any(required) => format!("{} in [{}]", flag, list(required)),
+            Requirement::Not(forbidden) => format!("{} != {}", flag, forbidden),
+            Requirement::NotAny(forbidden) => format!("{} not in [{}]", flag, list(forbidden)),
+        }
+    }
+
+    /// A value that meets the requirement (if one can be chosen)
+    ///
+    /// Negative requirements can only be met by choosing a boolean value.
+    fn satisfying_value(&self) -> Option<Value> {
+        match self {
+            Requirement::Single(required) => Some(required.clone()),
+            Requirement::Any(requirement) => requirement.iter().next().cloned(),
+            Requirement::Not(_) | Requirement::NotAny(_) => [false, true]
+                .iter()
+                .map(|value| Value::Boolean(*value))
+                .find(|value| self.check(Some(value))),
         }
     }
 }
@@ -158,13 +353,20 @@ impl RequirementVisitor {
     fn from_string<E>(v: impl ToString) -> Result<Requirement, E> {
         Ok(Requirement::Single(Value::Text(v.to_string())))
     }
+
+    fn from_number<E>(v: impl TryInto<i64> + ToString) -> Result<Requirement, E> {
+        Ok(Requirement::Single(Value::from_integer(v)))
+    }
 }
 
 impl<'de> de::Visitor<'de> for RequirementVisitor {
     type Value = Requirement;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "a boolean or string value")
+        write!(
+            formatter,
+            "a boolean or string value, a list of values, or a table with a 'not' key"
+        )
     }
 
     fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
@@ -180,43 +382,43 @@ impl<'de> de::Visitor<'de> for RequirementVisitor {
     }
 
     fn visit_i8<E: de::Error>(self, v: i8) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_i16<E: de::Error>(self, v: i16) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_i32<E: de::Error>(self, v: i32) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_i128<E: de::Error>(self, v: i128) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_u8<E: de::Error>(self, v: u8) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_u16<E: de::Error>(self, v: u16) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_u32<E: de::Error>(self, v: u32) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_u128<E: de::Error>(self, v: u128) -> Result<Self::Value, E> {
-        Self::from_string(v)
+        Self::from_number(v)
     }
 
     fn visit_f32<E: de::Error>(self, v: f32) -> Result<Self::Value, E> {
@@ -234,6 +436,46 @@ impl<'de> de::Visitor<'de> for RequirementVisitor {
         }
         Ok(Requirement::Any(values))
     }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let requirement = match map.next_key::<String>()?.as_deref() {
+            Some("not") => match map.next_value()? {
+                Requirement::Single(value) => Requirement::Not(value),
+                Requirement::Any(values) => Requirement::NotAny(values),
+                _ => return Err(de::Error::custom("'not' requirements cannot be nested")),
+            },
+            Some(key) => return Err(de::Error::unknown_field(key, &["not"])),
+            None => return Err(de::Error::missing_field("not")),
+        };
+
+        if let Some(key) = map.next_key::<String>()? {
+            return Err(de::Error::unknown_field(&key, &["not"]));
+        }
+
+        Ok(requirement)
+    }
+}
+
+/// Requirements are serialized in the same form as they are written in the configuration
+impl Serialize for Requirement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        match self {
+            Requirement::Single(value) => value.serialize(serializer),
+            Requirement::Any(values) => values.serialize(serializer),
+            Requirement::Not(value) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("not", value)?;
+                map.end()
+            }
+            Requirement::NotAny(values) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("not", values)?;
+                map.end()
+            }
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for Requirement {
@@ -242,6 +484,49 @@ impl<'de> Deserialize<'de> for Requirement {
     }
 }
 
+/// A set of requirements that must all be satisfied together
+///
+/// Deserialization rejects a flag being listed more than once in the same set.
+struct RequirementSet(BTreeMap<FlagId, Requirement>);
+
+struct RequirementSetVisitor;
+
+impl<'de> de::Visitor<'de> for RequirementSetVisitor {
+    type Value = RequirementSet;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a table of flag requirements")
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut requirements = BTreeMap::new();
+        while let Some(flag) = map.next_key::<FlagId>()? {
+            let requirement = map.next_value()?;
+            if requirements.contains_key(&flag) {
+                return Err(de::Error::custom(format!(
+                    "flag {} is listed more than once in a requirement set",
+                    flag
+                )));
+            }
+            requirements.insert(flag, requirement);
+        }
+        Ok(RequirementSet(requirements))
+    }
+}
+
+impl<'de> Deserialize<'de> for RequirementSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(RequirementSetVisitor)
+    }
+}
+
+fn deserialize_requires<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeSet<BTreeMap<FlagId, Requirement>>, D::Error> {
+    let sets = Vec::<RequirementSet>::deserialize(deserializer)?;
+    Ok(sets.into_iter().map(|set| set.0).collect())
+}
+
 /// Type of value assigned to an option
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub enum Type {
@@ -249,6 +534,33 @@ pub enum Type {
     Boolean,
     #[serde(rename = "string")]
     Text,
+    #[serde(rename = "int", alias = "integer")]
+    Integer,
+}
+
+impl Type {
+    /// Convert a value to this type
+    fn coerce(self, value: Value) -> Result<Value> {
+        match (self, value) {
+            (Type::Boolean, value @ Value::Boolean(_)) => Ok(value),
+            (Type::Boolean, Value::Text(text)) => match text.to_uppercase().as_str() {
+                "TRUE" | "ON" | "YES" | "1" => Ok(Value::Boolean(true)),
+                "FALSE" | "OFF" | "NO" | "0" => Ok(Value::Boolean(false)),
+                _ => bail!("Invalid boolean value: {}", text),
+            },
+            (Type::Boolean, Value::Number(1)) => Ok(Value::Boolean(true)),
+            (Type::Boolean, Value::Number(0)) => Ok(Value::Boolean(false)),
+            (Type::Boolean, value) => bail!("Invalid boolean value: {}", value),
+            (Type::Text, value @ Value::Text(_)) => Ok(value),
+            (Type::Text, value) => Ok(Value::Text(value.to_string())),
+            (Type::Integer, value @ Value::Number(_)) => Ok(value),
+            (Type::Integer, Value::Text(text)) => match text.parse() {
+                Ok(number) => Ok(Value::Number(number)),
+                Err(_) => bail!("Invalid integer value: {}", text),
+            },
+            (Type::Integer, value) => bail!("Invalid integer value: {}", value),
+        }
+    }
 }
 
 /// Value assigned to an option
@@ -256,21 +568,53 @@ pub enum Type {
 pub enum Value {
     Boolean(bool),
     Text(String),
+    Number(i64),
 }
 
 impl Value {
     pub fn is_bool(&self) -> bool {
-        match self {
-            Value::Boolean(_) => true,
-            _ => false,
+        matches!(self, Value::Boolean(_))
+    }
+
+    /// Infer the type of a value given to CMake
+    ///
+    /// CMake boolean constants are booleans and integers are numbers; anything else is text.
+    fn infer(text: &str) -> Value {
+        match text.to_uppercase().as_str() {
+            "TRUE" | "ON" | "YES" => Value::Boolean(true),
+            "FALSE" | "OFF" | "NO" => Value::Boolean(false),
+            _ => match text.parse() {
+                Ok(number) => Value::Number(number),
+                Err(_) => Value::Text(text.to_owned()),
+            },
         }
     }
 
-    fn cmake_str(&self) -> &str {
+    /// Check if two values are equal, treating numbers as equal to the same number given as text
+    fn matches(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(_), Value::Text(_)) | (Value::Text(_), Value::Number(_)) => {
+                self.cmake_str() == other.cmake_str()
+            }
+            _ => self == other,
+        }
+    }
+
+    /// A number if it fits in an `i64`, and text otherwise
+    fn from_integer(value: impl TryInto<i64> + ToString) -> Self {
+        let text = value.to_string();
+        match value.try_into() {
+            Ok(number) => Value::Number(number),
+            Err(_) => Value::Text(text),
+        }
+    }
+
+    fn cmake_str(&self) -> Cow<'_, str> {
         match self {
-            Value::Boolean(true) => "ON",
-            Value::Boolean(false) => "OFF",
-            Value::Text(text) => text.as_str(),
+            Value::Boolean(true) => "ON".into(),
+            Value::Boolean(false) => "OFF".into(),
+            Value::Text(text) => text.as_str().into(),
+            Value::Number(number) => number.to_string().into(),
         }
     }
 }
@@ -280,19 +624,44 @@ impl fmt::Display for Value {
         match self {
             Value::Boolean(value) => fmt::Display::fmt(value, f),
             Value::Text(value) => fmt::Display::fmt(value, f),
+            Value::Number(value) => fmt::Display::fmt(value, f),
         }
     }
 }
 
 impl MergeId for Value {}
 
+impl MergeId for FlagId {}
+
 struct ValueVisitor;
 
 impl<'de> de::Visitor<'de> for ValueVisitor {
     type Value = Value;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "a boolean or string value")
+        write!(
+            formatter,
+            "a boolean or string value, or a table with a type and value"
+        )
+    }
+
+    /// Values with an explicit type, e.g. `{ type = "string", value = "true" }`
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut type_: Option<Type> = None;
+        let mut value: Option<Value> = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "type" if type_.is_none() => type_ = Some(map.next_value()?),
+                "value" if value.is_none() => value = Some(map.next_value()?),
+                "type" | "value" => return Err(de::Error::duplicate_field("type or value")),
+                key => return Err(de::Error::unknown_field(key, &["type", "value"])),
+            }
+        }
+
+        let type_ = type_.ok_or_else(|| de::Error::missing_field("type"))?;
+        let value = value.ok_or_else(|| de::Error::missing_field("value"))?;
+        type_.coerce(value).map_err(de::Error::custom)
     }
 
     fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
@@ -308,43 +677,43 @@ impl<'de> de::Visitor<'de> for ValueVisitor {
     }
 
     fn visit_i8<E: de::Error>(self, v: i8) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_i16<E: de::Error>(self, v: i16) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_i32<E: de::Error>(self, v: i32) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_i128<E: de::Error>(self, v: i128) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_u8<E: de::Error>(self, v: u8) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_u16<E: de::Error>(self, v: u16) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_u32<E: de::Error>(self, v: u32) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_u128<E: de::Error>(self, v: u128) -> Result<Self::Value, E> {
-        Ok(Value::Text(v.to_string()))
+        Ok(Value::from_integer(v))
     }
 
     fn visit_f32<E: de::Error>(self, v: f32) -> Result<Self::Value, E> {
@@ -367,19 +736,61 @@ impl Serialize for Value {
         match self {
             Value::Boolean(value) => serializer.serialize_bool(*value),
             Value::Text(value) => serializer.serialize_str(value),
+            Value::Number(value) => serializer.serialize_i64(*value),
         }
     }
 }
 
 /// Setting a set of options to particular values
+///
+/// A setting may also remove flags, which are listed as `unset = [ "flag", ... ]` alongside the
+/// flag values. Removing a flag only matters when settings are merged: the removal clears any value
+/// for the flag from earlier layers and later layers may set the flag again (see the `Merge`
+/// implementation).
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
-pub struct Setting(#[serde(default)] BTreeMap<FlagId, Value>);
+#[serde(try_from = "SettingRepr", into = "SettingRepr")]
+pub struct Setting {
+    flags: BTreeMap<FlagId, Value>,
+    removed: BTreeSet<FlagId>,
+}
+
+/// Serialized form of a setting with the removed flags listed under `unset`
+#[derive(Deserialize, Serialize)]
+struct SettingRepr {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    unset: BTreeSet<FlagId>,
+    #[serde(flatten)]
+    flags: BTreeMap<FlagId, Value>,
+}
+
+impl TryFrom<SettingRepr> for Setting {
+    type Error = String;
+
+    fn try_from(repr: SettingRepr) -> std::result::Result<Self, Self::Error> {
+        if let Some(flag) = repr.unset.iter().find(|flag| repr.flags.contains_key(flag)) {
+            return Err(format!("Flag {} is both set and unset", flag));
+        }
+        Ok(Setting {
+            flags: repr.flags,
+            removed: repr.unset,
+        })
+    }
+}
+
+impl From<Setting> for SettingRepr {
+    fn from(setting: Setting) -> Self {
+        SettingRepr {
+            unset: setting.removed,
+            flags: setting.flags,
+        }
+    }
+}
 
 impl fmt::Display for Setting {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{{")?;
         let mut empty = true;
-        for (id, value) in self.0.iter() {
+        for (id, value) in self.flags.iter() {
             if empty {
                 empty = false;
             } else {
@@ -396,13 +807,24 @@ impl fmt::Display for Setting {
 
 impl FromIterator<(FlagId, Value)> for Setting {
     fn from_iter<T: IntoIterator<Item = (FlagId, Value)>>(iter: T) -> Self {
-        Setting(iter.into_iter().collect())
+        Setting {
+            flags: iter.into_iter().collect(),
+            removed: BTreeSet::new(),
+        }
     }
 }
 
+/// Flags set in `other` replace those in `self` and flags removed in `other` are removed from
+/// `self`, so the last layer to mention a flag decides whether it is set. Removals are kept in the
+/// merged setting so that they also apply when it is merged over another setting.
 impl Merge for Setting {
     fn merge(&mut self, other: Self) {
-        self.0.merge(other.0);
+        for flag in &other.removed {
+            self.flags.remove(flag);
+        }
+        self.removed.retain(|flag| !other.flags.contains_key(flag));
+        self.removed.extend(other.removed);
+        Merge::merge(&mut self.flags, other.flags);
     }
 }
 
@@ -410,32 +832,557 @@ impl Setting {
     const PLATFORM_FLAG: &'static str = "platform";
     const KERNEL_PLATFORM_FLAG: &'static str = "kernel-platform";
 
+    /// Parse a setting from lines of CMake arguments (`-DVar=Value`) or `VAR=VALUE` pairs
+    ///
+    /// Variables are mapped back to the flags that set them. Values are converted to the type of
+    /// the flag, or to the type given with the variable (`-DVar:BOOL=ON`), and otherwise inferred
+    /// from the value. Variables that are not set by any flag are skipped with a warning. Blank
+    /// lines and lines starting with `#` are ignored.
+    pub fn from_cmake_args(args: &str, config: &Config) -> Result<Setting> {
+        let flags = config.variable_index();
+        let mut setting = Setting::default();
+
+        for (number, line) in args.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let assignment = line.strip_prefix("-D").unwrap_or(line).trim_start();
+            let (variable, value) = match assignment.split_once('=') {
+                Some((variable, value)) if !variable.is_empty() => (variable, value),
+                _ => bail!(
+                    "Malformed CMake argument on line {} (expected -DVar=Value): {}",
+                    number + 1,
+                    line
+                ),
+            };
+            let (variable, hint) = match variable.split_once(':') {
+                Some((variable, "BOOL")) => (variable, Some(Type::Boolean)),
+                Some((variable, _)) => (variable, Some(Type::Text)),
+                None => (variable, None),
+            };
+            let value = value.trim_matches('"');
+
+            let flag = match flags.get(variable) {
+                Some(flag) => flag,
+                None => {
+                    eprintln!("Warning: no flag sets the CMake variable {}", variable);
+                    continue;
+                }
+            };
+            let value = match config.flag(flag).and_then(|flag| flag.ty()).or(hint) {
+                Some(type_) => type_.coerce(Value::Text(value.to_owned())).map_err(|e| {
+                    format_err!(
+                        "Invalid value for {} on line {}: {}",
+                        variable,
+                        number + 1,
+                        e
+                    )
+                })?,
+                None => Value::infer(value),
+            };
+            setting.insert((*flag).clone(), value);
+        }
+
+        Ok(setting)
+    }
+
     /// Get the setting of all of the flags
     pub fn flags(&self) -> impl Iterator<Item = (&FlagId, &Value)> {
-        self.0.iter()
+        self.flags.iter()
+    }
+
+    /// Get the value of a flag if it has been set
+    pub fn get(&self, flag: &FlagId) -> Option<&Value> {
+        self.flags.get(flag)
     }
 
     /// Get the setting of a particular flag
+    ///
+    /// Flags that have not been set are reported as `false`; use `get` to tell an unset flag apart
+    /// from one that is explicitly `false`.
     pub fn flag(&self, flag: &FlagId) -> Value {
-        self.0.get(flag).cloned().unwrap_or(Value::Boolean(false))
+        self.flags
+            .get(flag)
+            .cloned()
+            .unwrap_or(Value::Boolean(false))
+    }
+
+    /// Format the setting as a table with one flag per line
+    ///
+    /// Columns are aligned and the description of each flag is included if it is defined in
+    /// `flags`.
+    pub fn display_table(&self, flags: &NamedMap<Flag>) -> String {
+        let rows = self
+            .flags
+            .iter()
+            .map(|(id, value)| {
+                let description = flags
+                    .get(id)
+                    .map(|flag| flag.description.clone())
+                    .unwrap_or_default();
+                (id.to_string(), value.to_string(), description)
+            })
+            .collect::<Vec<_>>();
+        let id_width = rows.iter().map(|(id, _, _)| id.len()).max().unwrap_or(0);
+        let value_width = rows
+            .iter()
+            .map(|(_, value, _)| value.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut table = String::new();
+        for (id, value, description) in rows {
+            let line = format!(
+                "{:id_width$}  {:value_width$}  {}",
+                id,
+                value,
+                description,
+                id_width = id_width,
+                value_width = value_width
+            );
+            table.push_str(line.trim_end());
+            table.push('\n');
+        }
+        table
+    }
+
+    /// Check if two settings would configure CMake identically
+    ///
+    /// Values are compared by the value passed to CMake. A flag that is not set, or has been
+    /// removed, leaves CMake to use its default and so differs from any explicit value.
+    pub fn semantically_eq(&self, other: &Setting) -> bool {
+        self.difference(other).is_empty()
+    }
+
+    /// Find the flags that would configure CMake differently between two settings
+    ///
+    /// Each flag is listed once with its value in this setting followed by its value in `other`,
+    /// or `None` where it is not set, using the same comparison as `semantically_eq`.
+    pub fn difference(&self, other: &Setting) -> Vec<(FlagId, Option<Value>, Option<Value>)> {
+        let flags = self
+            .flags
+            .keys()
+            .chain(&self.removed)
+            .chain(other.flags.keys())
+            .chain(&other.removed)
+            .collect::<BTreeSet<_>>();
+        flags
+            .into_iter()
+            .filter_map(|flag| {
+                let (left, right) = (self.get(flag), other.get(flag));
+                if left.map(Value::cmake_str) == right.map(Value::cmake_str) {
+                    None
+                } else {
+                    Some((flag.clone(), left.cloned(), right.cloned()))
+                }
+            })
+            .collect()
+    }
+
+    /// Check if a flag has been explicitly set
+    pub fn contains(&self, flag: &FlagId) -> bool {
+        self.flags.contains_key(flag)
+    }
+
+    /// Check if a flag has been explicitly removed
+    pub fn is_unset(&self, flag: &FlagId) -> bool {
+        self.removed.contains(flag)
+    }
+
+    /// The flags that have been explicitly removed
+    pub fn unset_flags(&self) -> impl Iterator<Item = &FlagId> {
+        self.removed.iter()
+    }
+
+    /// Remove a flag from the setting
+    ///
+    /// The flag is no longer passed to CMake and the removal is recorded so that merging this
+    /// setting over another also removes the flag there.
+    pub fn unset(&mut self, flag: &FlagId) {
+        self.flags.remove(flag);
+        self.removed.insert(flag.clone());
+    }
+
+    fn insert(&mut self, flag: FlagId, value: Value) {
+        self.removed.remove(&flag);
+        self.flags.insert(flag, value);
+    }
+
+    /// Move the value of a flag to a new flag identifier
+    ///
+    /// Returns `false` if the original flag was not set.
+    pub fn rename(&mut self, from: &FlagId, to: FlagId) -> bool {
+        match self.flags.remove(from) {
+            Some(value) => {
+                self.insert(to, value);
+                true
+            }
+            None => false,
+        }
     }
 
     /// Set a particular setting to a boolean value
     pub fn set_bool(&mut self, flag: impl Into<FlagId>, value: bool) {
-        self.0.insert(flag.into(), Value::Boolean(value));
+        self.insert(flag.into(), Value::Boolean(value));
     }
 
     /// Set a particular setting to a text value
     pub fn set_text(&mut self, flag: impl Into<FlagId>, value: impl AsRef<str>) {
-        self.0
-            .insert(flag.into(), Value::Text(value.as_ref().to_owned()));
+        self.insert(flag.into(), Value::Text(value.as_ref().to_owned()));
     }
 
+    pub fn set_number(&mut self, flag: impl Into<FlagId>, value: i64) {
+        self.insert(flag.into(), Value::Number(value));
+    }
+
+    /// Set the platform used by cmake-tool
+    ///
+    /// This is the name of the platform, or of the variation if one is chosen, unless the platform
+    /// or variation sets the `platform` flag itself. Unlike `kernel-platform`, this is the only
+    /// platform flag changed by a variation.
     pub fn set_platform(&mut self, platform: impl AsRef<str>) {
         self.set_text(Self::PLATFORM_FLAG, platform);
     }
 
+    /// Set the platform recognised by seL4
+    ///
+    /// This is always the name of the platform, even when a variation is chosen.
     pub fn set_kernel_platform(&mut self, platform: impl AsRef<str>) {
         self.set_text(Self::KERNEL_PLATFORM_FLAG, platform);
     }
+
+    /// The platform used by cmake-tool (if set)
+    pub fn platform(&self) -> Option<&str> {
+        self.text(Self::PLATFORM_FLAG)
+    }
+
+    /// The platform recognised by seL4 (if set)
+    pub fn kernel_platform(&self) -> Option<&str> {
+        self.text(Self::KERNEL_PLATFORM_FLAG)
+    }
+
+    fn text(&self, flag: &str) -> Option<&str> {
+        match self.flags.get(&FlagId::from(flag)) {
+            Some(Value::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirements(toml: &str) -> BTreeMap<FlagId, Requirement> {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn setting_table() {
+        let flags: NamedMap<Flag> = toml::from_str(
+            r#"
+            mcs = { description = "Configure kernel to use MCS scheduler" }
+            platform = { description = "Set the platform used when building" }
+            "#,
+        )
+        .unwrap();
+
+        let mut setting = Setting::default();
+        setting.set_platform("odroidc2");
+        setting.set_bool("mcs", true);
+        setting.set_bool("has-hypervisor", false);
+
+        assert_eq!(
+            setting.display_table(&flags),
+            "has-hypervisor  false\n\
+             mcs             true      Configure kernel to use MCS scheduler\n\
+             platform        odroidc2  Set the platform used when building\n"
+        );
+    }
+
+    #[test]
+    fn semantically_equal_settings() {
+        let mut setting = Setting::default();
+        setting.set_bool("mcs", true);
+        let mut other = setting.clone();
+        other.set_text("mcs", "ON");
+        assert!(setting.semantically_eq(&other));
+        assert_ne!(setting, other);
+
+        other.set_bool("smp", false);
+        assert!(!setting.semantically_eq(&other));
+        assert_eq!(
+            setting.difference(&other),
+            vec![("smp".into(), None, Some(Value::Boolean(false)))]
+        );
+
+        other.unset(&"smp".into());
+        assert!(setting.semantically_eq(&other));
+        setting.set_bool("smp", true);
+        assert_eq!(
+            other.difference(&setting),
+            vec![("smp".into(), None, Some(Value::Boolean(true)))]
+        );
+    }
+
+    #[test]
+    fn negated_requirements() {
+        let required = requirements(
+            r#"
+            verification = { not = true }
+            platform = { not = ["pc99", "spike"] }
+            "#,
+        );
+
+        let not_true = &required[&"verification".into()];
+        assert_eq!(*not_true, Requirement::Not(Value::Boolean(true)));
+        assert!(not_true.check(Some(&Value::Boolean(false))));
+        assert!(!not_true.check(Some(&Value::Boolean(true))));
+
+        let not_any = &required[&"platform".into()];
+        assert!(not_any.check(Some(&Value::Text("odroidc2".into()))));
+        assert!(!not_any.check(Some(&Value::Text("spike".into()))));
+    }
+
+    #[test]
+    fn unset_flags_are_not_false() {
+        let required = requirements(
+            r#"
+            hypervisor = false
+            verification = { not = false }
+            "#,
+        );
+        let off = &required[&"hypervisor".into()];
+        let not_off = &required[&"verification".into()];
+
+        let mut setting = Setting::default();
+        assert_eq!(setting.get(&"hypervisor".into()), None);
+        assert_eq!(setting.flag(&"hypervisor".into()), Value::Boolean(false));
+        assert!(!off.check(setting.get(&"hypervisor".into())));
+        assert!(not_off.check(setting.get(&"verification".into())));
+
+        setting.set_bool("hypervisor", false);
+        setting.set_bool("verification", false);
+        assert_eq!(
+            setting.get(&"hypervisor".into()),
+            Some(&Value::Boolean(false))
+        );
+        assert!(off.check(setting.get(&"hypervisor".into())));
+        assert!(!not_off.check(setting.get(&"verification".into())));
+    }
+
+    #[test]
+    fn positive_requirements_still_parse() {
+        let required = requirements(
+            r#"
+            can-mcs = true
+            architecture = ["arm", "riscv"]
+            "#,
+        );
+        assert_eq!(
+            required[&"can-mcs".into()],
+            Requirement::Single(Value::Boolean(true))
+        );
+        assert!(required[&"architecture".into()].check(Some(&Value::Text("arm".into()))));
+    }
+
+    #[test]
+    fn duplicate_requirement_flags() {
+        let error = toml::from_str::<Flag>(
+            r#"
+            description = "Configure kernel to use MCS scheduler"
+
+            [[requires]]
+            can-mcs = true
+            can-mcs = false
+            "#,
+        )
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .contains("flag can-mcs is listed more than once in a requirement set"));
+
+        let flag = toml::from_str::<Flag>(
+            r#"
+            description = "Configure kernel to use MCS scheduler"
+
+            [[requires]]
+            can-mcs = true
+
+            [[requires]]
+            can-mcs = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(flag.requires.len(), 2);
+    }
+
+    /// Parse a setting as it would be flattened into a configuration table
+    fn setting(toml: &str) -> Result<Setting, toml::de::Error> {
+        toml::from_str::<crate::Variation>(toml).map(|variation| variation.setting().clone())
+    }
+
+    #[test]
+    fn explicitly_typed_values() {
+        let setting = setting(
+            r#"
+            version = { type = "string", value = "true" }
+            root-cnode-bits = { type = "int", value = 13 }
+            fastpath = { type = "bool", value = "ON" }
+            mcs = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            setting.flag(&"version".into()),
+            Value::Text("true".to_owned())
+        );
+        assert_eq!(setting.flag(&"root-cnode-bits".into()), Value::Number(13));
+        assert_eq!(setting.flag(&"fastpath".into()), Value::Boolean(true));
+        assert_eq!(setting.flag(&"mcs".into()), Value::Boolean(true));
+    }
+
+    #[test]
+    fn number_values() {
+        let setting = setting(
+            r#"
+            root-cnode-bits = 13
+            negative = -1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(setting.flag(&"root-cnode-bits".into()), Value::Number(13));
+        assert_eq!(setting.flag(&"negative".into()), Value::Number(-1));
+
+        let mut flags = NamedMap::default();
+        flags.insert(
+            "root-cnode-bits".into(),
+            Flag::new("", Some("KernelRootCNodeSizeBits"), Some(Type::Integer)),
+        );
+        let flag = flags.get(&"root-cnode-bits".into()).unwrap();
+        assert_eq!(
+            flag.cmake_arg(&Value::Number(13)).unwrap(),
+            "-DKernelRootCNodeSizeBits=13"
+        );
+
+        let mut text = Setting::default();
+        text.set_text("root-cnode-bits", "13");
+        let mut number = Setting::default();
+        number.set_number("root-cnode-bits", 13);
+        assert!(text.semantically_eq(&number));
+        assert_eq!(
+            toml::to_string(&number).unwrap().trim(),
+            "root-cnode-bits = 13"
+        );
+
+        let required = requirements("bits = 13");
+        assert_eq!(
+            required[&"bits".into()],
+            Requirement::Single(Value::Number(13))
+        );
+        assert!(required[&"bits".into()].check(Some(&Value::Text("13".into()))));
+    }
+
+    #[test]
+    fn setting_from_cmake_args() {
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [flag]
+                root-cnode-bits = { description = "Root CNode size", variable = "KernelRootCNodeSizeBits" }
+                opt-level = { description = "Optimisation", variable = "KernelOptimisation" }
+                benchmarks = { description = "Benchmarks", variable = "KernelBenchmarks" }
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let setting = Setting::from_cmake_args(
+            r#"
+            # Arguments from build.sh
+            -DMCS=ON
+            -DKernelRootCNodeSizeBits=13
+            -D KernelOptimisation="-O2"
+            -DKernelBenchmarks:BOOL=1
+            -DUnknownVariable=OFF
+            KernelSel4Arch=aarch64
+            "#,
+            &config,
+        )
+        .unwrap();
+
+        let mut expected = Setting::default();
+        expected.set_bool("mcs", true);
+        expected.set_number("root-cnode-bits", 13);
+        expected.set_text("opt-level", "-O2");
+        expected.set_bool("benchmarks", true);
+        expected.set_text("kernel-sel4-arch", "aarch64");
+        assert_eq!(setting, expected);
+
+        assert!(Setting::from_cmake_args("-DMCS", &config).is_err());
+        assert!(Setting::from_cmake_args("-DKernelBenchmarks:BOOL=maybe", &config).is_err());
+    }
+
+    #[test]
+    fn unset_in_later_layer() {
+        let mut base = Setting::default();
+        base.set_bool("hypervisor", true);
+        base.set_bool("mcs", true);
+
+        let layer: Setting = toml::from_str(
+            r#"
+            unset = [ "hypervisor" ]
+            smp = true
+            "#,
+        )
+        .unwrap();
+        assert!(layer.is_unset(&"hypervisor".into()));
+        assert_eq!(
+            toml::from_str::<Setting>(&toml::to_string(&layer).unwrap()).unwrap(),
+            layer
+        );
+
+        let mut merged = base.clone();
+        merged.merge(layer.clone());
+        assert!(!merged.contains(&"hypervisor".into()));
+        assert!(merged.contains(&"mcs".into()));
+        assert!(merged.contains(&"smp".into()));
+        assert_eq!(
+            merged
+                .flags()
+                .map(|(flag, _)| flag.to_string())
+                .collect::<Vec<_>>(),
+            vec!["mcs", "smp"]
+        );
+
+        let mut restore = Setting::default();
+        restore.set_bool("hypervisor", false);
+        merged.merge(restore);
+        assert_eq!(merged.flag(&"hypervisor".into()), Value::Boolean(false));
+        assert!(!merged.is_unset(&"hypervisor".into()));
+
+        assert!(toml::from_str::<Setting>("unset = [ \"mcs\" ]\nmcs = true").is_err());
+    }
+
+    #[test]
+    fn invalid_typed_values() {
+        assert!(setting(r#"bits = { type = "int", value = "thirteen" }"#).is_err());
+        assert!(setting(r#"mcs = { type = "bool", value = 2 }"#).is_err());
+        assert!(setting(r#"mcs = { type = "bool", value = "maybe" }"#).is_err());
+        assert!(setting(r#"mcs = { value = true }"#).is_err());
+        assert!(setting(r#"mcs = { type = "bool", value = true, extra = 1 }"#).is_err());
+    }
+
+    #[test]
+    fn invalid_negated_requirements() {
+        let parse = |toml| toml::from_str::<BTreeMap<FlagId, Requirement>>(toml);
+        assert!(parse("smp = { is = true }").is_err());
+        assert!(parse("smp = { not = true, also = false }").is_err());
+        assert!(parse("smp = { not = { not = true } }").is_err());
+    }
 }
@@ -2,20 +2,25 @@
 
 use crate::util::*;
 use crate::{
-    Flag, Platform, PlatformId, Project, ProjectId, Repository, Sel4Architecture, Setting,
-    VariationId,
+    Apps, Flag, FlagId, Platform, PlatformId, Project, ProjectId, Repository, Script, ScriptId,
+    Sel4Architecture, Setting, Type, Value, VariationId,
 };
-use anyhow::{format_err, Result};
-use dirs::{config_dir, home_dir};
-use serde::Deserialize;
+use anyhow::{bail, format_err, Result};
+use dirs::{cache_dir, config_dir, home_dir};
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
+use std::env::var_os;
+use std::fmt;
+use std::fs::read_dir;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use toml;
+use std::time::Duration;
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 /// Configuration for the whole s4 tool
 pub struct Config {
     /// Global default configuration
@@ -33,6 +38,24 @@ pub struct Config {
     /// Known projects
     #[serde(default, rename = "project")]
     projects: NamedMap<Project>,
+    /// Deprecated flag identifiers and the flags that replace them
+    #[serde(default, rename = "flag-rename")]
+    flag_renames: BTreeMap<FlagId, FlagId>,
+    /// Flags that can not be overridden by the user
+    #[serde(default)]
+    locked: BTreeSet<FlagId>,
+    /// Named sets of flags that can be applied when creating builds
+    #[serde(default, rename = "preset")]
+    presets: BTreeMap<String, Setting>,
+    /// Helper scripts run with `s4 run <name>`
+    #[serde(default, rename = "script")]
+    scripts: NamedMap<Script>,
+    /// Treat warnings about settings as errors
+    #[serde(skip)]
+    strict: bool,
+    /// Flags added from the easy settings of a workspace and whether the configuration overrides them
+    #[serde(skip)]
+    easy_settings_flags: BTreeMap<FlagId, FlagSource>,
 }
 
 impl Config {
@@ -42,53 +65,546 @@ impl Config {
     /// Configuration for s4
     const CONFIG_FILES: &'static [&'static str] = &[".s4", ".s4.toml", "s4.toml"];
 
+    /// Configuration file in the user's home directory written by s4
+    const USER_CONFIG_FILE: &'static str = ".s4.toml";
+
+    /// Key used to set the default project
+    const DEFAULT_PROJECT_KEY: &'static str = "default-project";
+
     /// Parse the builtin configuration file
     pub fn builtin() -> Result<Self> {
         toml::from_slice(Self::BUILTIN_TOML).map_err(|e| e.into())
     }
 
+    /// Environment variable naming an additional configuration file to merge
+    pub const CONFIG_ENV_VAR: &'static str = "S4_CONFIG";
+
     /// Load the configuration
+    ///
+    /// The builtin configuration is extended by any configuration files found in the home and
+    /// user configuration directories, followed by the file named by `S4_CONFIG` (if set).
+    /// Defaults set in more than one file take the value from the last file.
     pub fn load() -> Result<Self> {
+        Self::load_files(false)
+    }
+
+    /// Load the configuration, failing if two configuration files set a default differently
+    pub fn load_strict_config() -> Result<Self> {
+        Self::load_files(true)
+    }
+
+    fn load_files(strict_config: bool) -> Result<Self> {
+        let paths = Self::config_files();
+        if strict_config {
+            Self::check_conflicts(&paths)?;
+        }
+
         let mut configuration = Self::builtin()?;
+        for path in paths {
+            configuration.merge_file(path)?;
+        }
+        configuration.validate()?;
+        Ok(configuration)
+    }
 
+    /// Configuration files to load in order
+    fn config_files() -> Vec<PathBuf> {
         fn all_config_files(directory: PathBuf) -> impl Iterator<Item = PathBuf> {
             Config::CONFIG_FILES.iter().map(move |file| {
                 let mut path = directory.clone();
                 path.push(file);
                 path
             })
-        };
+        }
 
         home_dir()
             .into_iter()
-            .chain(config_dir().into_iter())
+            .chain(config_dir())
             .flat_map(all_config_files)
-            .try_for_each(|path| -> Result<()> {
-                if path.exists() {
-                    configuration.merge(toml_load(path)?);
+            .filter(|path| path.exists())
+            .chain(var_os(Self::CONFIG_ENV_VAR).map(PathBuf::from))
+            .collect()
+    }
+
+    /// Ensure that no two configuration files set the same default to different values
+    fn check_conflicts(paths: &[PathBuf]) -> Result<()> {
+        let layers = paths
+            .iter()
+            .map(|path| {
+                toml_load::<Defaults>(path)
+                    .map(|defaults| (path, defaults))
+                    .map_err(|e| format_err!("Could not load {}: {}", path.display(), e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut conflicts = Vec::new();
+        for (index, (path, defaults)) in layers.iter().enumerate() {
+            for (other_path, other) in &layers[index + 1..] {
+                for key in defaults.conflicts(other) {
+                    conflicts.push(format!(
+                        "  {}: {} and {}",
+                        key,
+                        path.display(),
+                        other_path.display()
+                    ));
                 }
-                Ok(())
-            })?;
+            }
+        }
 
+        if !conflicts.is_empty() {
+            bail!(
+                "Configuration files set defaults to different values:\n{}",
+                conflicts.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// Load the builtin configuration extended only by a single configuration file
+    ///
+    /// Unlike `load`, no other configuration files are discovered and `S4_CONFIG` is ignored.
+    pub fn load_exact(path: impl AsRef<Path>) -> Result<Self> {
+        let mut configuration = Self::builtin()?;
+        configuration.merge_file(path)?;
+        configuration.validate()?;
         Ok(configuration)
     }
 
+    /// Merge a configuration file followed by the platform definitions in its platforms directory
+    fn merge_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut layer: Config = toml_load(path)?;
+        layer.flags.set_source(path);
+        layer.platforms.set_source(path);
+        layer.projects.set_source(path);
+        layer.scripts.set_source(path);
+        self.merge(layer);
+
+        let platforms_dir = path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(self.defaults.platforms_dir());
+        if !platforms_dir.is_dir() {
+            return Ok(());
+        }
+
+        let mut files = read_dir(&platforms_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.sort();
+
+        for file in files {
+            if file.extension().is_some_and(|ext| ext == "toml") {
+                let mut platforms: PlatformFile = toml_load(&file)
+                    .map_err(|e| format_err!("Could not load {}: {}", file.display(), e))?;
+                platforms.platforms.set_source(&file);
+                self.platforms.merge(platforms.platforms);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ensure that the configuration is consistent
+    pub fn validate(&self) -> Result<()> {
+        for platform in self.platforms.all() {
+            Platform::validate(&platform)?;
+        }
+
+        for project in self.projects.all() {
+            self.validate_command_line(&project)?;
+            Apps::check_repo_init_args(project.repo_init_args(&self.defaults))
+                .map_err(|e| format_err!("Project {}: {}", project.name().as_ref(), e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the flags a project exposes on the command line can be parsed from it
+    ///
+    /// Flags must declare a type and flags with requirements must be boolean.
+    fn validate_command_line(&self, project: &NameRef<Project>) -> Result<()> {
+        for id in project.command_line_flags() {
+            let flag = match self.flags.get(id) {
+                Some(flag) => flag,
+                // Flags may be defined by easy settings in the workspace
+                None => continue,
+            };
+
+            match flag.ty() {
+                None => bail!(
+                    "Flag {} on the command line of project {} has no type",
+                    id,
+                    project.name().as_ref()
+                ),
+                Some(ty) if ty != Type::Boolean && flag.has_requirements() => bail!(
+                    "Flag {} on the command line of project {} has requirements but is not boolean",
+                    id,
+                    project.name().as_ref()
+                ),
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get the defaults from the config
     pub fn defaults(&self) -> &Defaults {
         &self.defaults
     }
 
-    pub fn project(&self, project: &ProjectId) -> NameRef<Project> {
+    pub fn project(&self, project: &ProjectId) -> NameRef<'_, Project> {
         self.projects
             .get(project)
             .unwrap_or(NameRef::owned(Project::default(), ProjectId::auto()))
     }
 
+    /// Get a known project, suggesting a similar project if it does not exist
+    pub fn find_project(&self, project: &ProjectId) -> Result<NameRef<'_, Project>> {
+        self.projects.get(project).ok_or_else(|| {
+            format_err!(
+                "No such project {}{}",
+                project.as_ref(),
+                suggestion_hint(project.as_ref(), self.projects.ids())
+            )
+        })
+    }
+
+    /// Path of the configuration file in the user's home directory
+    pub fn user_config_path() -> Option<PathBuf> {
+        home_dir().map(|home| home.join(Self::USER_CONFIG_FILE))
+    }
+
+    /// Set the default project in a user configuration file
+    ///
+    /// The file is created if it does not exist and any other settings in it are kept.
+    pub fn save_default_project(&self, project: &ProjectId, path: impl AsRef<Path>) -> Result<()> {
+        self.find_project(project)?;
+
+        let path = path.as_ref();
+        let mut user = if path.exists() {
+            toml_load(path)?
+        } else {
+            toml::value::Table::new()
+        };
+        user.insert(
+            Self::DEFAULT_PROJECT_KEY.to_owned(),
+            toml::Value::String(project.as_ref().to_owned()),
+        );
+        toml_save(&toml::Value::Table(user), path)
+    }
+
+    /// Get a known platform, suggesting a similar platform if it does not exist
+    pub fn find_platform(&self, platform: &PlatformId) -> Result<NameRef<'_, Platform>> {
+        self.platforms.get(platform).ok_or_else(|| {
+            format_err!(
+                "No such platform {}{}",
+                platform.as_ref(),
+                suggestion_hint(platform.as_ref(), self.platforms.ids())
+            )
+        })
+    }
+
+    /// Render the merged configuration as TOML
+    ///
+    /// If a project or platform is given, only the definitions of those are included.
+    pub fn dump(
+        &self,
+        project: Option<&ProjectId>,
+        platform: Option<&PlatformId>,
+    ) -> Result<String> {
+        fn section(name: &str, id: &str, value: toml::Value) -> (String, toml::Value) {
+            let mut table = toml::value::Table::new();
+            table.insert(id.to_owned(), value);
+            (name.to_owned(), toml::Value::Table(table))
+        }
+
+        let value = if project.is_none() && platform.is_none() {
+            toml::Value::try_from(self)?
+        } else {
+            let mut table = toml::value::Table::new();
+            if let Some(id) = project {
+                let project = toml::Value::try_from(&*self.find_project(id)?)?;
+                table.extend(Some(section("project", id.as_ref(), project)));
+            }
+            if let Some(id) = platform {
+                let platform = toml::Value::try_from(&*self.find_platform(id)?)?;
+                table.extend(Some(section("platform", id.as_ref(), platform)));
+            }
+            toml::Value::Table(table)
+        };
+        Ok(toml::to_string(&value)?)
+    }
+
+    /// Find where the flags, platforms, projects, and scripts with a name were last defined
+    ///
+    /// Each definition is given with its kind and where it came from.
+    pub fn describe(&self, name: &str) -> Vec<(&'static str, DefinitionSource<'_>)> {
+        let flag = FlagId::from(name);
+        let platform = PlatformId::from(name);
+        let project = ProjectId::from(name);
+        let script = ScriptId::from(name);
+        let flag_source = match self.easy_settings_flags.get(&flag) {
+            Some(FlagSource::EasySettings) => Some(DefinitionSource::EasySettings),
+            _ => self
+                .flags
+                .get(&flag)
+                .map(|_| self.flags.source(&flag).into()),
+        };
+        let definitions = [
+            ("flag", flag_source),
+            (
+                "platform",
+                self.platforms
+                    .get(&platform)
+                    .map(|_| self.platforms.source(&platform).into()),
+            ),
+            (
+                "project",
+                self.projects
+                    .get(&project)
+                    .map(|_| self.projects.source(&project).into()),
+            ),
+            (
+                "script",
+                self.scripts
+                    .get(&script)
+                    .map(|_| self.scripts.source(&script).into()),
+            ),
+        ];
+        definitions
+            .iter()
+            .filter_map(|&(kind, source)| source.map(|source| (kind, source)))
+            .collect()
+    }
+
+    /// Get the setting of a preset, suggesting a similar preset if it does not exist
+    pub fn find_preset(&self, preset: &str) -> Result<&Setting> {
+        self.presets.get(preset).ok_or_else(|| {
+            format_err!(
+                "No such preset {}{}",
+                preset,
+                suggestion_hint(preset, self.presets.keys())
+            )
+        })
+    }
+
+    /// Get a configured script, suggesting a similar script if it does not exist
+    pub fn find_script(&self, script: &ScriptId) -> Result<NameRef<'_, Script>> {
+        self.scripts.get(script).ok_or_else(|| {
+            format_err!(
+                "No such script {}{}",
+                script.as_ref(),
+                suggestion_hint(script.as_ref(), self.scripts.ids())
+            )
+        })
+    }
+
     /// Ensure that a given set of sttings is a valid combination
+    ///
+    /// Every flag is checked so that all of the problems with the setting are reported together.
     pub fn check_setting(&self, setting: &Setting) -> Result<()> {
+        let mut errors = Vec::new();
+        let mut unmet = BTreeMap::new();
         for (id, value) in setting.flags() {
             if let Some(flag) = self.flags.get(id) {
-                Flag::validate(flag, setting, value)?;
+                let constrained: BTreeSet<_> = flag
+                    .unmet_requirements(setting)
+                    .into_iter()
+                    .flatten()
+                    .map(|(flag, _)| flag.clone())
+                    .collect();
+                if let Err(error) = Flag::validate(flag, setting, value) {
+                    errors.push(error.to_string());
+                    unmet.insert(id, constrained);
+                }
+            }
+        }
+
+        for (index, (flag, constrained)) in unmet.iter().enumerate() {
+            for (other, other_constrained) in unmet.iter().skip(index + 1) {
+                if constrained.contains(other) && other_constrained.contains(flag) {
+                    errors.push(format!(
+                        "Flags {} and {} each require a different value of the other, so they can not both be set",
+                        flag, other
+                    ));
+                }
+            }
+        }
+
+        if let Err(error) = self.check_platform(setting) {
+            errors.push(error.to_string());
+        }
+
+        match errors.len() {
+            0 => {}
+            1 => bail!(errors.remove(0)),
+            count => bail!(
+                "The setting has {} problems:\n  {}",
+                count,
+                errors.join("\n  ")
+            ),
+        }
+
+        for warning in self.deprecation_warnings(setting) {
+            if self.strict {
+                bail!(warning);
+            }
+            eprintln!("Warning: {}", warning);
+        }
+
+        Ok(())
+    }
+
+    /// Ensure that the `platform` flag of a setting agrees with its `kernel-platform` flag
+    ///
+    /// `kernel-platform` always names the platform. `platform` must name the same platform, one of
+    /// its variations, or a value set for `platform` by the platform or one of its variations.
+    /// Settings for platforms that are not defined by the configuration are not checked.
+    fn check_platform(&self, setting: &Setting) -> Result<()> {
+        let (kernel_platform, platform) = match (setting.kernel_platform(), setting.platform()) {
+            (Some(kernel_platform), Some(platform)) => (kernel_platform, platform),
+            _ => return Ok(()),
+        };
+        let definition = match self.platforms.get(&kernel_platform.into()) {
+            Some(definition) => definition,
+            None => return Ok(()),
+        };
+
+        let mut allowed = BTreeSet::new();
+        allowed.insert(kernel_platform.to_owned());
+        allowed.extend(definition.setting().platform().map(str::to_owned));
+        for variation in definition.variations() {
+            allowed.insert(variation.name().as_ref().to_owned());
+            allowed.extend(variation.setting().platform().map(str::to_owned));
+        }
+
+        if !allowed.contains(platform) {
+            bail!(
+                "Flag platform is {} but kernel-platform {} only allows {}",
+                platform,
+                kernel_platform,
+                allowed.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Warnings for each deprecated flag that is set in a setting
+    pub fn deprecation_warnings(&self, setting: &Setting) -> Vec<String> {
+        setting
+            .flags()
+            .filter_map(|(id, _)| {
+                let message = self.flags.get(id)?.deprecated()?.to_owned();
+                Some(format!("Flag {} is deprecated: {}", id, message))
+            })
+            .collect()
+    }
+
+    /// Treat warnings about settings (such as the use of deprecated flags) as errors
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Enable boolean flags required by flags that are set in a setting
+    ///
+    /// This only applies to flags that allow automatic enabling (or to all flags if enabled by
+    /// default) and only enables flags that have not been explicitly set. Returns the flags that
+    /// were enabled. The setting should still be checked afterwards.
+    pub fn auto_enable(&self, setting: &mut Setting) -> Vec<FlagId> {
+        let mut enabled = Vec::new();
+
+        loop {
+            let enable = setting
+                .flags()
+                .filter(|(_, value)| **value == Value::Boolean(true))
+                .filter_map(|(id, _)| self.flags.get(id))
+                .filter(|flag| self.defaults.auto_enable() || flag.auto_enable())
+                .find_map(|flag| {
+                    let name = flag.name().clone();
+                    Flag::flags_to_enable(flag, setting).map(|enable| (name, enable))
+                });
+
+            match enable {
+                Some((flag, enable)) if !enable.is_empty() => {
+                    for required in enable {
+                        eprintln!(
+                            "Warning: enabling {} as it is required by {}",
+                            required, flag
+                        );
+                        setting.set_bool(required.clone(), true);
+                        enabled.push(required);
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        enabled
+    }
+
+    /// Additional flags to set so that a flag can be enabled in a setting
+    ///
+    /// The requirements of any boolean flags enabled by the suggestion are satisfied in turn.
+    /// Returns `None` if the requirements can not be satisfied.
+    pub fn suggest_satisfying(&self, setting: &Setting, flag: &FlagId) -> Option<Setting> {
+        let mut combined = setting.clone();
+        let mut suggestion = Setting::default();
+        let mut pending = vec![flag.clone()];
+
+        while let Some(id) = pending.pop() {
+            let flag = match self.flags.get(&id) {
+                Some(flag) => flag,
+                None => continue,
+            };
+            let required = flag.satisfying_setting(&combined)?;
+            pending.extend(
+                required
+                    .flags()
+                    .filter(|(_, value)| **value == Value::Boolean(true))
+                    .map(|(id, _)| id.clone()),
+            );
+            combined.merge(required.clone());
+            suggestion.merge(required);
+        }
+
+        Some(suggestion)
+    }
+
+    /// Flags in a setting that are defined neither in the configuration nor in the given
+    /// additional flags (such as those from easy settings)
+    pub fn unknown_flags(&self, setting: &Setting, extra: &NamedMap<Flag>) -> Vec<FlagId> {
+        setting
+            .flags()
+            .map(|(id, _)| id)
+            .filter(|id| self.flags.get(id).is_none() && extra.get(id).is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Flags that are available on the command line of a project
+    ///
+    /// If the project exposes its easy settings, every flag added from the easy settings of the
+    /// workspace is included along with the flags listed by the project.
+    pub fn command_line_flags(&self, project: &ProjectId) -> BTreeSet<FlagId> {
+        let project = self.project(project);
+        let mut flags = project
+            .command_line_flags()
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        if project.expose_easy_settings(&self.defaults) {
+            flags.extend(self.easy_settings_flags.keys().cloned());
+        }
+        flags
+    }
+
+    /// Ensure that a setting supplied by the user does not override any locked flags
+    pub fn check_overrides(&self, project: &ProjectId, overrides: &Setting) -> Result<()> {
+        let project = self.project(project);
+        for (id, _) in overrides.flags() {
+            if self.locked.contains(id) || project.locked_flags().any(|locked| locked == id) {
+                bail!("Flag {} is locked and can not be overridden", id);
             }
         }
 
@@ -96,12 +612,47 @@ impl Config {
     }
 
     /// Apply the settings as CMake command line arguments
-    pub fn cmake_args<'c>(&self, setting: &Setting, command: &mut Command) {
-        for (id, value) in setting.flags() {
-            if let Some(flag) = self.flags.get(id) {
-                flag.cmake_flag(command, value);
+    pub fn cmake_args(&self, setting: &Setting, command: &mut Command) {
+        command.args(self.cmake_arg_strings(setting));
+    }
+
+    /// The CMake command line arguments for a setting
+    pub fn cmake_arg_strings(&self, setting: &Setting) -> Vec<String> {
+        setting
+            .flags()
+            .filter_map(|(id, value)| self.flags.get(id)?.cmake_arg(value))
+            .collect()
+    }
+
+    /// The environment variables to set for the build tools for a setting
+    pub fn env_vars(&self, setting: &Setting) -> Vec<(String, String)> {
+        setting
+            .flags()
+            .filter_map(|(id, value)| self.flags.get(id)?.env_var(value))
+            .collect()
+    }
+
+    /// Translate deprecated flag identifiers in a setting to their replacements
+    ///
+    /// The renamed flags are returned so that the caller can report them. Chains of renames are
+    /// followed to the final flag identifier.
+    pub fn migrate_setting(&self, setting: &mut Setting) -> Vec<(FlagId, FlagId)> {
+        let mut renamed = Vec::new();
+
+        for (old, mut new) in self.flag_renames.iter() {
+            for _ in 0..self.flag_renames.len() {
+                match self.flag_renames.get(new) {
+                    Some(next) if next != old => new = next,
+                    _ => break,
+                }
+            }
+
+            if setting.rename(old, new.clone()) {
+                renamed.push((old.clone(), new.clone()));
             }
         }
+
+        renamed
     }
 
     pub fn platform_setting(
@@ -113,10 +664,7 @@ impl Config {
     ) -> Result<Setting> {
         let mut setting = Setting::default();
 
-        let platform = self
-            .platforms
-            .get(platform)
-            .ok_or(format_err!("No such platform {}", platform.as_ref()))?;
+        let platform = self.find_platform(platform)?;
         Platform::check_architecture(&platform, arch)?;
 
         setting.set_kernel_platform(platform.name());
@@ -124,11 +672,14 @@ impl Config {
         setting.merge(platform.setting().clone());
 
         if let Some(variation) = variation {
-            let variation = platform.variation(variation).ok_or(format_err!(
-                "No such platform variation {} for platform {}",
-                variation.as_ref(),
-                platform.name().as_ref()
-            ))?;
+            let variation = platform.variation(variation).ok_or_else(|| {
+                format_err!(
+                    "No such platform variation {} for platform {}{}",
+                    variation.as_ref(),
+                    platform.name().as_ref(),
+                    suggestion_hint(variation.as_ref(), platform.variation_ids())
+                )
+            })?;
             setting.set_platform(variation.name());
             setting.merge(variation.setting().clone());
         }
@@ -144,36 +695,200 @@ impl Config {
         Ok(setting)
     }
 
+    /// Get the definition of a flag
+    pub fn flag(&self, flag: &FlagId) -> Option<NameRef<'_, Flag>> {
+        self.flags.get(flag)
+    }
+
+    /// The flags that set each CMake variable
+    ///
+    /// If several flags set the same variable, the last flag in order of identifier is used.
+    pub fn variable_index(&self) -> BTreeMap<&str, &FlagId> {
+        self.flags
+            .map
+            .iter()
+            .filter_map(|(id, flag)| Some((flag.variable()?, id)))
+            .collect()
+    }
+
+    /// Get the definitions of all flags
+    pub fn flags(&self) -> impl Iterator<Item = NameRef<'_, Flag>> {
+        self.flags.all()
+    }
+
+    /// Add the flags found in the easy settings of a workspace
+    ///
+    /// Where a flag is also defined by the configuration, the description and type of the
+    /// configuration are kept.
     pub fn add_flags(&mut self, flags: NamedMap<Flag>) {
+        for id in flags.ids() {
+            let source = if self.flags.get(id).is_some() {
+                FlagSource::Overridden
+            } else {
+                FlagSource::EasySettings
+            };
+            self.easy_settings_flags.insert(id.clone(), source);
+        }
+        // Flags that are also in the configuration are still described by its files
+        let sources = self.flags.sources.clone();
         self.flags.merge(flags);
+        self.flags.sources = sources;
+    }
+
+    /// Where each of the flags was defined
+    pub fn flag_sources(&self) -> impl Iterator<Item = (NameRef<'_, Flag>, FlagSource)> {
+        self.flags.all().map(move |flag| {
+            let source = self
+                .easy_settings_flags
+                .get(flag.name())
+                .copied()
+                .unwrap_or(FlagSource::Config);
+            (flag, source)
+        })
+    }
+
+    /// Format a setting as a table including the descriptions of its flags
+    pub fn display_setting(&self, setting: &Setting) -> String {
+        setting.display_table(&self.flags)
+    }
+
+    /// Get the definitions of all platforms
+    pub fn platforms(&self) -> impl Iterator<Item = NameRef<'_, Platform>> {
+        self.platforms.all()
+    }
+}
+
+/// Where the definition of a flag came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FlagSource {
+    /// Defined only by the configuration
+    Config,
+    /// Defined only by the easy settings of the workspace
+    EasySettings,
+    /// Defined by both, with the configuration overriding the easy settings
+    Overridden,
+}
+
+impl fmt::Display for FlagSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlagSource::Config => write!(f, "config"),
+            FlagSource::EasySettings => write!(f, "easy-settings"),
+            FlagSource::Overridden => write!(f, "config (overrides easy-settings)"),
+        }
+    }
+}
+
+/// Where a named definition in the configuration came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefinitionSource<'c> {
+    /// Only the builtin configuration
+    Builtin,
+    /// The configuration file it was last merged from
+    File(&'c Path),
+    /// The easy settings of the workspace
+    EasySettings,
+}
+
+impl<'c> From<Option<&'c Path>> for DefinitionSource<'c> {
+    fn from(path: Option<&'c Path>) -> Self {
+        path.map_or(DefinitionSource::Builtin, DefinitionSource::File)
     }
 }
 
+impl fmt::Display for DefinitionSource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DefinitionSource::Builtin => write!(f, "builtin"),
+            DefinitionSource::File(path) => write!(f, "{}", path.display()),
+            DefinitionSource::EasySettings => write!(f, "easy-settings"),
+        }
+    }
+}
+
+/// A file of platform definitions from a platforms directory
+#[derive(Deserialize)]
+struct PlatformFile {
+    #[serde(default, rename = "platform")]
+    platforms: NamedMap<Platform>,
+}
+
 impl Merge for Config {
     fn merge(&mut self, other: Self) {
         self.defaults.merge(other.defaults);
         self.flags.merge(other.flags);
         self.platforms.merge(other.platforms);
-        self.architectures.merge(other.architectures);
+        Merge::merge(&mut self.architectures, other.architectures);
         self.projects.merge(other.projects);
+        Merge::merge(&mut self.flag_renames, other.flag_renames);
+        self.locked.merge(other.locked);
+        Merge::merge(&mut self.presets, other.presets);
+        self.scripts.merge(other.scripts);
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Defaults {
+    /// Project to use when none is given
+    default_project: Option<ProjectId>,
     /// Server to use for repo manifests
     git_server: Option<String>,
     /// Docker image for build tools
     docker_image: Option<String>,
+    /// Container runtime used to run build tools
+    container_runtime: Option<ContainerRuntime>,
+    /// Entrypoint to use in place of that of the docker image (cleared if empty)
+    docker_entrypoint: Option<String>,
     /// URL to download repo script
     repo_url: Option<String>,
+    /// Directory used to keep downloaded tools
+    tool_cache_dir: Option<PathBuf>,
     /// Git branch to check out with repo
     repo_branch: Option<String>,
     /// Repo manifest file to check out
     repo_manifest: Option<String>,
+    /// Manifest groups to sync with repo
+    repo_groups: Option<Vec<String>>,
+    /// Extra arguments passed verbatim to repo init
+    repo_init_args: Option<Vec<String>>,
+    /// Directory of platform definitions relative to each configuration file
+    #[serde(alias = "platforms-dir")]
+    platforms_directory: Option<PathBuf>,
+    /// Subdirectories of the workspace searched for the easy settings hint
+    source_search: Option<Vec<PathBuf>>,
     /// Phrase to indicate completion of root server
     exit_phrase: Option<String>,
+    /// How the seL4 artifact cache is shared between builds
+    cache_mode: Option<CacheMode>,
+    /// SELinux label applied to directories mounted in docker
+    mount_label: Option<MountLabel>,
+    /// Whether to run builds in simulation or on hardware
+    run_target: Option<RunTarget>,
+    /// Enable required boolean flags automatically for all flags
+    auto_enable: Option<bool>,
+    /// Check that the docker image provides the build tools before configuring a build
+    check_image_tools: Option<bool>,
+    /// Make every flag from the easy settings available on project command lines
+    expose_easy_settings: Option<bool>,
+    /// Seconds to wait to connect to a server when downloading tools
+    download_connect_timeout: Option<u64>,
+    /// Seconds to wait for a server to respond when downloading tools
+    download_timeout: Option<u64>,
+    /// Free disk space in GiB below which a warning is given before syncing or building
+    min_free_space: Option<u64>,
+    /// Environment variables forwarded from the host to docker
+    #[serde(default)]
+    docker_env: BTreeSet<String>,
+    /// Additional host directories to mount in docker, keyed by path in the container
+    #[serde(default, rename = "mount")]
+    extra_mounts: BTreeMap<PathBuf, PathBuf>,
+    /// CMake toolchain files for builds of each architecture, relative to the workspace
+    #[serde(default, rename = "toolchain-file")]
+    toolchain_files: BTreeMap<Sel4Architecture, PathBuf>,
+    /// Additional host directories to mount in docker for builds of a specific architecture
+    #[serde(default, rename = "architecture-mount", alias = "arch-mount")]
+    architecture_mounts: BTreeMap<Sel4Architecture, BTreeMap<PathBuf, PathBuf>>,
 }
 
 impl Defaults {
@@ -189,6 +904,19 @@ impl Defaults {
     /// Default exit phrase to expect when a run ends
     const EXIT_PHRASE: &'static str = "All is well";
 
+    /// Default directory of platform definitions relative to each configuration file
+    const PLATFORMS_DIR: &'static str = "platforms";
+
+    /// Project to use when none is given
+    pub fn default_project(&self) -> Option<&ProjectId> {
+        self.default_project.as_ref()
+    }
+
+    /// The configured git server base URL (without the builtin fallback)
+    pub fn raw_git_server(&self) -> Option<&str> {
+        option_ref(&self.git_server)
+    }
+
     /// Get the git server base URL
     pub fn git_server(&self) -> &str {
         option_fallback(&self.git_server, Self::GIT_SERVER)
@@ -199,16 +927,47 @@ impl Defaults {
         format!("{}/{}.git", self.git_server(), repo)
     }
 
+    /// The configured docker image (without the builtin fallback)
+    pub fn raw_docker_image(&self) -> Option<&str> {
+        option_ref(&self.docker_image)
+    }
+
     /// Docker image to execute for build tools
     pub fn docker_image(&self) -> &str {
         option_fallback(&self.docker_image, Self::DOCKER_IMAGE)
     }
 
+    /// Container runtime used to run build tools
+    pub fn container_runtime(&self) -> ContainerRuntime {
+        self.container_runtime.unwrap_or(ContainerRuntime::Auto)
+    }
+
+    /// Entrypoint to use in place of that of the docker image
+    ///
+    /// An empty entrypoint clears the entrypoint of the image.
+    pub fn docker_entrypoint(&self) -> Option<&str> {
+        option_ref(&self.docker_entrypoint)
+    }
+
+    /// The configured URL to download repo (without the builtin fallback)
+    pub fn raw_repo_url(&self) -> Option<&str> {
+        option_ref(&self.repo_url)
+    }
+
     /// URL to download repo
     pub fn repo_url(&self) -> &str {
         option_fallback(&self.repo_url, Self::REPO_URL)
     }
 
+    /// Directory used to keep downloaded tools (if any)
+    ///
+    /// Defaults to a directory in the user cache directory.
+    pub fn tool_cache_dir(&self) -> Option<PathBuf> {
+        self.tool_cache_dir
+            .clone()
+            .or_else(|| cache_dir().map(|cache| cache.join("s4").join("tools")))
+    }
+
     /// Branch to check out for repo
     pub fn repo_branch(&self) -> Option<&str> {
         option_ref(&self.repo_branch)
@@ -219,22 +978,313 @@ impl Defaults {
         option_ref(&self.repo_manifest)
     }
 
+    /// Manifest groups to sync with repo (the manifest default if empty)
+    pub fn repo_groups(&self) -> &[String] {
+        option_fallback(&self.repo_groups, &[])
+    }
+
+    /// Extra arguments passed verbatim to repo init
+    pub fn repo_init_args(&self) -> &[String] {
+        option_fallback(&self.repo_init_args, &[])
+    }
+
+    /// Subdirectories of the workspace searched for the easy settings hint (besides the root)
+    pub fn source_search(&self) -> &[PathBuf] {
+        option_fallback(&self.source_search, &[])
+    }
+
+    /// Directory of platform definitions relative to each configuration file
+    pub fn platforms_dir(&self) -> &Path {
+        self.platforms_directory
+            .as_deref()
+            .unwrap_or_else(|| Path::new(Self::PLATFORMS_DIR))
+    }
+
+    /// The configured phrase to indicate completion of root server (without the builtin fallback)
+    pub fn raw_exit_phrase(&self) -> Option<&str> {
+        option_ref(&self.exit_phrase)
+    }
+
     /// Phrase to indicate completion of root server
     pub fn exit_phrase(&self) -> &str {
         option_fallback(&self.exit_phrase, Self::EXIT_PHRASE)
     }
+
+    /// How the seL4 artifact cache is shared between builds
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode.unwrap_or(CacheMode::Shared)
+    }
+
+    /// SELinux label applied to directories mounted in docker
+    pub fn mount_label(&self) -> MountLabel {
+        self.mount_label.unwrap_or(MountLabel::Shared)
+    }
+
+    /// Whether required boolean flags are enabled automatically for all flags
+    pub fn auto_enable(&self) -> bool {
+        self.auto_enable.unwrap_or(false)
+    }
+
+    /// Whether to check that the docker image provides the build tools before configuring a build
+    pub fn check_image_tools(&self) -> bool {
+        self.check_image_tools.unwrap_or(false)
+    }
+
+    /// Whether every flag from the easy settings is available on project command lines
+    pub fn expose_easy_settings(&self) -> bool {
+        self.expose_easy_settings.unwrap_or(false)
+    }
+
+    /// Environment variables forwarded from the host to docker
+    pub fn docker_env(&self) -> &BTreeSet<String> {
+        &self.docker_env
+    }
+
+    /// How long to wait to connect to a server when downloading tools
+    pub fn download_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.download_connect_timeout.unwrap_or(10))
+    }
+
+    /// How long to wait for a server to respond when downloading tools
+    pub fn download_timeout(&self) -> Duration {
+        Duration::from_secs(self.download_timeout.unwrap_or(60))
+    }
+
+    /// Free disk space in bytes below which a warning is given before syncing or building (if any)
+    pub fn min_free_space(&self) -> Option<u64> {
+        self.min_free_space
+            .map(|gib| gib.saturating_mul(1024 * 1024 * 1024))
+    }
+
+    /// Whether to run builds in simulation or on hardware
+    pub fn run_target(&self) -> RunTarget {
+        self.run_target.unwrap_or(RunTarget::Auto)
+    }
+
+    /// CMake toolchain file for builds of an architecture (if any), relative to the workspace
+    pub fn toolchain_file(&self, architecture: Sel4Architecture) -> Option<&Path> {
+        self.toolchain_files
+            .get(&architecture)
+            .map(PathBuf::as_path)
+    }
+
+    /// Additional mounts for docker as (container path, host path) pairs
+    ///
+    /// Mounts specific to the given architecture are included after general mounts.
+    pub fn extra_mounts(
+        &self,
+        architecture: Option<Sel4Architecture>,
+    ) -> impl Iterator<Item = (&PathBuf, &PathBuf)> {
+        let architecture_mounts = architecture.and_then(|arch| self.architecture_mounts.get(&arch));
+        self.extra_mounts
+            .iter()
+            .chain(architecture_mounts.into_iter().flatten())
+    }
+}
+
+impl Defaults {
+    /// Names of the single-valued defaults that are set to different values in each
+    fn conflicts(&self, other: &Self) -> Vec<&'static str> {
+        fn differs<T: PartialEq>(value: &Option<T>, other: &Option<T>) -> bool {
+            matches!((value, other), (Some(value), Some(other)) if value != other)
+        }
+
+        [
+            (
+                "default-project",
+                differs(&self.default_project, &other.default_project),
+            ),
+            ("git-server", differs(&self.git_server, &other.git_server)),
+            (
+                "docker-image",
+                differs(&self.docker_image, &other.docker_image),
+            ),
+            (
+                "container-runtime",
+                differs(&self.container_runtime, &other.container_runtime),
+            ),
+            (
+                "docker-entrypoint",
+                differs(&self.docker_entrypoint, &other.docker_entrypoint),
+            ),
+            ("repo-url", differs(&self.repo_url, &other.repo_url)),
+            (
+                "tool-cache-dir",
+                differs(&self.tool_cache_dir, &other.tool_cache_dir),
+            ),
+            (
+                "repo-branch",
+                differs(&self.repo_branch, &other.repo_branch),
+            ),
+            (
+                "repo-manifest",
+                differs(&self.repo_manifest, &other.repo_manifest),
+            ),
+            (
+                "repo-groups",
+                differs(&self.repo_groups, &other.repo_groups),
+            ),
+            (
+                "repo-init-args",
+                differs(&self.repo_init_args, &other.repo_init_args),
+            ),
+            (
+                "platforms-directory",
+                differs(&self.platforms_directory, &other.platforms_directory),
+            ),
+            (
+                "source-search",
+                differs(&self.source_search, &other.source_search),
+            ),
+            (
+                "exit-phrase",
+                differs(&self.exit_phrase, &other.exit_phrase),
+            ),
+            ("cache-mode", differs(&self.cache_mode, &other.cache_mode)),
+            (
+                "mount-label",
+                differs(&self.mount_label, &other.mount_label),
+            ),
+            ("run-target", differs(&self.run_target, &other.run_target)),
+            (
+                "auto-enable",
+                differs(&self.auto_enable, &other.auto_enable),
+            ),
+            (
+                "check-image-tools",
+                differs(&self.check_image_tools, &other.check_image_tools),
+            ),
+            (
+                "expose-easy-settings",
+                differs(&self.expose_easy_settings, &other.expose_easy_settings),
+            ),
+            (
+                "download-connect-timeout",
+                differs(
+                    &self.download_connect_timeout,
+                    &other.download_connect_timeout,
+                ),
+            ),
+            (
+                "download-timeout",
+                differs(&self.download_timeout, &other.download_timeout),
+            ),
+            (
+                "min-free-space",
+                differs(&self.min_free_space, &other.min_free_space),
+            ),
+        ]
+        .iter()
+        .filter_map(|&(key, differs)| differs.then_some(key))
+        .collect()
+    }
 }
 
 impl Merge for Defaults {
     fn merge(&mut self, other: Self) {
+        self.default_project.merge(other.default_project);
         self.git_server.merge(other.git_server);
         self.docker_image.merge(other.docker_image);
+        self.container_runtime.merge(other.container_runtime);
+        self.docker_entrypoint.merge(other.docker_entrypoint);
         self.repo_url.merge(other.repo_url);
+        self.tool_cache_dir.merge(other.tool_cache_dir);
         self.repo_branch.merge(other.repo_branch);
         self.repo_manifest.merge(other.repo_manifest);
+        self.exit_phrase.merge(other.exit_phrase);
+        self.repo_groups.merge(other.repo_groups);
+        self.repo_init_args.merge(other.repo_init_args);
+        self.platforms_directory.merge(other.platforms_directory);
+        self.source_search.merge(other.source_search);
+        self.cache_mode.merge(other.cache_mode);
+        self.mount_label.merge(other.mount_label);
+        self.run_target.merge(other.run_target);
+        self.auto_enable.merge(other.auto_enable);
+        self.check_image_tools.merge(other.check_image_tools);
+        self.expose_easy_settings.merge(other.expose_easy_settings);
+        self.docker_env.merge(other.docker_env);
+        self.download_connect_timeout
+            .merge(other.download_connect_timeout);
+        self.download_timeout.merge(other.download_timeout);
+        self.min_free_space.merge(other.min_free_space);
+        Merge::merge(&mut self.toolchain_files, other.toolchain_files);
+        Merge::merge(&mut self.extra_mounts, other.extra_mounts);
+        Merge::merge(&mut self.architecture_mounts, other.architecture_mounts);
+    }
+}
+
+/// How the seL4 artifact cache is shared between the builds of a workspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheMode {
+    /// All builds use the cache in the workspace directory
+    Shared,
+    /// Each build uses its own cache in the build directory
+    Isolated,
+}
+
+/// How directories mounted in docker are relabelled for SELinux
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum MountLabel {
+    /// Label the directory so that it can be shared between containers (`z`)
+    #[serde(rename = "z")]
+    Shared,
+    /// Label the directory so that only the container can use it (`Z`)
+    #[serde(rename = "Z")]
+    Private,
+    /// Leave the label of the directory unchanged
+    #[serde(rename = "none")]
+    Unlabelled,
+}
+
+impl MountLabel {
+    /// Suffix of the docker volume option that applies the label
+    pub fn volume_suffix(self) -> &'static str {
+        match self {
+            MountLabel::Shared => ":z",
+            MountLabel::Private => ":Z",
+            MountLabel::Unlabelled => "",
+        }
     }
 }
 
+/// Where a build should be run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunTarget {
+    /// Always run in the simulator
+    Simulate,
+    /// Always run on a hardware system from the machine queue
+    Hardware,
+    /// Run on hardware if a matching system exists, otherwise simulate
+    Auto,
+}
+
+impl RunTarget {
+    /// Choose between simulation and hardware given whether a matching system is available
+    ///
+    /// This never returns `Auto`.
+    pub fn resolve(self, hardware_available: bool) -> RunTarget {
+        match self {
+            RunTarget::Auto if hardware_available => RunTarget::Hardware,
+            RunTarget::Auto => RunTarget::Simulate,
+            target => target,
+        }
+    }
+}
+
+/// Which container runtime to use for build tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerRuntime {
+    /// Always use docker
+    Docker,
+    /// Always use podman
+    Podman,
+    /// Use podman if it is installed, otherwise docker
+    Auto,
+}
+
 /// Make reference option
 fn option_ref<T: AsRef<R>, R: ?Sized>(option: &Option<T>) -> Option<&R> {
     option.as_ref().map(|s| s.as_ref())
@@ -261,10 +1311,11 @@ pub trait Merge<Other = Self> {
 impl<T: Clone + Merge<T>, K: Ord> Merge for BTreeMap<K, T> {
     fn merge(&mut self, other: BTreeMap<K, T>) {
         for (key, other) in other.into_iter() {
-            if self.contains_key(&key) {
-                self.get_mut(&key).unwrap().merge(other);
-            } else {
-                self.insert(key, other);
+            match self.entry(key) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(other),
+                Entry::Vacant(entry) => {
+                    entry.insert(other);
+                }
             }
         }
     }
@@ -291,6 +1342,8 @@ pub trait MergeId {}
 
 impl MergeId for String {}
 
+impl MergeId for PathBuf {}
+
 impl<T: MergeId> Merge for T {
     fn merge(&mut self, other: Self) {
         *self = other;
@@ -346,14 +1399,20 @@ where
 }
 
 /// Mapping of name identifiers to items
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+///
+/// Maps are compared only by their items, not by where the items were loaded from.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(transparent)]
+#[serde(bound(serialize = "T: Serialize, T::Id: Serialize"))]
 pub struct NamedMap<T: Named>
 where
     T::Id: Ord,
     T::Id: for<'nde> Deserialize<'nde>,
 {
     map: BTreeMap<T::Id, T>,
+    /// Configuration file that each item was last merged from
+    #[serde(skip)]
+    sources: BTreeMap<T::Id, PathBuf>,
 }
 
 impl<T: Named> Default for NamedMap<T>
@@ -364,10 +1423,48 @@ where
     fn default() -> Self {
         NamedMap {
             map: BTreeMap::default(),
+            sources: BTreeMap::default(),
         }
     }
 }
 
+impl<T: Named + PartialEq> PartialEq for NamedMap<T>
+where
+    T::Id: Ord,
+    T::Id: for<'nde> Deserialize<'nde>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.map == other.map
+    }
+}
+
+impl<T: Named + Eq> Eq for NamedMap<T>
+where
+    T::Id: Ord,
+    T::Id: for<'nde> Deserialize<'nde>,
+{
+}
+
+impl<T: Named + PartialOrd> PartialOrd for NamedMap<T>
+where
+    T::Id: Ord,
+    T::Id: for<'nde> Deserialize<'nde>,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.map.partial_cmp(&other.map)
+    }
+}
+
+impl<T: Named + Ord> Ord for NamedMap<T>
+where
+    T::Id: Ord,
+    T::Id: for<'nde> Deserialize<'nde>,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.map.cmp(&other.map)
+    }
+}
+
 impl<T> NamedMap<T>
 where
     T: Named + Clone,
@@ -379,16 +1476,37 @@ where
     }
 
     /// Get an object with its name from the map
-    pub fn get(&self, index: &T::Id) -> Option<NameRef<T>> {
+    pub fn get(&self, index: &T::Id) -> Option<NameRef<'_, T>> {
         self.map
             .get_key_value(index)
             .map(move |(k, v)| NameRef::new(v, k))
     }
 
     /// Get all of the objects with names from the map
-    pub fn all(&self) -> impl Iterator<Item = NameRef<T>> {
+    pub fn all(&self) -> impl Iterator<Item = NameRef<'_, T>> {
         self.map.iter().map(|(k, v)| NameRef::new(v, k))
     }
+
+    /// Get the names of all of the objects in the map
+    pub fn ids(&self) -> impl Iterator<Item = &T::Id> {
+        self.map.keys()
+    }
+
+    /// Get the configuration file an object was last merged from
+    ///
+    /// Objects defined only by the builtin configuration have no source.
+    pub fn source(&self, index: &T::Id) -> Option<&Path> {
+        self.sources.get(index).map(PathBuf::as_path)
+    }
+
+    /// Record that every object in the map was loaded from a file
+    fn set_source(&mut self, path: &Path) {
+        self.sources = self
+            .map
+            .keys()
+            .map(|id| (id.clone(), path.to_owned()))
+            .collect();
+    }
 }
 
 impl<T: Named> Merge for NamedMap<T>
@@ -398,6 +1516,799 @@ where
     T: Clone + Merge<T>,
 {
     fn merge(&mut self, other: Self) {
-        self.map.merge(other.map)
+        for id in other.map.keys() {
+            self.sources.remove(id);
+        }
+        self.sources.extend(other.sources);
+        Merge::merge(&mut self.map, other.map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+    use crate::{AArch64, RiscV64};
+
+    #[test]
+    fn conflicting_defaults_fail_strict_config() {
+        let root = test_dir("strict-config");
+        let write = |name: &str, contents: &str| {
+            let path = root.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let home = write("home.toml", "git-server = \"https://github.com\"\n");
+        let same = write("same.toml", "git-server = \"https://github.com\"\n");
+        let project = write(
+            "project.toml",
+            "git-server = \"https://git.example.com\"\ndocker-image = \"local/sel4\"\n",
+        );
+
+        Config::check_conflicts(&[home.clone(), same]).unwrap();
+        let error = Config::check_conflicts(&[home.clone(), project.clone()])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(&format!(
+            "git-server: {} and {}",
+            home.display(),
+            project.display()
+        )));
+        assert!(!error.contains("docker-image"));
+    }
+
+    #[test]
+    fn exposed_easy_settings_on_command_line() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [project.sel4test]
+            repository = "seL4/sel4test-manifest"
+            command-line = ["mcs"]
+
+            [project.camkes]
+            repository = "seL4/camkes-manifest"
+            command-line = []
+            expose-easy-settings = true
+            "#,
+        )
+        .unwrap();
+        let mut easy_settings = NamedMap::default();
+        easy_settings.insert(
+            "kernel-printing".into(),
+            Flag::new("Print from the kernel", Some("KernelPrinting"), None),
+        );
+        config.add_flags(easy_settings);
+
+        let sel4test = config.command_line_flags(&"sel4test".into());
+        assert_eq!(sel4test, vec![FlagId::from("mcs")].into_iter().collect());
+        let camkes = config.command_line_flags(&"camkes".into());
+        assert!(camkes.contains(&"kernel-printing".into()));
+
+        config.merge(toml::from_str("expose-easy-settings = true").unwrap());
+        let sel4test = config.command_line_flags(&"sel4test".into());
+        assert!(sel4test.contains(&"mcs".into()));
+        assert!(sel4test.contains(&"kernel-printing".into()));
+    }
+
+    #[test]
+    fn dumped_config_loads_unchanged() {
+        let config = Config::builtin().unwrap();
+        let dumped: Config = toml::from_str(&config.dump(None, None).unwrap()).unwrap();
+        assert_eq!(dumped, config);
+
+        let sel4test = config.dump(Some(&"sel4test".into()), None).unwrap();
+        assert!(sel4test.starts_with("[project.sel4test]"));
+        assert!(!sel4test.contains("[platform."));
+        let odroidc2 = config.dump(None, Some(&"odroidc2".into())).unwrap();
+        let dumped: Config = toml::from_str(&odroidc2).unwrap();
+        assert_eq!(
+            *dumped.find_platform(&"odroidc2".into()).unwrap(),
+            *config.find_platform(&"odroidc2".into()).unwrap()
+        );
+        assert!(config.dump(Some(&"sel4tset".into()), None).is_err());
+    }
+
+    #[test]
+    fn easy_settings_overridden_by_config() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [flag.mcs]
+            description = "Use the MCS kernel"
+            variable = "MCS"
+            type = "bool"
+
+            [flag.verification]
+            description = "Build for verification"
+            variable = "Verification"
+            "#,
+        )
+        .unwrap();
+
+        let mut easy_settings = NamedMap::default();
+        easy_settings.insert(
+            "mcs".into(),
+            Flag::new("MCS from easy settings", Some("MCS"), None),
+        );
+        easy_settings.insert(
+            "platform".into(),
+            Flag::new("Platform to build for", Some("PLATFORM"), Some(Type::Text)),
+        );
+        config.add_flags(easy_settings);
+
+        let sources = config
+            .flag_sources()
+            .map(|(flag, source)| (flag.name().as_ref().to_owned(), source))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(sources["mcs"], FlagSource::Overridden);
+        assert_eq!(sources["platform"], FlagSource::EasySettings);
+        assert_eq!(sources["verification"], FlagSource::Config);
+        assert_eq!(
+            config.flags.get(&"mcs".into()).unwrap().description(),
+            "Use the MCS kernel"
+        );
+    }
+
+    #[test]
+    fn renamed_flag_is_migrated_once() {
+        let config: Config = toml::from_str(
+            r#"
+            [flag-rename]
+            old-flag = "new-flag"
+            "#,
+        )
+        .unwrap();
+
+        let mut setting = Setting::default();
+        setting.set_bool("old-flag", true);
+
+        let renamed = config.migrate_setting(&mut setting);
+        assert_eq!(renamed, vec![("old-flag".into(), "new-flag".into())]);
+        assert_eq!(
+            setting.flag(&"new-flag".into()),
+            crate::Value::Boolean(true)
+        );
+        assert_eq!(setting.flags().count(), 1);
+
+        assert!(config.migrate_setting(&mut setting).is_empty());
+    }
+
+    #[test]
+    fn cmake_arg_strings_match_command() {
+        let config = Config::builtin().unwrap();
+        let mut setting = config
+            .platform_setting(&"sel4test".into(), &"odroidc2".into(), None, AArch64)
+            .unwrap();
+        setting.set_bool("mcs", true);
+
+        let mut command = Command::new("cmake");
+        config.cmake_args(&setting, &mut command);
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+
+        assert_eq!(config.cmake_arg_strings(&setting), args);
+        assert!(args.contains(&"-DMCS=ON".to_owned()));
+        assert!(args.contains(&"-DKernelPlatform=odroidc2".to_owned()));
+        // Flags without a CMake variable are not passed
+        assert!(!args.iter().any(|arg| arg.contains("can-mcs")));
+    }
+
+    #[test]
+    fn locked_flags_can_not_be_overridden() {
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                locked = ["verification"]
+
+                [project.sel4test]
+                repository = "seL4/sel4test-manifest"
+                command-line = []
+                locked = ["fastpath"]
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let mut overrides = Setting::default();
+        overrides.set_bool("mcs", true);
+        config
+            .check_overrides(&"sel4test".into(), &overrides)
+            .unwrap();
+
+        for locked in &["verification", "fastpath"] {
+            let mut overrides = overrides.clone();
+            overrides.set_bool(*locked, false);
+            let error = config
+                .check_overrides(&"sel4test".into(), &overrides)
+                .err()
+                .unwrap();
+            assert_eq!(
+                error.to_string(),
+                format!("Flag {} is locked and can not be overridden", locked)
+            );
+        }
+    }
+
+    #[test]
+    fn exact_config_file() {
+        let path = test_dir("exact-config").join("s4.toml");
+        std::fs::write(&path, "git-server = \"ssh://git@example.com\"\n").unwrap();
+
+        let config = Config::load_exact(&path).unwrap();
+        assert_eq!(config.defaults().git_server(), "ssh://git@example.com");
+        assert_eq!(config.defaults().repo_url(), Defaults::REPO_URL);
+        assert_eq!(config.platforms, Config::builtin().unwrap().platforms);
+    }
+
+    #[test]
+    fn variation_keeps_kernel_platform() {
+        let config = Config::builtin().unwrap();
+        let setting = |platform: &str, variation: Option<&str>, arch| {
+            config
+                .platform_setting(
+                    &"sel4test".into(),
+                    &platform.into(),
+                    variation.map(VariationId::from).as_ref(),
+                    arch,
+                )
+                .unwrap()
+        };
+
+        // A variation replaces the platform but never the kernel platform
+        let rpi3 = setting("bcm2837", Some("rpi3"), AArch64);
+        assert_eq!(rpi3.kernel_platform(), Some("bcm2837"));
+        assert_eq!(rpi3.platform(), Some("rpi3"));
+        config.check_setting(&rpi3).unwrap();
+
+        // Platforms and variations may set the platform explicitly
+        let nehalem = setting("pc99", Some("nehalem"), crate::X86_64);
+        assert_eq!(nehalem.kernel_platform(), Some("pc99"));
+        assert_eq!(nehalem.platform(), Some("pc99"));
+        config.check_setting(&nehalem).unwrap();
+
+        let qemu = setting("qemu-cortex-a53", None, AArch64);
+        assert_eq!(qemu.kernel_platform(), Some("qemu-cortex-a53"));
+        assert_eq!(qemu.platform(), Some("qemu-arm-virt"));
+        config.check_setting(&qemu).unwrap();
+
+        let mut mismatched = rpi3;
+        mismatched.set_platform("odroidc2");
+        assert_eq!(
+            config.check_setting(&mismatched).err().unwrap().to_string(),
+            "Flag platform is odroidc2 but kernel-platform bcm2837 only allows bcm2837, rpi3"
+        );
+    }
+
+    #[test]
+    fn architecture_settings_merge_per_architecture() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [architecture.aarch64]
+            arm-hyp = false
+            verification = true
+
+            [architecture.riscv64]
+            riscv-code-model = "medany"
+            "#,
+        )
+        .unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [architecture.aarch64]
+                arm-hyp = true
+                smp = true
+
+                [architecture.x86_64]
+                mcs = true
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let aarch64 = &config.architectures[&AArch64];
+        assert_eq!(aarch64.flag(&"arm-hyp".into()), Value::Boolean(true));
+        assert_eq!(aarch64.flag(&"verification".into()), Value::Boolean(true));
+        assert_eq!(aarch64.flag(&"smp".into()), Value::Boolean(true));
+        assert_eq!(aarch64.flags().count(), 3);
+
+        let riscv64 = &config.architectures[&RiscV64];
+        assert_eq!(
+            riscv64.flag(&"riscv-code-model".into()),
+            Value::Text("medany".to_owned())
+        );
+        assert_eq!(riscv64.flags().count(), 1);
+
+        let x86_64 = &config.architectures[&crate::X86_64];
+        assert_eq!(x86_64.flag(&"mcs".into()), Value::Boolean(true));
+        assert_eq!(x86_64.flags().count(), 1);
+    }
+
+    #[test]
+    fn env_flags_are_passed_in_environment() {
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [flag.ccache]
+                description = "Cache compiler output"
+                env = "CCACHE_DISABLE"
+                type = "bool"
+                "#,
+            )
+            .unwrap(),
+        );
+        let mut setting = Setting::default();
+        setting.set_bool("ccache", false);
+
+        let apps = crate::Apps::mock(config.defaults(), crate::Docker);
+        let mut docker = apps.docker().unwrap();
+        for (name, value) in config.env_vars(&setting) {
+            docker = docker.env(name, value);
+        }
+        let mut command = docker.run("cmake");
+        config.cmake_args(&setting, &mut command);
+
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let env = args.iter().position(|arg| arg == "--env").unwrap();
+        assert_eq!(args[env + 1], "CCACHE_DISABLE=OFF");
+        assert!(env < args.iter().position(|arg| arg == "cmake").unwrap());
+        assert!(!args.iter().any(|arg| arg.starts_with("-D")));
+    }
+
+    #[test]
+    fn suggest_satisfying_requirements() {
+        let config = Config::builtin().unwrap();
+
+        let mut setting = Setting::default();
+        setting.set_text("architecture", "riscv");
+        let suggestion = config
+            .suggest_satisfying(&setting, &"riscv-ext-d".into())
+            .unwrap();
+        assert_eq!(
+            suggestion.flags().collect::<Vec<_>>(),
+            [(&FlagId::from("riscv-ext-f"), &Value::Boolean(true))]
+        );
+
+        let suggestion = config
+            .suggest_satisfying(&Setting::default(), &"riscv-ext-d".into())
+            .unwrap();
+        assert_eq!(
+            suggestion.flag(&"architecture".into()),
+            Value::Text("riscv".to_owned())
+        );
+        assert_eq!(suggestion.flag(&"riscv-ext-f".into()), Value::Boolean(true));
+        assert_eq!(suggestion.flags().count(), 2);
+
+        setting.set_bool("riscv-ext-f", true);
+        let suggestion = config
+            .suggest_satisfying(&setting, &"riscv-ext-d".into())
+            .unwrap();
+        assert_eq!(suggestion.flags().count(), 0);
+    }
+
+    #[test]
+    fn default_project_is_saved() {
+        let config = Config::builtin().unwrap();
+        let path = test_dir("set-project").join(".s4.toml");
+
+        config
+            .save_default_project(&"sel4test".into(), &path)
+            .unwrap();
+        let saved = Config::load_exact(&path).unwrap();
+        assert_eq!(
+            saved.defaults().default_project(),
+            Some(&ProjectId::from("sel4test"))
+        );
+
+        std::fs::write(
+            &path,
+            "default-project = \"sel4bench\"\n\
+             git-server = \"ssh://git@example.com\"\n\
+             \n\
+             [platform.custom]\n\
+             architectures = [ \"aarch64\" ]\n",
+        )
+        .unwrap();
+        config
+            .save_default_project(&"sel4test".into(), &path)
+            .unwrap();
+        let saved = Config::load_exact(&path).unwrap();
+        assert_eq!(
+            saved.defaults().default_project(),
+            Some(&ProjectId::from("sel4test"))
+        );
+        assert_eq!(saved.defaults().git_server(), "ssh://git@example.com");
+        assert!(saved.find_platform(&"custom".into()).is_ok());
+
+        let error = config
+            .save_default_project(&"sel4tset".into(), &path)
+            .unwrap_err();
+        assert!(error.to_string().contains("did you mean sel4test?"));
+    }
+
+    #[test]
+    fn platforms_directory_is_loaded() {
+        let root = test_dir("platforms-dir");
+        let path = root.join("s4.toml");
+        std::fs::write(&path, "platforms-directory = \"boards\"\n").unwrap();
+
+        let boards = root.join("boards");
+        std::fs::create_dir_all(&boards).unwrap();
+        std::fs::write(
+            boards.join("alpha.toml"),
+            "[platform.alpha]\narchitectures = [ \"aarch64\" ]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            boards.join("others.toml"),
+            concat!(
+                "[platform.beta]\narchitectures = [ \"riscv64\" ]\n",
+                "[platform.gamma]\narchitectures = [ \"x86_64\" ]\n",
+            ),
+        )
+        .unwrap();
+        std::fs::write(boards.join("README"), "not a platform").unwrap();
+
+        let config = Config::load_exact(&path).unwrap();
+        for name in &["alpha", "beta", "gamma", "odroidc2"] {
+            assert!(
+                config.find_platform(&PlatformId::from(*name)).is_ok(),
+                "missing platform {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn describe_reports_last_file() {
+        let root = test_dir("describe");
+        let first = root.join("first.toml");
+        std::fs::write(
+            &first,
+            "[platform.alpha]\narchitectures = [ \"aarch64\" ]\n[platform.beta]\narchitectures = [ \"aarch64\" ]\n",
+        )
+        .unwrap();
+        let second = root.join("second.toml");
+        std::fs::write(
+            &second,
+            "platforms-directory = \"boards\"\n[platform.alpha]\narchitectures = [ \"riscv64\" ]\n",
+        )
+        .unwrap();
+        let boards = root.join("boards");
+        std::fs::create_dir_all(&boards).unwrap();
+        let board = boards.join("beta.toml");
+        std::fs::write(&board, "[platform.beta]\ncan-mcs = true\n").unwrap();
+
+        let mut config = Config::builtin().unwrap();
+        config.merge_file(&first).unwrap();
+        config.merge_file(&second).unwrap();
+
+        assert_eq!(
+            config.describe("alpha"),
+            vec![("platform", DefinitionSource::File(&second))]
+        );
+        assert_eq!(
+            config.describe("beta"),
+            vec![("platform", DefinitionSource::File(&board))]
+        );
+        assert_eq!(
+            config.describe("odroidc2"),
+            vec![("platform", DefinitionSource::Builtin)]
+        );
+        assert!(config.describe("gamma").is_empty());
+
+        let mut easy_settings = NamedMap::default();
+        easy_settings.insert("mcs".into(), Flag::new("MCS", Some("KernelIsMCS"), None));
+        easy_settings.insert("extra".into(), Flag::new("Extra", Some("Extra"), None));
+        config.add_flags(easy_settings);
+        assert_eq!(
+            config.describe("mcs"),
+            vec![("flag", DefinitionSource::Builtin)]
+        );
+        assert_eq!(
+            config.describe("extra"),
+            vec![("flag", DefinitionSource::EasySettings)]
+        );
+
+        // Where the items were loaded from does not affect comparisons
+        let mut relabelled = config.platforms.clone();
+        relabelled.set_source(&first);
+        assert_eq!(relabelled, config.platforms);
+    }
+
+    #[test]
+    fn platform_without_architectures_is_invalid() {
+        let mut config = Config::builtin().unwrap();
+        config.validate().unwrap();
+
+        config.merge(
+            toml::from_str(
+                r#"
+                [platform.odroidc2.variation.smp4]
+                smp = true
+
+                [platform.new-board]
+                can-mcs = true
+                "#,
+            )
+            .unwrap(),
+        );
+        let error = config.validate().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Platform new-board declares no architectures"
+        );
+    }
+
+    #[test]
+    fn unknown_project_suggests_closest() {
+        let config = Config::builtin().unwrap();
+
+        let error = config.find_project(&"sel4tset".into()).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "No such project sel4tset (did you mean sel4test?)"
+        );
+
+        let error = config.find_project(&"camkes-vm".into()).err().unwrap();
+        assert_eq!(error.to_string(), "No such project camkes-vm");
+    }
+
+    #[test]
+    fn unknown_platform_suggests_closest() {
+        let config = Config::builtin().unwrap();
+        let error = config
+            .platform_setting(&"sel4test".into(), &"odroid2".into(), None, AArch64)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "No such platform odroid2 (did you mean odroidc2?)"
+        );
+    }
+
+    #[test]
+    fn riscv_code_model_is_validated() {
+        let config = Config::builtin().unwrap();
+        let mut setting = config
+            .platform_setting(&ProjectId::UNNAMED, &"spike".into(), None, RiscV64)
+            .unwrap();
+        assert_eq!(
+            setting.flag(&"riscv-code-model".into()),
+            Value::Text("medany".to_owned())
+        );
+
+        setting.set_text("riscv-code-model", "medlow");
+        config.check_setting(&setting).unwrap();
+
+        setting.set_text("riscv-code-model", "large");
+        let error = config.check_setting(&setting).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Flag riscv-code-model can not be set to large (expected one of: medany, medlow)"
+        );
+    }
+
+    #[test]
+    fn riscv_code_model_is_passed_to_cmake() {
+        let config = Config::builtin().unwrap();
+        let setting = config
+            .platform_setting(&ProjectId::UNNAMED, &"spike".into(), None, RiscV64)
+            .unwrap();
+        assert!(config
+            .cmake_arg_strings(&setting)
+            .contains(&"-DKernelRiscvCodeModel=medany".to_owned()));
+    }
+
+    #[test]
+    fn auto_run_target_prefers_hardware() {
+        assert_eq!(RunTarget::Auto.resolve(true), RunTarget::Hardware);
+        assert_eq!(RunTarget::Auto.resolve(false), RunTarget::Simulate);
+        assert_eq!(RunTarget::Simulate.resolve(true), RunTarget::Simulate);
+        assert_eq!(RunTarget::Hardware.resolve(false), RunTarget::Hardware);
+    }
+
+    #[test]
+    fn unknown_flags_are_reported() {
+        let config = Config::builtin().unwrap();
+        let extra: NamedMap<Flag> = toml::from_str(
+            r#"
+            lib-sel4-debug = { description = "Debug libsel4", variable = "LibSel4Debug" }
+            "#,
+        )
+        .unwrap();
+
+        let mut setting = Setting::default();
+        setting.set_bool("mcs", true);
+        setting.set_bool("lib-sel4-debug", true);
+        setting.set_bool("bogus-flag", true);
+
+        assert_eq!(
+            config.unknown_flags(&setting, &extra),
+            vec![FlagId::from("bogus-flag")]
+        );
+    }
+
+    #[test]
+    fn auto_enable_required_flags() {
+        let flags = r#"
+            [flag.a]
+            description = "Requires b"
+            type = "bool"
+
+                [[flag.a.requires]]
+                b = true
+
+            [flag.b]
+            description = "Required by a"
+            type = "bool"
+            "#;
+        let mut setting = Setting::default();
+        setting.set_bool("a", true);
+
+        // Strict by default
+        let mut config = Config::builtin().unwrap();
+        config.merge(toml::from_str(flags).unwrap());
+        let mut strict = setting.clone();
+        assert!(config.auto_enable(&mut strict).is_empty());
+        assert!(config.check_setting(&strict).is_err());
+
+        config.merge(toml::from_str("auto-enable = true").unwrap());
+        assert_eq!(config.auto_enable(&mut setting), vec![FlagId::from("b")]);
+        assert_eq!(setting.flag(&"b".into()), Value::Boolean(true));
+        config.check_setting(&setting).unwrap();
+
+        // Explicitly disabled flags are never enabled
+        let mut conflict = Setting::default();
+        conflict.set_bool("a", true);
+        conflict.set_bool("b", false);
+        assert!(config.auto_enable(&mut conflict).is_empty());
+        assert!(config.check_setting(&conflict).is_err());
+    }
+
+    #[test]
+    fn all_setting_problems_reported() {
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [flag.fast]
+                description = "Conflicts with safe"
+                type = "bool"
+
+                    [[flag.fast.requires]]
+                    safe = { not = true }
+
+                [flag.safe]
+                description = "Conflicts with fast"
+                type = "bool"
+
+                    [[flag.safe.requires]]
+                    fast = { not = true }
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let mut setting = Setting::default();
+        setting.set_bool("fast", true);
+        config.check_setting(&setting).unwrap();
+
+        setting.set_bool("safe", true);
+        setting.set_text("riscv-code-model", "large");
+        let error = config.check_setting(&setting).err().unwrap().to_string();
+        assert!(
+            error.starts_with("The setting has 4 problems:\n"),
+            "{}",
+            error
+        );
+        for problem in &[
+            "flag fast could be satisfied (needs safe != true)",
+            "flag safe could be satisfied (needs fast != true)",
+            "Flags fast and safe each require a different value of the other",
+            "Flag riscv-code-model can not be set to large",
+        ] {
+            assert!(
+                error.contains(problem),
+                "missing {:?} in {}",
+                problem,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn flag_metadata() {
+        let config = Config::builtin().unwrap();
+        let mcs = config.flag(&"mcs".into()).unwrap();
+        assert_eq!(mcs.name(), &FlagId::from("mcs"));
+        assert_eq!(mcs.description(), "Configure kernel to use MCS scheduler");
+        assert_eq!(mcs.variable(), Some("MCS"));
+        assert_eq!(mcs.ty(), Some(Type::Boolean));
+
+        assert!(config.flag(&"no-such-flag".into()).is_none());
+        assert!(config
+            .flags()
+            .any(|flag| flag.name().as_ref() == "kernel-platform"));
+    }
+
+    #[test]
+    fn deprecated_flags_warn() {
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [flag.old-option]
+                description = "An option removed from the kernel"
+                type = "bool"
+                deprecated = "use new-option instead"
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let mut setting = Setting::default();
+        setting.set_bool("fastpath", true);
+        assert!(config.deprecation_warnings(&setting).is_empty());
+
+        setting.set_bool("old-option", true);
+        assert_eq!(
+            config.deprecation_warnings(&setting),
+            ["Flag old-option is deprecated: use new-option instead"]
+        );
+        config.check_setting(&setting).unwrap();
+
+        config.set_strict(true);
+        assert_eq!(
+            config.check_setting(&setting).err().unwrap().to_string(),
+            "Flag old-option is deprecated: use new-option instead"
+        );
+    }
+
+    #[test]
+    fn command_line_flags_must_be_parseable() {
+        Config::builtin().unwrap().validate().unwrap();
+
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [flag.needs-mcs]
+                description = "Requires MCS"
+                type = "string"
+
+                    [[flag.needs-mcs.requires]]
+                    mcs = true
+
+                [project.sel4test]
+                repository = "seL4/sel4test-manifest"
+                command-line = ["needs-mcs"]
+                "#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            config.validate().err().unwrap().to_string(),
+            "Flag needs-mcs on the command line of project sel4test has requirements but is not boolean"
+        );
+    }
+
+    #[test]
+    fn raw_defaults_are_unset_without_fallback() {
+        let defaults = Defaults::default();
+        assert_eq!(defaults.raw_git_server(), None);
+        assert_eq!(defaults.git_server(), Defaults::GIT_SERVER);
+        assert_eq!(defaults.raw_docker_image(), None);
+        assert_eq!(defaults.docker_image(), Defaults::DOCKER_IMAGE);
+
+        let defaults: Defaults = toml::from_str(r#"git-server = "ssh://git@github.com""#).unwrap();
+        assert_eq!(defaults.raw_git_server(), Some("ssh://git@github.com"));
+        assert_eq!(defaults.git_server(), "ssh://git@github.com");
     }
 }
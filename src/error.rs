@@ -0,0 +1,171 @@
+//! Structured errors reported by s4
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+/// A stage of a build that runs an external tool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Configuring a build directory with CMake
+    Configure,
+    /// Building a build directory with ninja
+    Build,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Configure => write!(f, "CMake configure"),
+            Stage::Build => write!(f, "ninja build"),
+        }
+    }
+}
+
+/// Errors that s4 reports with additional structure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum S4Error {
+    /// A tool run for a stage of a build exited unsuccessfully
+    StageFailed {
+        stage: Stage,
+        /// Exit code of the tool (if it was not killed by a signal)
+        code: Option<i32>,
+        /// The last lines of output produced by the tool
+        output: Vec<String>,
+    },
+    /// A tool run for a stage of a build was stopped by an interrupt
+    Interrupted { stage: Stage },
+    /// Any other tool was stopped by an interrupt
+    ToolInterrupted {
+        /// The name of the program that was run
+        tool: String,
+    },
+    /// A download was stopped by an interrupt
+    DownloadInterrupted {
+        /// The URL being downloaded
+        url: String,
+    },
+    /// No system in the machine queue can run builds of a platform
+    NoMatchingSystem {
+        /// The platform (and variation) of the build
+        platform: String,
+    },
+    /// The docker or podman daemon could not be reached
+    ContainerDaemonUnavailable {
+        /// The message reported by the container tool
+        message: String,
+    },
+}
+
+impl S4Error {
+    /// Messages reported by docker and podman when their daemon can not be reached
+    const DAEMON_UNAVAILABLE: &'static [&'static str] = &[
+        "Cannot connect to the Docker daemon",
+        "Is the docker daemon running?",
+        "Cannot connect to Podman",
+        "unable to connect to Podman socket",
+    ];
+
+    /// Error for a stage that exited unsuccessfully given the tail of its output
+    ///
+    /// Failures caused by the container daemon not running are reported as
+    /// `ContainerDaemonUnavailable`.
+    pub fn stage_failed(stage: Stage, code: Option<i32>, output: Vec<String>) -> Self {
+        let daemon_down = output.iter().find(|line| {
+            Self::DAEMON_UNAVAILABLE
+                .iter()
+                .any(|pattern| line.contains(pattern))
+        });
+
+        match daemon_down {
+            Some(message) => S4Error::ContainerDaemonUnavailable {
+                message: message.trim().to_owned(),
+            },
+            None => S4Error::StageFailed {
+                stage,
+                code,
+                output,
+            },
+        }
+    }
+
+    /// Error for a command that was stopped by an interrupt
+    pub fn tool_interrupted(command: &Command) -> Self {
+        let program = Path::new(command.get_program());
+        S4Error::ToolInterrupted {
+            tool: program
+                .file_name()
+                .unwrap_or(program.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for S4Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            S4Error::StageFailed {
+                stage,
+                code,
+                output,
+            } => {
+                match code {
+                    Some(code) => write!(f, "{} failed (exit {})", stage, code)?,
+                    None => write!(f, "{} failed (killed by signal)", stage)?,
+                }
+                for line in output {
+                    write!(f, "\n    {}", line)?;
+                }
+                Ok(())
+            }
+            S4Error::Interrupted { stage } => write!(f, "{} interrupted", stage),
+            S4Error::ToolInterrupted { tool } => write!(f, "{} interrupted", tool),
+            S4Error::DownloadInterrupted { url } => write!(f, "Download of {} interrupted", url),
+            S4Error::NoMatchingSystem { platform } => {
+                write!(f, "No matching system found for {}", platform)
+            }
+            S4Error::ContainerDaemonUnavailable { message } => write!(
+                f,
+                "Could not connect to the container daemon: {}\n    is the docker/podman service running?",
+                message
+            ),
+        }
+    }
+}
+
+impl Error for S4Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daemon_down_is_detected() {
+        let message =
+            "docker: Cannot connect to the Docker daemon at unix:///var/run/docker.sock. \
+                       Is the docker daemon running?";
+        let error = S4Error::stage_failed(Stage::Build, Some(125), vec![message.to_owned()]);
+        assert_eq!(
+            error,
+            S4Error::ContainerDaemonUnavailable {
+                message: message.to_owned()
+            }
+        );
+        assert!(error
+            .to_string()
+            .ends_with("is the docker/podman service running?"));
+
+        let output = vec!["ninja: build stopped: subcommand failed.".to_owned()];
+        let error = S4Error::stage_failed(Stage::Build, Some(1), output.clone());
+        assert_eq!(
+            error,
+            S4Error::StageFailed {
+                stage: Stage::Build,
+                code: Some(1),
+                output
+            }
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Handling of interrupts while external tools are running
+
+use crate::S4Error;
+use anyhow::Result;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Set once an interrupt has been received
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Interval at which a running child is checked for an interrupt
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+extern "C" fn handle_interrupt(_: libc::c_int) {
+    // A second interrupt exits immediately in case s4 is not waiting on a child
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Install a handler so that an interrupt (SIGINT) stops running tools rather than s4 itself
+///
+/// After the first interrupt, any tool being waited on is terminated and s4 reports that it was
+/// interrupted once the tool has exited, giving it the chance to clean up. A second interrupt
+/// exits immediately.
+pub fn install_interrupt_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    });
+}
+
+/// Check if an interrupt has been received
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Wait for a child to exit, terminating it if an interrupt is received
+pub(crate) fn wait_interruptible(child: &mut Child) -> Result<ExitStatus> {
+    wait_or_terminate(child, &INTERRUPTED)
+}
+
+/// Run a command to completion, terminating it if an interrupt is received
+///
+/// It is an error if the command was interrupted.
+pub(crate) fn status_interruptible(command: &mut Command) -> Result<ExitStatus> {
+    status_or_terminate(command, &INTERRUPTED)
+}
+
+fn status_or_terminate(command: &mut Command, interrupted: &AtomicBool) -> Result<ExitStatus> {
+    let status = wait_or_terminate(&mut command.spawn()?, interrupted)?;
+    if interrupted.load(Ordering::SeqCst) {
+        return Err(S4Error::tool_interrupted(command).into());
+    }
+    Ok(status)
+}
+
+fn wait_or_terminate(child: &mut Child, interrupted: &AtomicBool) -> Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if interrupted.load(Ordering::SeqCst) {
+            unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+            return Ok(child.wait()?);
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::time::Instant;
+
+    #[test]
+    fn interrupt_terminates_child() {
+        let interrupted = AtomicBool::new(true);
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+
+        let start = Instant::now();
+        let status = wait_or_terminate(&mut child, &interrupted).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    }
+
+    #[test]
+    fn interrupted_command_is_an_error() {
+        let interrupted = AtomicBool::new(true);
+        let mut command = Command::new("/bin/sleep");
+        command.arg("10");
+
+        let error = status_or_terminate(&mut command, &interrupted).unwrap_err();
+        assert_eq!(
+            error.downcast::<S4Error>().unwrap(),
+            S4Error::ToolInterrupted {
+                tool: "sleep".to_owned()
+            }
+        );
+
+        let interrupted = AtomicBool::new(false);
+        let mut command = Command::new("false");
+        assert_eq!(
+            status_or_terminate(&mut command, &interrupted)
+                .unwrap()
+                .code(),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn uninterrupted_child_exits_normally() {
+        let interrupted = AtomicBool::new(false);
+        let mut child = Command::new("true").spawn().unwrap();
+        assert!(wait_or_terminate(&mut child, &interrupted)
+            .unwrap()
+            .success());
+    }
+}
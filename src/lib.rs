@@ -10,22 +10,42 @@
 mod app;
 mod cmake;
 mod config;
+mod error;
+mod interrupt;
 mod platform;
 mod project;
+mod script;
 mod util;
 mod workspace;
 
 pub use app::*;
 pub use cmake::*;
 pub use config::*;
+pub use error::*;
+pub use interrupt::*;
 pub use platform::*;
 pub use project::*;
+pub use script::*;
 pub use workspace::*;
 
+/// The version of the s4 crate
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn version_is_semver() {
+        let version = super::version();
+        let parts = version.split('.').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 3, "{}", version);
+        assert!(parts[..2].iter().all(|part| part.parse::<u32>().is_ok()));
+        assert!(parts[2].starts_with(|c: char| c.is_ascii_digit()));
+    }
 }
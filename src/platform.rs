@@ -1,19 +1,23 @@
 //! Platform definitions
 
-use crate::cmake::Setting;
+use crate::cmake::{FlagId, Setting, Value};
 use crate::{Merge, NameRef, Named, NamedMap};
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, format_err, Error, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 use std::convert::TryFrom;
+use std::env::var;
 use std::fmt;
 use std::str::FromStr;
 
 /// A single platform known to the build system
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Platform {
+    /// Human readable description of the platform
+    description: Option<String>,
     /// Supported architectures
+    #[serde(default)]
     architectures: BTreeSet<Sel4Architecture>,
     /// Variations
     #[serde(rename = "variation", alias = "variant", default)]
@@ -27,10 +31,46 @@ impl Platform {
         &self.setting
     }
 
-    pub fn variation(&self, id: &VariationId) -> Option<NameRef<Variation>> {
+    /// Human readable description of the platform
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// All flags set by the platform (excluding its variations)
+    pub fn all_flags(&self) -> impl Iterator<Item = (&FlagId, &Value)> {
+        self.setting.flags()
+    }
+
+    /// All variations of the platform
+    pub fn variations(&self) -> impl Iterator<Item = NameRef<'_, Variation>> {
+        self.variations.all()
+    }
+
+    /// Architectures supported by the platform
+    pub fn architectures(&self) -> impl Iterator<Item = Sel4Architecture> + '_ {
+        self.architectures.iter().copied()
+    }
+
+    /// Ensure the platform definition is usable
+    pub fn validate(self_ref: &NameRef<Self>) -> Result<()> {
+        if self_ref.architectures.is_empty() {
+            bail!(
+                "Platform {} declares no architectures",
+                self_ref.name().as_ref()
+            );
+        }
+        Ok(())
+    }
+
+    pub fn variation(&self, id: &VariationId) -> Option<NameRef<'_, Variation>> {
         self.variations.get(id)
     }
 
+    /// Identifiers of all variations of the platform
+    pub fn variation_ids(&self) -> impl Iterator<Item = &VariationId> {
+        self.variations.ids()
+    }
+
     pub fn check_architecture(
         self_ref: &NameRef<Self>,
         architecture: Sel4Architecture,
@@ -47,8 +87,41 @@ impl Platform {
     }
 }
 
+/// Shows the flags contributed by a platform and each of its variations
+impl fmt::Display for NameRef<'_, Platform> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "platform {}", self.name().as_ref())?;
+        if let Some(description) = self.description() {
+            write!(f, " - {}", description)?;
+        }
+        write!(f, "\n    architectures:")?;
+        for architecture in self.architectures() {
+            write!(f, " {}", architecture)?;
+        }
+        for (flag, value) in self.all_flags() {
+            write!(f, "\n    {}: {}", flag, value)?;
+        }
+        for variation in self.variations() {
+            write!(
+                f,
+                "\nvariation {}:{}",
+                self.name().as_ref(),
+                variation.name().as_ref()
+            )?;
+            if let Some(description) = variation.description() {
+                write!(f, " - {}", description)?;
+            }
+            for (flag, value) in variation.all_flags() {
+                write!(f, "\n    {}: {}", flag, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Merge for Platform {
     fn merge(&mut self, other: Self) {
+        self.description.merge(other.description);
         self.architectures.merge(other.architectures);
         self.variations.merge(other.variations);
         self.setting.merge(other.setting);
@@ -86,8 +159,10 @@ impl AsRef<str> for PlatformId {
 ///
 /// Where a platform may refer to multiple compatible architectures, the variation can specify a
 /// particular architecture with a certain set of features.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub struct Variation {
+    /// Human readable description of the variation
+    description: Option<String>,
     #[serde(flatten)]
     setting: Setting,
 }
@@ -96,10 +171,21 @@ impl Variation {
     pub fn setting(&self) -> &Setting {
         &self.setting
     }
+
+    /// Human readable description of the variation
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// All flags set by the variation on top of its platform
+    pub fn all_flags(&self) -> impl Iterator<Item = (&FlagId, &Value)> {
+        self.setting.flags()
+    }
 }
 
 impl Merge for Variation {
     fn merge(&mut self, other: Self) {
+        self.description.merge(other.description);
         self.setting.merge(other.setting);
     }
 }
@@ -143,6 +229,47 @@ pub enum PlatformChoice {
 }
 use PlatformChoice::*;
 
+impl PlatformChoice {
+    /// Environment variable used as the default platform choice
+    pub const ENV_VAR: &'static str = "S4_PLATFORM";
+
+    /// The chosen platform
+    pub fn platform(&self) -> &PlatformId {
+        match self {
+            ChoosePlatform(platform) => platform,
+            ChooseVariation(platform, _) => platform,
+        }
+    }
+
+    /// The chosen variation (if any)
+    pub fn variation(&self) -> Option<&VariationId> {
+        match self {
+            ChoosePlatform(_) => None,
+            ChooseVariation(_, variation) => Some(variation),
+        }
+    }
+
+    /// Use an explicit choice or fall back to the choice in the environment
+    pub fn or_env(explicit: Option<Self>) -> Result<Self> {
+        Self::or_lookup(explicit, |name| var(name).ok())
+    }
+
+    fn or_lookup(explicit: Option<Self>, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        match explicit {
+            Some(choice) => Ok(choice),
+            None => match lookup(Self::ENV_VAR) {
+                Some(choice) => choice
+                    .parse()
+                    .map_err(|e| format_err!("Invalid {}: {}", Self::ENV_VAR, e)),
+                None => bail!(
+                    "No platform specified; use --platform or set {}",
+                    Self::ENV_VAR
+                ),
+            },
+        }
+    }
+}
+
 impl FromStr for PlatformChoice {
     type Err = Error;
 
@@ -166,9 +293,9 @@ impl TryFrom<String> for PlatformChoice {
     }
 }
 
-impl Into<String> for PlatformChoice {
-    fn into(self) -> String {
-        format!("{}", self)
+impl From<PlatformChoice> for String {
+    fn from(value: PlatformChoice) -> Self {
+        format!("{}", value)
     }
 }
 
@@ -224,6 +351,19 @@ pub enum Sel4Architecture {
 pub use Sel4Architecture::*;
 
 impl Sel4Architecture {
+    /// All of the seL4 named architectures
+    const ALL: [Sel4Architecture; 6] = [AArch32, AArch64, RiscV32, RiscV64, Ia32, X86_64];
+
+    /// Iterate over all of the seL4 named architectures
+    pub fn all() -> impl Iterator<Item = Sel4Architecture> {
+        Self::ALL.iter().copied()
+    }
+
+    /// Iterate over the seL4 named architectures of an architecture family
+    pub fn all_of(architecture: Architecture) -> impl Iterator<Item = Sel4Architecture> {
+        Self::all().filter(move |sel4_arch| sel4_arch.architecture() == architecture)
+    }
+
     pub fn architecture(self) -> Architecture {
         match self {
             AArch32 => Arm,
@@ -236,6 +376,31 @@ impl Sel4Architecture {
     }
 }
 
+impl Sel4Architecture {
+    /// Environment variable used as the default architecture
+    pub const ENV_VAR: &'static str = "S4_ARCH";
+
+    /// Use an explicit architecture or fall back to the architecture in the environment
+    pub fn or_env(explicit: Option<Self>) -> Result<Self> {
+        Self::or_lookup(explicit, |name| var(name).ok())
+    }
+
+    fn or_lookup(explicit: Option<Self>, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        match explicit {
+            Some(architecture) => Ok(architecture),
+            None => match lookup(Self::ENV_VAR) {
+                Some(architecture) => architecture
+                    .parse()
+                    .map_err(|e| format_err!("Invalid {}: {}", Self::ENV_VAR, e)),
+                None => bail!(
+                    "No architecture specified; use --arch or set {}",
+                    Self::ENV_VAR
+                ),
+            },
+        }
+    }
+}
+
 impl FromStr for Sel4Architecture {
     type Err = Error;
 
@@ -261,9 +426,9 @@ impl TryFrom<String> for Sel4Architecture {
     }
 }
 
-impl Into<String> for Sel4Architecture {
-    fn into(self) -> String {
-        format!("{}", self)
+impl From<Sel4Architecture> for String {
+    fn from(value: Sel4Architecture) -> Self {
+        format!("{}", value)
     }
 }
 
@@ -279,3 +444,102 @@ impl fmt::Display for Sel4Architecture {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_architectures() {
+        assert_eq!(
+            Sel4Architecture::all().collect::<Vec<_>>(),
+            [AArch32, AArch64, RiscV32, RiscV64, Ia32, X86_64]
+        );
+        assert_eq!(
+            Sel4Architecture::all_of(RiscV).collect::<Vec<_>>(),
+            [RiscV32, RiscV64]
+        );
+    }
+
+    #[test]
+    fn show_platform_flags() {
+        let platforms: NamedMap<Platform> = toml::from_str(
+            r#"
+            [odroidc2]
+            architectures = ["aarch64"]
+            can-mcs = true
+            has-hypervisor = true
+
+            [odroidc2.variation.smp4]
+            smp = true
+            "#,
+        )
+        .unwrap();
+        let platform = platforms.get(&"odroidc2".into()).unwrap();
+
+        assert_eq!(
+            platform.to_string(),
+            "platform odroidc2\n    \
+             architectures: aarch64\n    \
+             can-mcs: true\n    \
+             has-hypervisor: true\n\
+             variation odroidc2:smp4\n    \
+             smp: true"
+        );
+    }
+
+    #[test]
+    fn show_platform_description() {
+        let platforms: NamedMap<Platform> = toml::from_str(
+            r#"
+            [odroidc2]
+            description = "Hardkernel ODROID-C2"
+            architectures = ["aarch64"]
+
+            [odroidc2.variation.smp4]
+            description = "All four cores"
+            smp = true
+            "#,
+        )
+        .unwrap();
+        let platform = platforms.get(&"odroidc2".into()).unwrap();
+        let variation = platform.variation(&"smp4".into()).unwrap();
+
+        assert_eq!(platform.description(), Some("Hardkernel ODROID-C2"));
+        assert_eq!(variation.description(), Some("All four cores"));
+        assert_eq!(platform.all_flags().count(), 0);
+        assert_eq!(variation.all_flags().count(), 1);
+        assert_eq!(
+            platform.to_string(),
+            "platform odroidc2 - Hardkernel ODROID-C2\n    \
+             architectures: aarch64\n\
+             variation odroidc2:smp4 - All four cores\n    \
+             smp: true"
+        );
+    }
+
+    #[test]
+    fn environment_defaults() {
+        let env = |name: &str| match name {
+            "S4_PLATFORM" => Some("odroidc2:smp4".to_owned()),
+            "S4_ARCH" => Some("aarch64".to_owned()),
+            _ => None,
+        };
+
+        let choice = PlatformChoice::or_lookup(None, env).unwrap();
+        assert_eq!(choice.platform().as_ref(), "odroidc2");
+        assert_eq!(choice.variation().map(|v| v.as_ref()), Some("smp4"));
+        assert_eq!(Sel4Architecture::or_lookup(None, env).unwrap(), AArch64);
+
+        let explicit = PlatformChoice::or_lookup(Some("pc99".parse().unwrap()), env).unwrap();
+        assert_eq!(explicit, ChoosePlatform("pc99".into()));
+        assert_eq!(
+            Sel4Architecture::or_lookup(Some(X86_64), env).unwrap(),
+            X86_64
+        );
+
+        let error = PlatformChoice::or_lookup(None, |_| None).err().unwrap();
+        assert!(error.to_string().contains("S4_PLATFORM"));
+        assert!(Sel4Architecture::or_lookup(None, |_| None).is_err());
+    }
+}
@@ -1,31 +1,59 @@
 //! Descriptions of projects
 
 use crate::util::*;
-use crate::{Apps, BuildContext, Config, Context, FlagId, Merge, Named, Setting, CACHE_SUBDIR};
+use crate::{
+    Apps, BuildContext, Config, Context, Defaults, FlagId, Merge, Named, RunTarget, S4Error,
+    Sel4Architecture, Setting, Stage,
+};
 use anyhow::{bail, format_err, Error, Result};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::fmt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Stdio};
 use std::str::FromStr;
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Project {
     repository: Repository,
-    /// Path to the CMake source directory
-    #[serde(alias = "source-dir")]
-    source_directory: Option<PathBuf>,
+    /// Candidate paths of the CMake source directory, in order of preference
+    #[serde(
+        default,
+        rename = "source-directory",
+        alias = "source-dir",
+        deserialize_with = "deserialize_paths"
+    )]
+    source_directories: Vec<PathBuf>,
+    /// Subdirectories searched for the easy settings hint (overrides the default)
+    source_search: Option<Vec<PathBuf>>,
     /// Name of the root server binary
     #[serde(alias = "rootserver")]
     root_server: Option<String>,
+    /// Path of the directory containing built images relative to the build directory
+    #[serde(alias = "images-dir")]
+    images_directory: Option<PathBuf>,
+    /// Pattern of root server image names, with `{rootserver}` and `{plat}` placeholders
+    image_name_pattern: Option<String>,
+    /// Command run in the build directory to print the paths of the images to run
+    image_resolver: Option<String>,
     /// Phrase used to indicate the root server has completed
     exit_phrase: Option<String>,
     /// Flags to make available via the command line when configuring a build directory
     #[serde(alias = "cmdline")]
     command_line: BTreeSet<FlagId>,
+    /// Flags that can not be overridden by the user
+    #[serde(default)]
+    locked: BTreeSet<FlagId>,
+    /// Manifest groups to sync with repo (overrides the default)
+    repo_groups: Option<Vec<String>>,
+    /// Extra arguments passed verbatim to repo init (overrides the default)
+    repo_init_args: Option<Vec<String>>,
+    /// Whether to run builds in simulation or on hardware (overrides the default)
+    run_target: Option<RunTarget>,
+    /// Make every flag from the easy settings available on the command line (overrides the default)
+    expose_easy_settings: Option<bool>,
     #[serde(flatten)]
     setting: Setting,
 }
@@ -34,11 +62,34 @@ impl Project {
     pub fn setting(&self) -> &Setting {
         &self.setting
     }
+
+    /// Path of the directory containing built images relative to the build directory
+    pub fn images_dir(&self) -> &Path {
+        self.images_directory
+            .as_deref()
+            .unwrap_or_else(|| Path::new(Self::IMAGES_DIR))
+    }
+
+    /// Pattern used to name root server images and to infer the root server from them
+    pub fn image_name_pattern(&self) -> &str {
+        self.image_name_pattern
+            .as_deref()
+            .unwrap_or(Self::IMAGE_NAME_PATTERN)
+    }
 }
 
 impl Merge for Project {
     fn merge(&mut self, other: Self) {
         self.command_line.merge(other.command_line);
+        self.image_name_pattern.merge(other.image_name_pattern);
+        self.image_resolver.merge(other.image_resolver);
+        self.images_directory.merge(other.images_directory);
+        self.locked.merge(other.locked);
+        self.repo_groups.merge(other.repo_groups);
+        self.repo_init_args.merge(other.repo_init_args);
+        self.run_target.merge(other.run_target);
+        self.expose_easy_settings.merge(other.expose_easy_settings);
+        self.source_search.merge(other.source_search);
         self.setting.merge(other.setting);
     }
 }
@@ -51,89 +102,250 @@ impl Project {
     pub const WORKSPACE_DOCKER_DIR: &'static str = "/workspace";
     pub const BUILD_DOCKER_DIR: &'static str = "/build";
     pub const CMAKE_CACHE_FILE: &'static str = "settings.cmake";
+    pub const SIMULATE_SCRIPT: &'static str = "simulate";
+    pub const IMAGES_DIR: &'static str = "images";
+    pub const IMAGE_NAME_PATTERN: &'static str = "{rootserver}-image-{plat}";
+
+    /// Path of the CMake source directory relative to the workspace
+    ///
+    /// The first configured source directory that exists is used; otherwise the source directory
+    /// is inferred from the easy settings hints in the workspace.
+    pub fn source_dir(
+        &self,
+        context: &(impl Context + ?Sized),
+        defaults: &Defaults,
+    ) -> Result<PathBuf> {
+        let workspace_root = context.workspace_root();
+        if let Some(directory) = self
+            .source_directories
+            .iter()
+            .find(|directory| workspace_root.join(directory).is_dir())
+        {
+            return Ok(directory.clone());
+        }
+
+        let search = self
+            .source_search
+            .as_deref()
+            .unwrap_or_else(|| defaults.source_search());
+        context.search_source(search).map_err(|error| {
+            if self.source_directories.is_empty() {
+                error
+            } else {
+                format_err!(
+                    "None of the source directories {} exist: {}",
+                    self.source_directories
+                        .iter()
+                        .map(|directory| directory.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    error
+                )
+            }
+        })
+    }
 
     pub fn init(&self, workspace_root: impl AsRef<Path>, apps: &Apps) -> Result<()> {
+        let workspace_root = workspace_root.as_ref();
+        apps.check_free_space(workspace_root)?;
         in_dir(workspace_root, || {
-            if !apps.repo_init(&self.repository)?.success() {
+            let groups = self
+                .repo_groups
+                .as_deref()
+                .unwrap_or_else(|| apps.defaults().repo_groups());
+            let extra_args = self.repo_init_args(apps.defaults());
+            if !apps
+                .repo_init(&self.repository, groups, extra_args)?
+                .success()
+            {
                 bail!("Failed to initialise project")
             }
-            if !apps.repo().arg("sync").status()?.success() {
+            if !apps.status(&mut apps.repo_sync())?.success() {
                 bail!("Failed to sync project")
             }
             Ok(())
         })
     }
 
-    pub fn init_build(
-        &self,
-        context: &BuildContext,
-        apps: &Apps,
-        config: &Config,
-    ) -> Result<ExitStatus> {
+    pub fn init_build(&self, context: &BuildContext, apps: &Apps, config: &Config) -> Result<()> {
+        if apps.defaults().check_image_tools() {
+            apps.check_image_tools()?;
+        }
+
         let mut command = self.cmake(context, apps, config)?;
 
         // Alwayse generate ninja builds
         command.arg("-G").arg("Ninja");
 
-        // Use the workspace cache directory
+        // Use the workspace or build cache directory
         command.arg(format!(
-            "-DSEL4_CACHE_DIR={}/{}",
-            Self::WORKSPACE_DOCKER_DIR,
-            CACHE_SUBDIR
+            "-DSEL4_CACHE_DIR={}",
+            context
+                .docker_cache_dir(apps.defaults().cache_mode())
+                .display()
         ));
 
+        // Use the toolchain file configured for the architecture
+        if let Some(arg) = Self::toolchain_arg(apps.defaults(), context.architecture()) {
+            command.arg(arg);
+        }
+
         // Use the build directory as mapped into docker
         command.arg("-B").arg(Self::BUILD_DOCKER_DIR);
 
         // Use the source directory as mapped into docker
         let mut source_dir = PathBuf::new();
         source_dir.push(Self::WORKSPACE_DOCKER_DIR);
-        let source_directory = self
-            .source_directory
-            .as_ref()
-            .cloned()
-            .map(Ok)
-            .unwrap_or(context.inferred_source())?;
-        source_dir.push(source_directory);
+        source_dir.push(self.source_dir(context, apps.defaults())?);
         command.arg("-S").arg(&source_dir);
 
         // Use the cache file from the source directory
         source_dir.push(Self::CMAKE_CACHE_FILE);
         command.arg("-C").arg(source_dir);
 
-        println!("{:?}", command);
-        Ok(command.status()?)
+        apps.echo(&command);
+        apps.run_stage(Stage::Configure, &mut command)?;
+        Self::mark_configured(context, apps)
+    }
+
+    /// Argument to use the toolchain file configured for an architecture as mapped into docker
+    fn toolchain_arg(defaults: &Defaults, architecture: Sel4Architecture) -> Option<String> {
+        defaults.toolchain_file(architecture).map(|file| {
+            format!(
+                "-DCMAKE_TOOLCHAIN_FILE={}",
+                Path::new(Self::WORKSPACE_DOCKER_DIR).join(file).display()
+            )
+        })
+    }
+
+    /// Configure a build directory from scratch, discarding its CMake cache
+    ///
+    /// This is needed when the source or flags of a build change in ways that are incompatible
+    /// with the existing cache. Build outputs are kept.
+    pub fn fresh_build(&self, context: &BuildContext, apps: &Apps, config: &Config) -> Result<()> {
+        if !apps.is_dry_run() {
+            context.clear_cmake_cache()?;
+        }
+        self.init_build(context, apps, config)
     }
 
+    /// Re-run CMake for a build directory
+    ///
+    /// Unless `force` is set, CMake is only run if the setting has changed since the build
+    /// directory was last configured.
     pub fn update_build(
         &self,
         context: &BuildContext,
         apps: &Apps,
         config: &Config,
-    ) -> Result<ExitStatus> {
+        force: bool,
+    ) -> Result<()> {
+        if !force && !context.needs_reconfigure()? {
+            println!("Setting unchanged since last configure; skipping CMake");
+            return Ok(());
+        }
+
         let mut command = self.cmake(context, apps, config)?;
         command.arg(Self::BUILD_DOCKER_DIR);
-        Ok(command.status()?)
+        apps.run_stage(Stage::Configure, &mut command)?;
+        Self::mark_configured(context, apps)
+    }
+
+    /// Record a successful configure, unless CMake was not actually run
+    fn mark_configured(context: &BuildContext, apps: &Apps) -> Result<()> {
+        if apps.is_dry_run() {
+            return Ok(());
+        }
+        context.mark_configured()
     }
 
     fn cmake(&self, context: &BuildContext, apps: &Apps, config: &Config) -> Result<Command> {
         // Make sure we can actually build with the given settings
-        config.check_setting(context.setting())?;
-        context.save()?;
+        context.workspace().check_synced()?;
+        context.check_local_overrides(config)?;
+        let mut setting = context.configure_setting()?;
+        config.auto_enable(&mut setting);
+        config.check_setting(&setting)?;
+        if !apps.is_dry_run() {
+            context.save()?;
+        }
 
-        let mut command = apps
-            .docker()?
-            .mount(Self::WORKSPACE_DOCKER_DIR, context.workspace_root())?
-            .mount(Self::BUILD_DOCKER_DIR, context.build_root())?
-            .work_dir(Self::BUILD_DOCKER_DIR)?
-            .run("cmake");
+        let mut docker = context.docker(apps)?.work_dir(Self::BUILD_DOCKER_DIR)?;
+
+        // Pass flags that are set in the environment
+        for (name, value) in config.env_vars(&setting) {
+            docker = docker.env(name, value);
+        }
+
+        let mut command = docker.run("cmake");
 
         // Add the command line arguments to be set directly
-        config.cmake_args(&context.setting(), &mut command);
+        config.cmake_args(&setting, &mut command);
 
         Ok(command)
     }
 
+    /// Run a build in simulation or on hardware according to the configured run target
+    ///
+    /// When the run target is `auto`, the build is run on hardware if a matching system is
+    /// available and in simulation otherwise. Any other error choosing a system, such as an
+    /// explicitly chosen system that can not run the build or an unreachable machine queue, is
+    /// reported rather than falling back to simulation.
+    pub fn run(
+        &self,
+        context: &BuildContext,
+        config: &Config,
+        apps: &Apps,
+        system: Option<&str>,
+    ) -> Result<()> {
+        let target = self
+            .run_target
+            .unwrap_or_else(|| config.defaults().run_target());
+
+        let systems = match target {
+            RunTarget::Simulate => Vec::new(),
+            RunTarget::Hardware => Self::systems(context, apps, system)?,
+            RunTarget::Auto => match Self::systems(context, apps, system) {
+                Err(error)
+                    if system.is_none()
+                        && matches!(
+                            error.downcast_ref(),
+                            Some(S4Error::NoMatchingSystem { .. })
+                        ) =>
+                {
+                    Vec::new()
+                }
+                systems => systems?,
+            },
+        };
+
+        match target.resolve(!systems.is_empty()) {
+            RunTarget::Simulate => self.simulate(context, apps),
+            _ => self.mq_run_systems(context, config, apps, systems),
+        }
+    }
+
+    /// Run a build in the simulator
+    pub fn simulate(&self, context: &BuildContext, apps: &Apps) -> Result<()> {
+        if !context.build_root().join(Self::SIMULATE_SCRIPT).is_file() {
+            bail!("Build directory has no simulation script; configure it with simulation enabled");
+        }
+
+        let mut command = context
+            .docker(apps)?
+            .work_dir(Self::BUILD_DOCKER_DIR)?
+            .run(format!("./{}", Self::SIMULATE_SCRIPT));
+
+        apps.echo(&command);
+        if !apps
+            .status_logged(&mut command, &context.new_run_log()?)?
+            .success()
+        {
+            bail!("Simulation failed");
+        }
+        Ok(())
+    }
+
     pub fn mq_run(
         &self,
         context: &BuildContext,
@@ -141,14 +353,33 @@ impl Project {
         apps: &Apps,
         system: Option<&str>,
     ) -> Result<()> {
-        let systems = system
-            .map(|sys| Ok(vec![sys.to_owned()]))
-            .unwrap_or_else(|| {
-                apps.machine_queue_match_system(context.platform(), context.variation())
-            })?;
+        let systems = Self::systems(context, apps, system)?;
+        self.mq_run_systems(context, config, apps, systems)
+    }
+
+    /// Systems to try to run a build on
+    ///
+    /// An explicitly chosen system must match the platform and variation of the build.
+    fn systems(context: &BuildContext, apps: &Apps, system: Option<&str>) -> Result<Vec<String>> {
+        match system {
+            Some(system) => {
+                apps.machine_queue_check_system(system, context.platform(), context.variation())?;
+                Ok(vec![system.to_owned()])
+            }
+            None => apps.machine_queue_match_system(context.platform(), context.variation()),
+        }
+    }
 
+    fn mq_run_systems(
+        &self,
+        context: &BuildContext,
+        config: &Config,
+        apps: &Apps,
+        systems: Vec<String>,
+    ) -> Result<()> {
+        let log = context.new_run_log()?;
         for system in systems {
-            let result = self.try_mq_run(context, config, apps, system)?;
+            let result = self.try_mq_run(context, config, apps, system, &log)?;
 
             if result.success() {
                 return Ok(());
@@ -164,39 +395,141 @@ impl Project {
         config: &Config,
         apps: &Apps,
         system: String,
+        log: &Path,
     ) -> Result<ExitStatus> {
+        let mut command = self.mq_run_command(context, config, apps, system)?;
+        apps.echo(&command);
+        apps.status_logged(&mut command, log)
+    }
+
+    fn mq_run_command(
+        &self,
+        context: &BuildContext,
+        config: &Config,
+        apps: &Apps,
+        system: String,
+    ) -> Result<Command> {
         let mut command = apps.machine_queue()?;
         command.arg("run");
         command.arg("-c").arg(
             self.exit_phrase
-                .as_ref()
-                .map(|s| s.as_str())
+                .as_deref()
                 .unwrap_or(config.defaults().exit_phrase()),
         );
         command.arg("-s").arg(system);
 
-        if context.architecture().architecture() == crate::X86 {
-            command.arg("-f").arg(context.kernel_image_path()?);
+        let images = self.image_paths(context)?;
+        if let Some(kernel) = images.kernel {
+            command.arg("-f").arg(kernel);
+        }
+        command.arg("-f").arg(images.root_server);
+
+        command.current_dir(context.build_root());
+        Ok(command)
+    }
+
+    /// Paths of the images to run relative to the build directory
+    ///
+    /// If the project has an image resolver, it is run in the build directory and must print a
+    /// `rootserver=<path>` line and, if a separate kernel image is needed, a `kernel=<path>` line.
+    fn image_paths(&self, context: &BuildContext) -> Result<ImagePaths> {
+        if let Some(resolver) = &self.image_resolver {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(resolver)
+                .current_dir(context.build_root())
+                .stderr(Stdio::inherit())
+                .output()?;
+            if !output.status.success() {
+                bail!("Image resolver '{}' failed", resolver);
+            }
+            return ImagePaths::parse(&String::from_utf8(output.stdout)?)
+                .map_err(|e| format_err!("Image resolver '{}': {}", resolver, e));
         }
 
+        let kernel = if context.architecture().architecture() == crate::X86 {
+            Some(context.kernel_image_path(self.images_dir())?)
+        } else {
+            None
+        };
+
         let root_server = self
             .root_server
             .as_ref()
             .cloned()
             .map(Ok)
-            .unwrap_or_else(|| context.inferred_root_server())?;
-        command.arg("-f").arg(context.image_path(&root_server)?);
+            .unwrap_or_else(|| {
+                context.inferred_root_server(self.images_dir(), self.image_name_pattern())
+            })?;
+        let root_server =
+            context.image_path(self.images_dir(), self.image_name_pattern(), &root_server)?;
 
-        command.current_dir(context.build_root());
+        Ok(ImagePaths {
+            kernel,
+            root_server,
+        })
+    }
 
-        println!("{:?}", command);
-        Ok(command.status()?)
+    /// Check whether the images needed to run a build have been built
+    pub fn images_built(&self, context: &BuildContext) -> bool {
+        self.image_paths(context).is_ok()
     }
 
     /// Flags that should appear on the command-line
     pub fn command_line_flags(&self) -> impl Iterator<Item = &FlagId> {
         self.command_line.iter()
     }
+
+    /// Extra arguments passed verbatim to repo init
+    pub fn repo_init_args<'a>(&'a self, defaults: &'a Defaults) -> &'a [String] {
+        self.repo_init_args
+            .as_deref()
+            .unwrap_or_else(|| defaults.repo_init_args())
+    }
+
+    /// Whether every flag from the easy settings should appear on the command-line
+    pub fn expose_easy_settings(&self, defaults: &Defaults) -> bool {
+        self.expose_easy_settings
+            .unwrap_or_else(|| defaults.expose_easy_settings())
+    }
+
+    /// Flags that can not be overridden by the user
+    pub fn locked_flags(&self) -> impl Iterator<Item = &FlagId> {
+        self.locked.iter()
+    }
+}
+
+/// Images used to run a build
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ImagePaths {
+    /// Kernel image (if it is not part of the root server image)
+    kernel: Option<PathBuf>,
+    /// Root server image
+    root_server: PathBuf,
+}
+
+impl ImagePaths {
+    /// Parse the `kind=path` lines printed by an image resolver
+    fn parse(output: &str) -> Result<Self> {
+        let mut kernel = None;
+        let mut root_server = None;
+        for line in output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            match line.split_once('=') {
+                Some(("kernel", path)) => kernel = Some(PathBuf::from(path)),
+                Some(("rootserver", path)) => root_server = Some(PathBuf::from(path)),
+                _ => bail!("unexpected output: {}", line),
+            }
+        }
+
+        Ok(ImagePaths {
+            kernel,
+            root_server: root_server.ok_or_else(|| format_err!("no rootserver image given"))?,
+        })
+    }
 }
 
 /// Identifier of a project
@@ -262,9 +595,9 @@ impl TryFrom<String> for Repository {
     }
 }
 
-impl Into<String> for Repository {
-    fn into(self) -> String {
-        format!("{}", self)
+impl From<Repository> for String {
+    fn from(value: Repository) -> Self {
+        format!("{}", value)
     }
 }
 
@@ -273,3 +606,194 @@ impl fmt::Display for Repository {
         write!(f, "{}/{}", self.0, self.1)
     }
 }
+
+/// Deserialize either a single path or a list of paths
+fn deserialize_paths<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Paths {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+
+    Ok(match Paths::deserialize(deserializer)? {
+        Paths::One(path) => vec![path],
+        Paths::Many(paths) => paths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DockerImpl, WorkspaceContext};
+
+    #[test]
+    fn image_resolver_output_is_run() {
+        let root = test_dir("image-resolver");
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [project.sel4test]
+                repository = "seL4/sel4test-manifest"
+                command-line = []
+                image-resolver = "echo kernel=out/kernel.elf; echo rootserver=out/$(basename $PWD).elf"
+                "#,
+            )
+            .unwrap(),
+        );
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let build = BuildContext::create(
+            &config,
+            &workspace,
+            "odroidc2".into(),
+            None,
+            crate::AArch64,
+            Setting::default(),
+            root.join("build"),
+            false,
+        )
+        .unwrap();
+
+        let apps = Apps::mock(config.defaults(), DockerImpl::Docker);
+        let project = config.project(build.project());
+        let command = project
+            .mq_run_command(&build, &config, &apps, "odroidc2".to_owned())
+            .unwrap();
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(args[..2], ["run", "-c"]);
+        assert_eq!(args[3..5], ["-s", "odroidc2"]);
+        assert_eq!(args[5..], ["-f", "out/kernel.elf", "-f", "out/build.elf"]);
+    }
+
+    #[test]
+    fn auto_run_reports_system_errors() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = test_dir("auto-run");
+        let config = Config::builtin().unwrap();
+        assert_eq!(config.defaults().run_target(), RunTarget::Auto);
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let build = BuildContext::create(
+            &config,
+            &workspace,
+            "odroidc2".into(),
+            None,
+            crate::AArch64,
+            Setting::default(),
+            root.join("build"),
+            false,
+        )
+        .unwrap();
+        let project = config.project(build.project());
+
+        let mut apps = Apps::mock(config.defaults(), DockerImpl::Docker);
+        let mut run = |name: &str, systems: &str, system: Option<&str>| {
+            let machine_queue = root.join(name);
+            std::fs::write(
+                &machine_queue,
+                format!(
+                    "#!/bin/sh\n[ \"$1\" = system-tsv ] && printf '{}'\nexit 0\n",
+                    systems
+                ),
+            )
+            .unwrap();
+            std::fs::set_permissions(&machine_queue, std::fs::Permissions::from_mode(0o755))
+                .unwrap();
+            apps.set_machine_queue(machine_queue);
+            project
+                .run(&build, &config, &apps, system)
+                .unwrap_err()
+                .to_string()
+        };
+
+        let systems = "name\\tsel4_plat\\nhaswell2\\tpc99:haswell\\n";
+        assert_eq!(
+            run("mq-explicit.sh", systems, Some("haswell2")),
+            "System haswell2 runs pc99:haswell but the build is for odroidc2"
+        );
+
+        // Only the lack of a matching system falls back to simulation
+        assert!(run("mq-unmatched.sh", systems, None)
+            .starts_with("Build directory has no simulation script"));
+        assert!(run("mq-empty.sh", "name\\tsel4_plat\\n", None)
+            .starts_with("The machine queue listed no systems"));
+    }
+
+    #[test]
+    fn source_directory_candidates_and_search() {
+        let root = test_dir("source-search");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        for source in ["projects/first", "projects/second", "projects/libs"] {
+            std::fs::create_dir_all(root.join(source)).unwrap();
+        }
+        std::fs::write(root.join("projects/first/easy-settings.cmake"), "").unwrap();
+        let defaults: Defaults = toml::from_str(r#"source-search = ["projects/*"]"#).unwrap();
+
+        let project: Project = toml::from_str(
+            r#"
+            repository = "seL4/sel4test-manifest"
+            source-directory = ["projects/missing", "projects/second"]
+            command-line = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            project.source_dir(&workspace, &defaults).unwrap(),
+            Path::new("projects/second")
+        );
+
+        let project: Project = toml::from_str(
+            r#"
+            repository = "seL4/sel4test-manifest"
+            command-line = []
+            "#,
+        )
+        .unwrap();
+        assert!(project
+            .source_dir(&workspace, &Defaults::default())
+            .is_err());
+        assert_eq!(
+            project.source_dir(&workspace, &defaults).unwrap(),
+            Path::new("projects/first")
+        );
+
+        std::fs::write(root.join("projects/second/easy-settings.cmake"), "").unwrap();
+        let error = project
+            .source_dir(&workspace, &defaults)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("projects/first, projects/second"));
+    }
+
+    #[test]
+    fn toolchain_file_for_architecture() {
+        let defaults: Defaults = toml::from_str(
+            r#"
+            [toolchain-file]
+            aarch64 = "tools/aarch64-toolchain.cmake"
+            x86_64 = "/opt/toolchains/x86_64.cmake"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Project::toolchain_arg(&defaults, crate::AArch64).as_deref(),
+            Some("-DCMAKE_TOOLCHAIN_FILE=/workspace/tools/aarch64-toolchain.cmake")
+        );
+        assert_eq!(
+            Project::toolchain_arg(&defaults, crate::X86_64).as_deref(),
+            Some("-DCMAKE_TOOLCHAIN_FILE=/opt/toolchains/x86_64.cmake")
+        );
+        assert_eq!(Project::toolchain_arg(&defaults, crate::RiscV64), None);
+    }
+
+    #[test]
+    fn image_resolver_requires_rootserver() {
+        assert!(ImagePaths::parse("kernel=kernel.elf\n").is_err());
+        assert!(ImagePaths::parse("rootserver=a.elf\nother=b\n").is_err());
+    }
+}
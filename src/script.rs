@@ -0,0 +1,238 @@
+//! Helper scripts defined in the configuration
+
+use crate::{Apps, Context, MergeId, Named};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+
+/// A shell command run with `s4 run <name>`
+///
+/// Scripts are run on the host (not in docker) with `sh -c`. Any extra arguments are passed to
+/// the script as positional parameters.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Script {
+    /// Human readable description of the script
+    description: Option<String>,
+    /// Shell command to run
+    command: String,
+    /// Whether the script runs in the workspace or in a build directory
+    #[serde(default)]
+    context: ScriptContext,
+}
+
+/// A later definition of a script replaces the whole script
+impl MergeId for Script {}
+
+impl Named for Script {
+    type Id = ScriptId;
+}
+
+impl Script {
+    /// Environment variable holding the path of the workspace
+    pub const WORKSPACE_ENV_VAR: &'static str = "S4_WORKSPACE";
+    /// Environment variable holding the project of the workspace
+    pub const PROJECT_ENV_VAR: &'static str = "S4_PROJECT";
+    /// Environment variable holding the path of the build directory (if any)
+    pub const BUILD_ENV_VAR: &'static str = "S4_BUILD";
+    /// Environment variable holding the description of the build (if any)
+    pub const DESCRIBE_ENV_VAR: &'static str = "S4_DESCRIBE";
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Build the command that runs the script
+    ///
+    /// Scripts with a build context require a build directory and run in it; other scripts run in
+    /// the workspace directory.
+    pub fn command(
+        &self,
+        name: &ScriptId,
+        context: &dyn Context,
+        args: &[String],
+    ) -> Result<Command> {
+        let directory: &Path = match (self.context, context.maybe_build_root()) {
+            (ScriptContext::Workspace, _) => context.workspace_root(),
+            (ScriptContext::Build, Some(build_root)) => build_root,
+            (ScriptContext::Build, None) => bail!(
+                "Script {} must be run in a build directory; use --build-dir to select one",
+                name.as_ref()
+            ),
+        };
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .arg(name.as_ref())
+            .args(args)
+            .current_dir(directory)
+            .env(Self::WORKSPACE_ENV_VAR, context.workspace_root())
+            .env(Self::PROJECT_ENV_VAR, context.project().as_ref());
+        if let Some(build_root) = context.maybe_build_root() {
+            command.env(Self::BUILD_ENV_VAR, build_root);
+        }
+        if let Some(describe) = context.maybe_describe() {
+            command.env(Self::DESCRIBE_ENV_VAR, describe);
+        }
+        Ok(command)
+    }
+
+    /// Run the script, failing if it does not exit successfully
+    ///
+    /// In a dry run the script is only printed.
+    pub fn run(
+        &self,
+        apps: &Apps,
+        name: &ScriptId,
+        context: &dyn Context,
+        args: &[String],
+    ) -> Result<()> {
+        if !apps
+            .status(&mut self.command(name, context, args)?)?
+            .success()
+        {
+            bail!("Script {} failed", name.as_ref());
+        }
+        Ok(())
+    }
+}
+
+/// Where a script is run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScriptContext {
+    /// Run in the workspace directory
+    #[default]
+    Workspace,
+    /// Run in a build directory
+    Build,
+}
+
+/// Identifier of a script
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct ScriptId(String);
+
+impl From<String> for ScriptId {
+    fn from(s: String) -> Self {
+        ScriptId(s)
+    }
+}
+
+impl From<&str> for ScriptId {
+    fn from(s: &str) -> Self {
+        ScriptId(s.to_owned())
+    }
+}
+
+impl AsRef<str> for ScriptId {
+    fn as_ref(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::test_dir;
+    use crate::{Apps, BuildContext, Config, Defaults, Docker, Setting, WorkspaceContext};
+    use std::fs::read_to_string;
+
+    #[test]
+    fn script_runs_with_environment() {
+        let config: Config = toml::from_str(
+            r#"
+            [script.where]
+            description = "Record where the script ran"
+            command = 'echo "$PWD|$S4_WORKSPACE|$S4_PROJECT|$S4_BUILD|$S4_DESCRIBE|$0|$1" > "$1"'
+
+            [script.flash]
+            command = 'pwd > "$S4_BUILD/flash.out"'
+            context = "build"
+            "#,
+        )
+        .unwrap();
+
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, Docker);
+        let root = test_dir("script").canonicalize().unwrap();
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let script = config.find_script(&"where".into()).unwrap();
+
+        let output = root.join("workspace.out");
+        script
+            .run(
+                &apps,
+                &"where".into(),
+                &workspace,
+                &[output.display().to_string()],
+            )
+            .unwrap();
+        assert_eq!(
+            read_to_string(&output).unwrap().trim(),
+            format!(
+                "{0}|{0}|sel4test|||where|{1}",
+                root.display(),
+                output.display()
+            )
+        );
+
+        let build = BuildContext::create(
+            &Config::builtin().unwrap(),
+            &workspace,
+            "odroidc2".into(),
+            None,
+            crate::AArch64,
+            Setting::default(),
+            root.join("build"),
+            false,
+        )
+        .unwrap();
+        let output = root.join("build.out");
+        script
+            .run(
+                &apps,
+                &"where".into(),
+                &build,
+                &[output.display().to_string()],
+            )
+            .unwrap();
+        let build_root = root.join("build");
+        assert_eq!(
+            read_to_string(&output).unwrap().trim(),
+            format!(
+                "{}|{}|sel4test|{}|{}|where|{}",
+                root.display(),
+                root.display(),
+                build_root.display(),
+                build.describe(),
+                output.display()
+            )
+        );
+
+        let flash = config.find_script(&"flash".into()).unwrap();
+        assert!(flash.run(&apps, &"flash".into(), &workspace, &[]).is_err());
+        flash.run(&apps, &"flash".into(), &build, &[]).unwrap();
+        assert_eq!(
+            read_to_string(build_root.join("flash.out")).unwrap().trim(),
+            build_root.display().to_string()
+        );
+        assert!(config.find_script(&"flahs".into()).is_err());
+
+        // Scripts are only printed in a dry run
+        let mut apps = Apps::mock(&defaults, Docker);
+        apps.set_dry_run(true);
+        let output = root.join("dry-run.out");
+        script
+            .run(
+                &apps,
+                &"where".into(),
+                &workspace,
+                &[output.display().to_string()],
+            )
+            .unwrap();
+        assert!(!output.exists());
+    }
+}
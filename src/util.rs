@@ -1,12 +1,28 @@
 //! Utilities for library
 
+use crate::interrupt::{interrupted, wait_interruptible};
+use crate::{S4Error, Stage};
 use anyhow::Result;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::env::{current_dir, set_current_dir};
-use std::fs::File;
-use std::io::{Read, Write};
+use std::collections::VecDeque;
+use std::env::{current_dir, set_current_dir, var_os};
+use std::ffi::{CString, OsStr};
+use std::fs::{
+    create_dir_all, read_dir, read_to_string, remove_dir_all, rename, File, OpenOptions,
+};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+/// Number of lines of output retained when a stage fails
+const STAGE_OUTPUT_TAIL: usize = 20;
 
 pub(crate) fn toml_load<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T> {
     let mut data = Vec::new();
@@ -14,11 +30,253 @@ pub(crate) fn toml_load<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T
     toml::from_slice(&data).map_err(|e| e.into())
 }
 
+/// Save data as TOML, replacing the file atomically so it is never left partially written
+///
+/// If the file already exists, only the values that changed are rewritten so that any comments and
+/// formatting added by hand are kept.
 pub(crate) fn toml_save<T: Serialize>(data: &T, path: impl AsRef<Path>) -> Result<()> {
-    File::create(path.as_ref())?
-        .write(toml::to_vec(&data)?.as_slice())
-        .map_err(|e| e.into())
-        .map(|_| ())
+    let path = path.as_ref();
+    let mut document: DocumentMut = toml::to_string(&data)?.parse()?;
+    if let Some(mut existing) = read_to_string(path)
+        .ok()
+        .and_then(|text| text.parse::<DocumentMut>().ok())
+    {
+        update_table(existing.as_table_mut(), document.as_table());
+        document = existing;
+    }
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+
+    File::create(&partial)?.write_all(document.to_string().as_bytes())?;
+    rename(&partial, path)?;
+    Ok(())
+}
+
+/// Update a TOML table in place to match another, keeping the formatting of unchanged entries
+fn update_table(table: &mut Table, new: &Table) {
+    let removed = table
+        .iter()
+        .map(|(key, _)| key.to_owned())
+        .filter(|key| !new.contains_key(key))
+        .collect::<Vec<_>>();
+    for key in removed {
+        table.remove(&key);
+    }
+
+    fn undecorated(value: &Value) -> String {
+        let mut value = value.clone();
+        value.decor_mut().clear();
+        value.to_string()
+    }
+
+    for (key, item) in new.iter() {
+        match (table.get_mut(key), item) {
+            (Some(Item::Table(existing)), Item::Table(item)) => update_table(existing, item),
+            (Some(Item::Value(existing)), Item::Value(value)) => {
+                if undecorated(existing) != undecorated(value) {
+                    let decor = existing.decor().clone();
+                    *existing = value.clone();
+                    *existing.decor_mut() = decor;
+                }
+            }
+            (Some(existing), item) => *existing = item.clone(),
+            (None, item) => {
+                table.insert(key, item.clone());
+            }
+        }
+    }
+}
+
+/// Space in bytes available to unprivileged users on the file system containing a path
+// The statvfs fields are u64 on 64-bit Linux but narrower on some other targets
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn available_space(path: &Path) -> Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+/// Render a command as it could be typed into a shell
+///
+/// Arguments are quoted where needed, and any working directory and environment variables set on
+/// the command are included. Variables set to the value they already have in the environment of
+/// s4 are left out, as they are inherited anyway; this keeps forwarded host variables (which may
+/// hold credentials) out of printed commands.
+pub(crate) fn display_command(command: &Command) -> String {
+    fn quote(arg: &OsStr) -> String {
+        let arg = arg.to_string_lossy();
+        let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+        if !arg.is_empty() && arg.chars().all(safe) {
+            arg.into_owned()
+        } else {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        }
+    }
+
+    let mut parts = Vec::new();
+    if let Some(dir) = command.get_current_dir() {
+        parts.push(format!("cd {} &&", quote(dir.as_os_str())));
+    }
+    for (name, value) in command.get_envs() {
+        if let Some(value) = value.filter(|value| var_os(name).as_deref() != Some(*value)) {
+            parts.push(format!("{}={}", name.to_string_lossy(), quote(value)));
+        }
+    }
+    parts.push(quote(command.get_program()));
+    parts.extend(command.get_args().map(quote));
+    parts.join(" ")
+}
+
+/// Run the command for a stage of a build, reporting a structured error if it fails
+///
+/// Output is passed through to the terminal as it is produced and the tail of the output is
+/// included in the error. If an interrupt is received, the command is terminated.
+pub(crate) fn run_stage(stage: Stage, command: &mut Command) -> Result<()> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let tail = Arc::new(Mutex::new(VecDeque::new()));
+
+    fn tee(
+        stream: impl Read + Send + 'static,
+        tail: &Arc<Mutex<VecDeque<String>>>,
+        print: fn(&str),
+    ) -> thread::JoinHandle<()> {
+        let tail = tail.clone();
+        thread::spawn(move || {
+            // Read raw lines so that output that is not UTF-8 does not stop the stream from being
+            // drained, which would leave the tool blocked on or killed by a closed pipe
+            let mut stream = BufReader::new(stream);
+            let mut bytes = Vec::new();
+            while stream.read_until(b'\n', &mut bytes).unwrap_or(0) > 0 {
+                let text = String::from_utf8_lossy(&bytes);
+                let line = text.strip_suffix('\n').unwrap_or(&text);
+                let line = line.strip_suffix('\r').unwrap_or(line).to_owned();
+                bytes.clear();
+
+                print(&line);
+                let mut tail = tail.lock().unwrap();
+                if tail.len() == STAGE_OUTPUT_TAIL {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+        })
+    }
+
+    let readers = vec![
+        child
+            .stdout
+            .take()
+            .map(|out| tee(out, &tail, |l| println!("{}", l))),
+        child
+            .stderr
+            .take()
+            .map(|err| tee(err, &tail, |l| eprintln!("{}", l))),
+    ];
+
+    let status = wait_interruptible(&mut child)?;
+    for reader in readers.into_iter().flatten() {
+        let _ = reader.join();
+    }
+
+    if interrupted() {
+        Err(S4Error::Interrupted { stage }.into())
+    } else if status.success() {
+        Ok(())
+    } else {
+        let output = tail.lock().unwrap().drain(..).collect();
+        Err(S4Error::stage_failed(stage, status.code(), output).into())
+    }
+}
+
+/// Run a command, passing its output through to the terminal and appending it to a log file
+///
+/// Output is copied byte for byte as soon as it is produced, so prompts without a trailing newline
+/// are shown and output that is not UTF-8 is kept. The directory of the log is created if needed. If an interrupt is received, the command is
+/// terminated.
+pub(crate) fn run_logged(command: &mut Command, log: &Path) -> Result<ExitStatus> {
+    if let Some(directory) = log.parent() {
+        create_dir_all(directory)?;
+    }
+    let log = Arc::new(Mutex::new(
+        OpenOptions::new().create(true).append(true).open(log)?,
+    ));
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    /// Writes output to both the terminal and the log
+    ///
+    /// Errors writing either copy are ignored so that the output of the command is always
+    /// drained.
+    struct Tee<W> {
+        terminal: W,
+        log: Arc<Mutex<File>>,
+    }
+
+    impl<W: Write> Write for Tee<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self
+                .terminal
+                .write_all(buf)
+                .and_then(|()| self.terminal.flush());
+            let _ = self.log.lock().unwrap().write_all(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn tee(
+        mut stream: impl Read + Send + 'static,
+        log: &Arc<Mutex<File>>,
+        terminal: impl Write + Send + 'static,
+    ) -> thread::JoinHandle<()> {
+        let mut tee = Tee {
+            terminal,
+            log: log.clone(),
+        };
+        thread::spawn(move || {
+            let _ = io::copy(&mut stream, &mut tee);
+        })
+    }
+
+    let readers = vec![
+        child.stdout.take().map(|out| tee(out, &log, io::stdout())),
+        child.stderr.take().map(|err| tee(err, &log, io::stderr())),
+    ];
+
+    let status = wait_interruptible(&mut child)?;
+    for reader in readers.into_iter().flatten() {
+        let _ = reader.join();
+    }
+    if interrupted() {
+        return Err(S4Error::tool_interrupted(command).into());
+    }
+    Ok(status)
+}
+
+/// A stable hash of the TOML representation of some data
+pub(crate) fn toml_hash<T: Serialize>(data: &T) -> Result<String> {
+    // 64-bit FNV-1a
+    let hash = toml::to_vec(data)?
+        .into_iter()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+    Ok(format!("{:016x}", hash))
 }
 
 pub(crate) fn in_dir<T>(path: impl AsRef<Path>, f: impl FnOnce() -> Result<T>) -> Result<T> {
@@ -29,6 +287,100 @@ pub(crate) fn in_dir<T>(path: impl AsRef<Path>, f: impl FnOnce() -> Result<T>) -
     result
 }
 
+/// Create a directory (if it does not exist) and populate it
+///
+/// If populating the directory fails, any directories that were created are removed again. A
+/// directory that already existed is left in place.
+pub(crate) fn with_new_dir<T>(path: impl AsRef<Path>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let created = path
+        .as_ref()
+        .ancestors()
+        .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+        .last()
+        .map(Path::to_owned);
+    create_dir_all(path.as_ref())?;
+
+    let result = f();
+    if let (Err(_), Some(created)) = (&result, created) {
+        let _ = remove_dir_all(created);
+    }
+    result
+}
+
+/// Most recent modification time of any file in a directory tree
+///
+/// Hidden files and directories (such as `.git`) are ignored.
+pub(crate) fn newest_modification(path: impl AsRef<Path>) -> Result<Option<SystemTime>> {
+    let mut newest = None;
+    for entry in read_dir(path.as_ref())? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let modified = if metadata.is_dir() {
+            newest_modification(entry.path())?
+        } else {
+            Some(metadata.modified()?)
+        };
+        newest = newest.max(modified);
+    }
+    Ok(newest)
+}
+
+/// Edit distance between two strings
+pub(crate) fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + if a == *b { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Find the candidate closest to a string, if any is close enough to be a likely typo
+pub(crate) fn closest_match<'c, T: AsRef<str> + 'c>(
+    target: &str,
+    candidates: impl IntoIterator<Item = &'c T>,
+) -> Option<&'c T> {
+    let threshold = (target.chars().count() / 3).max(2);
+    candidates
+        .into_iter()
+        .map(|candidate| (levenshtein(target, candidate.as_ref()), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// A " (did you mean x?)" hint for an unknown identifier
+pub(crate) fn suggestion_hint<'c, T: AsRef<str> + 'c>(
+    target: &str,
+    candidates: impl IntoIterator<Item = &'c T>,
+) -> String {
+    closest_match(target, candidates)
+        .map(|candidate| format!(" (did you mean {}?)", candidate.as_ref()))
+        .unwrap_or_default()
+}
+
+/// Create a fresh, empty directory for a test
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("s4-test-{}-{}", std::process::id(), name));
+    if path.exists() {
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
 pub(crate) fn relative_path(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<PathBuf> {
     let to = to.as_ref().canonicalize()?;
     let mut to = to.components();
@@ -45,7 +397,7 @@ pub(crate) fn relative_path(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Res
 
     let mut result = PathBuf::new();
 
-    while let Some(_) = from_next {
+    while from_next.is_some() {
         result.push("..");
         from_next = from.next();
     }
@@ -57,3 +409,102 @@ pub(crate) fn relative_path(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Res
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_quoted() {
+        let mut command = Command::new("cmake");
+        command
+            .arg("-DKernelOptimisation=-O2")
+            .arg("-DCROSS_COMPILER_PREFIX=")
+            .arg("")
+            .arg("two words")
+            .arg("it's")
+            .env("CCACHE_DIR", "/tmp/cache dir")
+            .current_dir("/build");
+        assert_eq!(
+            display_command(&command),
+            "cd /build && CCACHE_DIR='/tmp/cache dir' cmake -DKernelOptimisation=-O2 \
+             -DCROSS_COMPILER_PREFIX= '' 'two words' 'it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn failing_stage_reports_stage_and_output() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo configuring; echo 'bad option' >&2; exit 1"]);
+
+        let error = run_stage(Stage::Configure, &mut command).err().unwrap();
+        let error = error.downcast::<S4Error>().unwrap();
+        match &error {
+            S4Error::StageFailed {
+                stage,
+                code,
+                output,
+            } => {
+                assert_eq!(*stage, Stage::Configure);
+                assert_eq!(*code, Some(1));
+                assert_eq!(output.len(), 2);
+                assert!(output.contains(&"bad option".to_owned()));
+            }
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert!(error
+            .to_string()
+            .starts_with("CMake configure failed (exit 1)"));
+    }
+
+    #[test]
+    fn stage_output_need_not_be_utf8() {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "printf 'bad \\377 byte\\n'; echo still running; exit 1",
+        ]);
+
+        let error = run_stage(Stage::Build, &mut command).err().unwrap();
+        match error.downcast::<S4Error>().unwrap() {
+            S4Error::StageFailed { output, .. } => {
+                assert_eq!(output, ["bad \u{fffd} byte", "still running"]);
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn successful_stage() {
+        let mut command = Command::new("true");
+        run_stage(Stage::Build, &mut command).unwrap();
+    }
+
+    #[test]
+    fn logged_output_is_appended() {
+        let log = test_dir("run-logged").join("logs").join("run.log");
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo booting; echo 'All is well' >&2"]);
+
+        assert!(run_logged(&mut command, &log).unwrap().success());
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo again; exit 2"]);
+        assert_eq!(run_logged(&mut command, &log).unwrap().code(), Some(2));
+
+        let output = read_to_string(&log).unwrap();
+        let mut lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.pop(), Some("again"));
+        lines.sort_unstable();
+        assert_eq!(lines, ["All is well", "booting"]);
+    }
+
+    #[test]
+    fn logged_output_is_copied_unchanged() {
+        let log = test_dir("run-logged-raw").join("run.log");
+        let mut command = Command::new("sh");
+        command.args(["-c", "printf 'serial \\377\\n'; printf 'login: '"]);
+
+        assert!(run_logged(&mut command, &log).unwrap().success());
+        assert_eq!(std::fs::read(&log).unwrap(), b"serial \xff\nlogin: ");
+    }
+}
@@ -2,18 +2,25 @@
 
 use crate::util::*;
 use crate::{
-    Apps, Config, Docker, Flag, Merge, NamedMap, PlatformId, Project, ProjectId, Sel4Architecture,
-    Setting, Type, VariationId,
+    Apps, CacheMode, Config, Docker, Flag, FlagId, Merge, NamedMap, Platform, PlatformChoice,
+    PlatformId, Project, ProjectId, Sel4Architecture, Setting, Stage, Type, Value, VariationId,
 };
-use anyhow::{bail, Result};
+use anyhow::{bail, format_err, Result};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
-use std::env::current_dir;
-use std::fs::{create_dir_all, read_dir, File};
-use std::io::{BufRead, BufReader};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env::{current_dir, temp_dir, var_os};
+use std::fmt;
+use std::fs::{
+    create_dir, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, write, File,
+};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Inferred execution context
 pub trait Context {
@@ -25,6 +32,16 @@ pub trait Context {
         None
     }
 
+    /// The architecture being built (if in a build directory)
+    fn maybe_architecture(&self) -> Option<Sel4Architecture> {
+        None
+    }
+
+    /// Identifier of the build (if in a build directory)
+    fn maybe_describe(&self) -> Option<String> {
+        None
+    }
+
     /// Obtain only the workspace context
     fn workspace(&self) -> &WorkspaceContext;
 
@@ -32,6 +49,7 @@ pub trait Context {
     fn project(&self) -> &ProjectId;
 
     /// Create a new build context
+    #[allow(clippy::too_many_arguments)]
     fn create_build(
         self: Box<Self>,
         config: &Config,
@@ -40,6 +58,7 @@ pub trait Context {
         variation: Option<VariationId>,
         architecture: Sel4Architecture,
         setting: Setting,
+        force: bool,
     ) -> Result<BuildContext> {
         BuildContext::create(
             config,
@@ -49,6 +68,7 @@ pub trait Context {
             architecture,
             setting,
             path,
+            force,
         )
     }
 
@@ -62,10 +82,21 @@ pub trait Context {
             docker = docker.mount(Project::BUILD_DOCKER_DIR, build_root)?;
         }
 
+        if let Some(description) = self.maybe_describe() {
+            docker = docker.describe(description);
+        }
+
+        for (internal, external) in apps.defaults().extra_mounts(self.maybe_architecture()) {
+            docker = docker.mount(internal, external)?;
+        }
+
         Ok(docker)
     }
 
-    fn easy_settings(&self) -> Result<NamedMap<Flag>> {
+    /// Flags defined by the easy settings in the source directory of the project
+    ///
+    /// There are no flags if the source directory can not be found.
+    fn easy_settings(&self, config: &Config) -> Result<NamedMap<Flag>> {
         let mut flags = NamedMap::default();
 
         // Regex to match a setting
@@ -73,8 +104,15 @@ pub trait Context {
             "^set\\((?P<variable>[A-Za-z][A-Za-z0-9_]*)( [^ ]+){2} (?P<type>[A-Z]+) \"(?P<description>[^\"]*)\"\\)$",
         )?;
 
-        let mut easy_settings = self.workspace_root().to_owned();
-        easy_settings.push(Workspace::EASY_SETTINGS);
+        let project = config.project(self.project());
+        let source = match project.source_dir(self, config.defaults()) {
+            Ok(source) => source,
+            Err(_) => return Ok(flags),
+        };
+        let easy_settings = self
+            .workspace_root()
+            .join(source)
+            .join(Workspace::EASY_SETTINGS);
 
         // No flags if no file
         if !easy_settings.is_file() {
@@ -115,8 +153,9 @@ pub trait Context {
                         })
                         .collect()
                 };
+                // CMake has no numeric cache type, so other types are treated as text
                 let type_ = match &matches["type"] {
-                    "STRING" => Some(Type::Text),
+                    "STRING" | "FILEPATH" | "PATH" => Some(Type::Text),
                     "BOOL" => Some(Type::Boolean),
                     _ => None,
                 };
@@ -131,65 +170,164 @@ pub trait Context {
         Ok(flags)
     }
 
-    /// Infer the path to the source directory
+    /// Infer the path to the source directory from the easy settings hint in the workspace root
     fn inferred_source(&self) -> Result<PathBuf> {
+        self.search_source(&[])
+    }
+
+    /// Infer the path to the source directory from the easy settings hints in the workspace
+    ///
+    /// The workspace root and each of the given subdirectories are searched for the hint. A
+    /// subdirectory ending in `*` searches each of the directories it contains. It is an error if
+    /// hints are found in more than one source directory.
+    fn search_source(&self, subdirectories: &[PathBuf]) -> Result<PathBuf> {
         let workspace_root = self.workspace_root().canonicalize()?;
-        let mut hint_path = workspace_root.clone();
-        hint_path.push(Workspace::EASY_SETTINGS);
 
-        if hint_path.exists() {
-            hint_path = hint_path.canonicalize()?;
-            hint_path.pop();
-            relative_path(workspace_root, hint_path)
-        } else {
-            bail!("Could not infer source directory");
+        let mut directories = vec![workspace_root.clone()];
+        for subdirectory in subdirectories {
+            let path = workspace_root.join(subdirectory);
+            if subdirectory.file_name().is_some_and(|name| name == "*") {
+                let parent = path.parent().unwrap_or(&workspace_root);
+                if parent.is_dir() {
+                    let mut children = read_dir(parent)?
+                        .map(|entry| entry.map(|entry| entry.path()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    children.sort();
+                    directories.extend(children.into_iter().filter(|child| child.is_dir()));
+                }
+            } else {
+                directories.push(path);
+            }
+        }
+
+        let mut sources = Vec::new();
+        for directory in directories {
+            let hint_path = directory.join(Workspace::EASY_SETTINGS);
+            if hint_path.exists() {
+                let mut source = hint_path.canonicalize()?;
+                source.pop();
+                let source = relative_path(&workspace_root, source)?;
+                if !sources.contains(&source) {
+                    sources.push(source);
+                }
+            }
+        }
+
+        match sources.len() {
+            0 => bail!("Could not infer source directory"),
+            1 => Ok(sources.remove(0)),
+            _ => bail!(
+                "Found several source directories ({}); set source-directory for the project",
+                sources
+                    .iter()
+                    .map(|source| source.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
         }
     }
 }
 
-pub fn find_context() -> Result<Option<Box<dyn Context>>> {
-    let mut path = current_dir()?;
+/// Find the build or workspace containing the current directory
+///
+/// Any deprecated flags in a discovered build are renamed using the configuration. If
+/// `S4_DISCOVERY_CACHE` names a file, discovered directories are cached in it.
+pub fn find_context(config: &Config) -> Result<Option<Box<dyn Context>>> {
+    let cache = var_os(WorkspaceContext::DISCOVERY_CACHE_ENV_VAR);
+    context_at(&current_dir()?, cache.as_deref().map(Path::new), config)
+}
 
-    while path.parent().is_some() {
-        path.push(Build::FILENAME);
-        if path.exists() {
-            let build: Build = toml_load(&path)?;
-            path.pop();
-            let mut workspace_root = build.workspace_root.clone();
-            workspace_root.push(Workspace::FILENAME);
-            let workspace: Workspace = toml_load(&workspace_root)?;
-            let build_root = path;
-            let mut workspace_root = build_root.clone();
-            workspace_root.push(&build.workspace_root);
-            let workspace = WorkspaceContext {
-                workspace_root,
-                workspace,
-            };
-            let context = Box::new(BuildContext {
-                workspace,
-                build,
-                build_root,
-            });
-            return Ok(Some(context));
-        } else {
-            path.pop();
-            path.push(Workspace::FILENAME);
-            if path.exists() {
-                let workspace: Workspace = toml_load(&path)?;
-                path.pop();
-                let workspace_root = path;
-                let context = Box::new(WorkspaceContext {
-                    workspace,
-                    workspace_root,
-                });
-                return Ok(Some(context));
-            } else {
-                path.pop();
+/// Find the build or workspace containing a directory
+fn context_at(
+    start: &Path,
+    cache: Option<&Path>,
+    config: &Config,
+) -> Result<Option<Box<dyn Context>>> {
+    let mut path = match discover_context_root(start, cache) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    path.push(Build::FILENAME);
+    if path.exists() {
+        let build: Build = toml_load(&path)?;
+        path.pop();
+        let build_root = path;
+        let mut workspace_root = build_root.clone();
+        workspace_root.push(&build.workspace_root);
+        workspace_root.push(Workspace::FILENAME);
+        let workspace: Workspace = toml_load(&workspace_root)?;
+        workspace_root.pop();
+        let workspace = WorkspaceContext {
+            workspace_root,
+            workspace,
+        };
+        let mut context = BuildContext {
+            workspace,
+            build,
+            build_root,
+        };
+        context.migrate(config)?;
+        Ok(Some(Box::new(context)))
+    } else {
+        path.pop();
+        path.push(Workspace::FILENAME);
+        let workspace: Workspace = toml_load(&path)?;
+        path.pop();
+        let workspace_root = path;
+        let context = Box::new(WorkspaceContext {
+            workspace,
+            workspace_root,
+        });
+        Ok(Some(context))
+    }
+}
+
+/// Find the closest build or workspace directory containing a path
+///
+/// If a cache file is given, the directory discovered from each starting directory is recorded
+/// in it. While searching upwards, a directory with a cached result reuses that result instead of
+/// searching further, as long as the cached directory is still a build or workspace directory.
+/// Starting directories outside of a cached directory never use it. Errors reading or writing the
+/// cache are ignored.
+fn discover_context_root(start: &Path, cache: Option<&Path>) -> Option<PathBuf> {
+    fn is_context_root(path: &Path) -> bool {
+        path.join(Build::FILENAME).exists() || path.join(Workspace::FILENAME).exists()
+    }
+
+    let mut cached: DiscoveryCache = cache
+        .and_then(|cache| toml_load(cache).ok())
+        .unwrap_or_default();
+
+    let root = start
+        .ancestors()
+        .filter(|path| path.parent().is_some())
+        .find_map(|path| {
+            if is_context_root(path) {
+                return Some(path.to_owned());
             }
+            cached
+                .roots
+                .get(path)
+                .filter(|root| path.starts_with(root) && is_context_root(root))
+                .cloned()
+        })?;
+
+    if let Some(cache) = cache {
+        if cached.roots.get(start) != Some(&root) {
+            cached.roots.insert(start.to_owned(), root.clone());
+            let _ = toml_save(&cached, cache);
         }
     }
 
-    Ok(None)
+    Some(root)
+}
+
+/// Build or workspace directories discovered from each starting directory
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct DiscoveryCache {
+    #[serde(default)]
+    roots: BTreeMap<PathBuf, PathBuf>,
 }
 
 /// Working context
@@ -214,46 +352,96 @@ impl Context for WorkspaceContext {
 }
 
 /// Directory within the root of a workspace used to cache artifacts
-pub const CACHE_SUBDIR: &'static str = ".sel4_cache";
+pub const CACHE_SUBDIR: &str = ".sel4_cache";
 
 impl WorkspaceContext {
+    /// Environment variable naming a file used to cache discovered workspace and build directories
+    pub const DISCOVERY_CACHE_ENV_VAR: &'static str = "S4_DISCOVERY_CACHE";
+
     /// Create a new workspace directory
-    pub fn create(project: ProjectId, path: impl AsRef<Path>) -> Result<Self> {
+    ///
+    /// If `force` is set, the workspace will be created in an existing directory even if it is not
+    /// empty. If `gitignore` is set, the cache and build directories are added to the
+    /// `.gitignore` of the workspace (see `update_gitignore`).
+    pub fn create(
+        project: ProjectId,
+        path: impl AsRef<Path>,
+        force: bool,
+        gitignore: bool,
+    ) -> Result<Self> {
         let workspace = Workspace {
             project,
             builds: BTreeSet::new(),
         };
 
         let mut workspace_root = path.as_ref().to_owned();
-        if workspace_root.is_dir() && !read_dir(&workspace_root)?.count() != 0 {
-            bail!(
-                "Workspace directory {} is not empty",
-                workspace_root.display()
-            );
+        if workspace_root.is_dir() {
+            if !force && read_dir(&workspace_root)?.count() != 0 {
+                bail!(
+                    "Workspace directory {} is not empty",
+                    workspace_root.display()
+                );
+            }
         } else if workspace_root.exists() {
             bail!(
                 "Workspace directory path {} already exists",
                 workspace_root.display()
             );
-        } else {
-            create_dir_all(&workspace_root)?;
         }
 
-        // Create a cache directory for the workspace
-        workspace_root.push(CACHE_SUBDIR);
-        create_dir_all(&workspace_root)?;
-        workspace_root.pop();
+        with_new_dir(path, || {
+            // Create a cache directory for the workspace
+            workspace_root.push(CACHE_SUBDIR);
+            create_dir_all(&workspace_root)?;
+            workspace_root.pop();
 
-        workspace_root.push(Workspace::FILENAME);
-        toml_save(&workspace, &workspace_root)?;
-        workspace_root.pop();
+            workspace_root.push(Workspace::FILENAME);
+            toml_save(&workspace, &workspace_root)?;
+            workspace_root.pop();
 
-        Ok(WorkspaceContext {
-            workspace,
-            workspace_root,
+            let context = WorkspaceContext {
+                workspace,
+                workspace_root,
+            };
+            if gitignore {
+                context.update_gitignore()?;
+            }
+            Ok(context)
         })
     }
 
+    /// Add the cache and build directories to the `.gitignore` of the workspace
+    ///
+    /// Any existing `.gitignore` is preserved and only the entries that are missing from it are
+    /// appended.
+    pub fn update_gitignore(&self) -> Result<()> {
+        let path = self.workspace_root.join(Workspace::GITIGNORE);
+        let mut contents = match read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        let existing = contents.lines().map(str::trim).collect::<BTreeSet<_>>();
+        let missing = Workspace::GITIGNORE_ENTRIES
+            .iter()
+            .filter(|entry| !existing.contains(*entry))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        for entry in missing {
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        write(&path, contents)?;
+        Ok(())
+    }
+
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let mut workspace_root = path.as_ref().to_owned();
         workspace_root.push(Workspace::FILENAME);
@@ -266,21 +454,385 @@ impl WorkspaceContext {
         })
     }
 
+    /// Check whether repo has completely synced the workspace
+    ///
+    /// A workspace is synced if repo has been initialised with a manifest and the easy settings
+    /// hint (if present) links to an existing source directory.
+    pub fn is_synced(&self) -> bool {
+        let repo = self.workspace_root.join(Workspace::REPO_DIR);
+        let easy_settings = self.workspace_root.join(Workspace::EASY_SETTINGS);
+
+        repo.join("manifest.xml").exists()
+            && (easy_settings.exists() || easy_settings.symlink_metadata().is_err())
+    }
+
+    /// Directories of the projects checked out by repo, relative to the workspace
+    ///
+    /// There are none if repo has not synced the workspace.
+    pub fn repo_projects(&self) -> Result<Vec<PathBuf>> {
+        let list = self
+            .workspace_root
+            .join(Workspace::REPO_DIR)
+            .join(Workspace::PROJECT_LIST);
+        if !list.is_file() {
+            return Ok(Vec::new());
+        }
+        Ok(read_to_string(list)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    /// Ensure that the workspace has been completely synced
+    pub fn check_synced(&self) -> Result<()> {
+        if !self.is_synced() {
+            bail!("Workspace not fully synced; run s4 sync");
+        }
+        Ok(())
+    }
+
+    /// Version of the seL4 kernel in the workspace sources
+    ///
+    /// This is `None` if the kernel sources are not present (such as before the workspace has been
+    /// synced).
+    pub fn sel4_version(&self) -> Result<Option<String>> {
+        let path = self.workspace_root.join(Workspace::KERNEL_VERSION);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let version = read_to_string(&path)?;
+        match version.lines().map(str::trim).find(|line| !line.is_empty()) {
+            Some(version) => Ok(Some(version.to_owned())),
+            None => bail!("{} is empty", path.display()),
+        }
+    }
+
+    /// Export the definition of the workspace and its builds without any build artifacts
+    pub fn export(&self) -> Result<WorkspaceExport> {
+        let builds = self
+            .builds()
+            .map(|build| {
+                let build = build?;
+                Ok(BuildExport {
+                    path: relative_path(&self.workspace_root, &build.build_root)?,
+                    platform: build.build.platform,
+                    variation: build.build.variation,
+                    architecture: build.build.architecture,
+                    source_revision: build.build.source_revision,
+                    image_digest: build.build.image_digest,
+                    tags: build.build.tags,
+                    setting: build.build.setting,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(WorkspaceExport {
+            project: self.workspace.project.clone(),
+            builds,
+        })
+    }
+
+    /// Create a workspace and its builds from an exported definition
+    ///
+    /// The definition is checked against the configuration before anything is created. The
+    /// workspace sources are not initialised and the builds are not configured.
+    pub fn import(
+        config: &Config,
+        export: &WorkspaceExport,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        export.validate(config)?;
+        let mut context = Self::create(export.project.clone(), path, false, false)?;
+
+        for exported in &export.builds {
+            let build_root = context.workspace_root.join(&exported.path);
+            create_dir_all(&build_root)?;
+
+            let mut build = Build::new(
+                relative_path(&build_root, &context.workspace_root)?,
+                exported.platform.clone(),
+                exported.variation.clone(),
+                exported.architecture,
+                exported.setting.clone(),
+            );
+            build.source_revision = exported.source_revision.clone();
+            build.image_digest = exported.image_digest.clone();
+            build.tags = exported.tags.clone();
+            toml_save(&build, build_root.join(Build::FILENAME))?;
+
+            context.workspace.builds.insert(exported.path.clone());
+        }
+
+        toml_save(
+            &context.workspace,
+            context.workspace_root.join(Workspace::FILENAME),
+        )?;
+        Ok(context)
+    }
+
+    /// Re-sync the workspace sources with repo without re-initialising the workspace
+    pub fn sync(&self, apps: &Apps) -> Result<()> {
+        apps.check_free_space(&self.workspace_root)?;
+        if !apps.status(&mut self.sync_command(apps))?.success() {
+            bail!("Failed to sync workspace");
+        }
+        Ok(())
+    }
+
+    fn sync_command(&self, apps: &Apps) -> Command {
+        let mut command = apps.repo_sync();
+        command.current_dir(&self.workspace_root);
+        command
+    }
+
     /// Get all of the build contexts for a given workspace
     pub fn builds<'w>(&'w self) -> impl Iterator<Item = Result<BuildContext>> + 'w {
-        self.workspace.builds.iter().flat_map(move |build| {
-            let mut path = self.workspace_root.clone();
-            path.push(build);
-            // Skip non-existing builds
-            let build = if path.exists() {
-                Some(self.load_build(path))
+        // Skip non-existing builds
+        self.registered_builds()
+            .filter_map(|build| build.map(|(_, build)| build).transpose())
+    }
+
+    /// All builds registered with the workspace, sorted by path
+    ///
+    /// Unlike `builds`, builds whose directory no longer exists are included with no context.
+    pub fn registered_builds<'w>(
+        &'w self,
+    ) -> impl Iterator<Item = Result<(PathBuf, Option<BuildContext>)>> + 'w {
+        self.workspace.builds.iter().map(move |build| {
+            let path = self.workspace_root.join(build);
+            if path.exists() {
+                let build = self.load_build(&path)?;
+                Ok((path, Some(build)))
             } else {
-                None
-            };
-            build.into_iter()
+                Ok((path, None))
+            }
+        })
+    }
+
+    /// Find existing builds that are configured equivalently to a given build
+    ///
+    /// Builds are equivalent if they have the same platform, variation, and architecture and their
+    /// settings are semantically equal.
+    pub fn find_equivalent(&self, build: &Build) -> Vec<PathBuf> {
+        self.builds()
+            .filter_map(Result::ok)
+            .filter(|existing| {
+                existing.build.platform == build.platform
+                    && existing.build.variation == build.variation
+                    && existing.build.architecture == build.architecture
+                    && existing.build.setting.semantically_eq(&build.setting)
+            })
+            .map(|existing| existing.build_root)
+            .collect()
+    }
+
+    /// Load all of the build contexts for a given workspace concurrently
+    ///
+    /// Builds are returned in the same order as [`builds`](Self::builds), with at most
+    /// `PARALLEL_LOADS` builds loaded at once.
+    pub fn builds_parallel(&self) -> Result<Vec<BuildContext>> {
+        let paths = self
+            .workspace
+            .builds
+            .iter()
+            .map(|build| self.workspace_root.join(build))
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let chunk_size = paths.len().div_ceil(Self::PARALLEL_LOADS);
+        thread::scope(|scope| {
+            let loaders = paths
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|path| self.load_build(path))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let mut builds = Vec::with_capacity(paths.len());
+            for loader in loaders {
+                let loaded = loader
+                    .join()
+                    .map_err(|_| format_err!("Thread loading build directories panicked"))?;
+                builds.extend(loaded?);
+            }
+            Ok(builds)
+        })
+    }
+
+    /// Create a build in a fresh temporary directory that is not added to the workspace
+    ///
+    /// The build directory is removed when the returned guard is dropped. Each ephemeral build
+    /// gets its own directory, so several can be used at once.
+    pub fn ephemeral_build(
+        &self,
+        config: &Config,
+        platform: PlatformId,
+        variation: Option<VariationId>,
+        architecture: Sel4Architecture,
+        setting: Setting,
+    ) -> Result<EphemeralBuild> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let build_root = loop {
+            let path = temp_dir().join(format!(
+                "s4-build-{}-{}",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match create_dir(&path) {
+                Ok(()) => break path,
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error.into()),
+            }
+        };
+
+        BuildContext::create_in(
+            config,
+            self,
+            platform,
+            variation,
+            architecture,
+            setting,
+            build_root.clone(),
+            false,
+        )
+        .map(|context| EphemeralBuild { context })
+        .inspect_err(|_| {
+            let _ = remove_dir_all(&build_root);
+        })
+    }
+
+    /// Create a build for every combination of platform, architecture, and preset in a matrix
+    ///
+    /// Combinations of a platform with an architecture it does not support are skipped with a
+    /// warning. Each build is created in the workspace directory in a directory named after its
+    /// description (see `BuildContext::describe`), and a build that already exists there is
+    /// reused, so a matrix can be created repeatedly.
+    pub fn create_matrix(&self, config: &Config, spec: &MatrixSpec) -> Result<Vec<BuildContext>> {
+        let presets = if spec.presets.is_empty() {
+            vec![None]
+        } else {
+            spec.presets.iter().map(Some).collect()
+        };
+
+        let mut workspace = self.clone();
+        let mut builds = Vec::new();
+        for choice in &spec.platforms {
+            let platform = config.find_platform(choice.platform())?;
+            for &architecture in &spec.architectures {
+                if let Err(error) = Platform::check_architecture(&platform, architecture) {
+                    eprintln!("Warning: {}; skipping {}", error, choice);
+                    continue;
+                }
+
+                for preset in &presets {
+                    let added_setting = match preset {
+                        Some(preset) => config.find_preset(preset)?.clone(),
+                        None => Setting::default(),
+                    };
+                    let setting = BuildContext::build_setting(
+                        config,
+                        workspace.project(),
+                        choice.platform(),
+                        choice.variation(),
+                        architecture,
+                        added_setting.clone(),
+                    )?;
+                    let description = BuildContext::description(
+                        workspace.project(),
+                        choice.platform(),
+                        choice.variation(),
+                        architecture,
+                        &setting,
+                    );
+                    let build_root = workspace
+                        .workspace_root
+                        .join(MatrixSpec::directory_name(&description));
+
+                    let build = if build_root.join(Build::FILENAME).is_file() {
+                        workspace.load_build(&build_root)?
+                    } else {
+                        BuildContext::create(
+                            config,
+                            &workspace,
+                            choice.platform().clone(),
+                            choice.variation().cloned(),
+                            architecture,
+                            added_setting,
+                            build_root,
+                            false,
+                        )?
+                    };
+                    workspace = build.workspace().clone();
+                    builds.push(build);
+                }
+            }
+        }
+        Ok(builds)
+    }
+
+    /// Builds with a given tag
+    pub fn tagged_builds<'w>(
+        &'w self,
+        tag: &'w str,
+    ) -> impl Iterator<Item = Result<BuildContext>> + 'w {
+        self.builds().filter(move |build| match build {
+            Ok(build) => build.has_tag(tag),
+            Err(_) => true,
         })
     }
 
+    /// Remove a build from the workspace and delete its build directory
+    pub fn remove_build(&mut self, build: &BuildContext) -> Result<()> {
+        let path = relative_path(&self.workspace_root, &build.build_root)?;
+        if !self.workspace.builds.remove(&path) {
+            bail!("{} is not a build of this workspace", path.display());
+        }
+        toml_save(
+            &self.workspace,
+            self.workspace_root.join(Workspace::FILENAME),
+        )?;
+        remove_dir_all(&build.build_root)?;
+        Ok(())
+    }
+
+    /// Remove every build with a given tag, returning the removed build directories
+    pub fn remove_tagged(&mut self, tag: &str) -> Result<Vec<PathBuf>> {
+        let builds = self.tagged_builds(tag).collect::<Result<Vec<_>>>()?;
+        let mut removed = Vec::new();
+        for build in builds {
+            self.remove_build(&build)?;
+            removed.push(build.build_root);
+        }
+        Ok(removed)
+    }
+
+    /// Builds whose sources have changed since they were last built
+    pub fn stale_builds(&self, config: &Config) -> Result<Vec<BuildContext>> {
+        let mut stale = Vec::new();
+        for build in self.builds() {
+            let build = build?;
+            if build.is_stale(config)? {
+                stale.push(build);
+            }
+        }
+        Ok(stale)
+    }
+
+    /// Maximum number of threads used to load builds concurrently
+    const PARALLEL_LOADS: usize = 8;
+
     /// Load an existing build directory
     fn load_build(&self, path: impl AsRef<Path>) -> Result<BuildContext> {
         BuildContext::load(self, path)
@@ -294,6 +846,32 @@ pub struct BuildContext {
     build_root: PathBuf,
 }
 
+/// A build in a temporary directory that is removed when dropped
+#[derive(Debug)]
+pub struct EphemeralBuild {
+    context: BuildContext,
+}
+
+impl Deref for EphemeralBuild {
+    type Target = BuildContext;
+
+    fn deref(&self) -> &BuildContext {
+        &self.context
+    }
+}
+
+impl DerefMut for EphemeralBuild {
+    fn deref_mut(&mut self) -> &mut BuildContext {
+        &mut self.context
+    }
+}
+
+impl Drop for EphemeralBuild {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.context.build_root);
+    }
+}
+
 impl Context for BuildContext {
     fn workspace_root(&self) -> &Path {
         self.workspace.workspace_root()
@@ -303,6 +881,14 @@ impl Context for BuildContext {
         Some(&self.build_root)
     }
 
+    fn maybe_architecture(&self) -> Option<Sel4Architecture> {
+        Some(self.architecture())
+    }
+
+    fn maybe_describe(&self) -> Option<String> {
+        Some(self.describe())
+    }
+
     fn project(&self) -> &ProjectId {
         self.workspace.project()
     }
@@ -314,6 +900,10 @@ impl Context for BuildContext {
 
 impl BuildContext {
     /// Create a new build directory for a workspace
+    ///
+    /// If `force` is set, the build will be created in an existing directory even if it is not
+    /// empty.
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         config: &Config,
         workspace: &WorkspaceContext,
@@ -322,33 +912,103 @@ impl BuildContext {
         architecture: Sel4Architecture,
         added_setting: Setting,
         path: impl AsRef<Path>,
+        force: bool,
     ) -> Result<Self> {
-        let WorkspaceContext {
-            mut workspace,
-            mut workspace_root,
-            ..
-        } = workspace.clone();
-
-        let mut build_root = path.as_ref().to_owned();
-        if build_root.is_dir() && !read_dir(&build_root)?.count() != 0 {
-            bail!("Build directory {} is not empty", build_root.display());
+        let build_root = path.as_ref().to_owned();
+        if build_root.is_dir() {
+            if !force && read_dir(&build_root)?.count() != 0 {
+                bail!("Build directory {} is not empty", build_root.display());
+            }
         } else if build_root.exists() {
             bail!(
                 "Build directory path {} already exists",
                 build_root.display()
             );
-        } else {
-            create_dir_all(&build_root)?;
         }
 
-        // Construct all settings
-        let mut setting = config.platform_setting(
-            &workspace.project,
+        with_new_dir(&build_root, || {
+            Self::create_in(
+                config,
+                workspace,
+                platform,
+                variation,
+                architecture,
+                added_setting,
+                build_root.clone(),
+                true,
+            )
+        })
+    }
+
+    /// Create a new build in an existing directory
+    ///
+    /// If `register` is not set, the build is not added to the builds of the workspace.
+    #[allow(clippy::too_many_arguments)]
+    fn create_in(
+        config: &Config,
+        context: &WorkspaceContext,
+        platform: PlatformId,
+        variation: Option<VariationId>,
+        architecture: Sel4Architecture,
+        added_setting: Setting,
+        build_root: PathBuf,
+        register: bool,
+    ) -> Result<Self> {
+        let setting = Self::build_setting(
+            config,
+            context.project(),
             &platform,
             variation.as_ref(),
             architecture,
+            added_setting,
         )?;
+
+        Self::new_in(
+            context,
+            platform,
+            variation,
+            architecture,
+            setting,
+            build_root,
+            register,
+        )
+    }
+
+    /// Construct the full setting of a new build from the setting added by the user
+    fn build_setting(
+        config: &Config,
+        project: &ProjectId,
+        platform: &PlatformId,
+        variation: Option<&VariationId>,
+        architecture: Sel4Architecture,
+        added_setting: Setting,
+    ) -> Result<Setting> {
+        config.check_overrides(project, &added_setting)?;
+
+        let mut setting = config.platform_setting(project, platform, variation, architecture)?;
         setting.merge(added_setting);
+        for (old, new) in config.migrate_setting(&mut setting) {
+            eprintln!("Warning: flag {} has been renamed to {}", old, new);
+        }
+        Ok(setting)
+    }
+
+    /// Write a new build file to an existing directory
+    ///
+    /// If `register` is not set, the build is not added to the builds of the workspace.
+    fn new_in(
+        context: &WorkspaceContext,
+        platform: PlatformId,
+        variation: Option<VariationId>,
+        architecture: Sel4Architecture,
+        setting: Setting,
+        mut build_root: PathBuf,
+        register: bool,
+    ) -> Result<Self> {
+        let WorkspaceContext {
+            mut workspace,
+            mut workspace_root,
+        } = context.clone();
 
         // Get relative path to workspace root
         let build = Build::new(
@@ -358,17 +1018,30 @@ impl BuildContext {
             architecture,
             setting,
         );
-        workspace
-            .builds
-            .insert(relative_path(&workspace_root, &build_root)?);
 
         build_root.push(Build::FILENAME);
         toml_save(&build, &build_root)?;
         build_root.pop();
 
-        workspace_root.push(Workspace::FILENAME);
-        toml_save(&workspace, &workspace_root)?;
-        workspace_root.pop();
+        if register {
+            let canonical_root = build_root.canonicalize()?;
+            for equivalent in context.find_equivalent(&build) {
+                if equivalent.canonicalize().ok().as_ref() != Some(&canonical_root) {
+                    eprintln!(
+                        "Warning: an equivalent build already exists at {}",
+                        equivalent.display()
+                    );
+                }
+            }
+
+            workspace
+                .builds
+                .insert(relative_path(&workspace_root, &build_root)?);
+
+            workspace_root.push(Workspace::FILENAME);
+            toml_save(&workspace, &workspace_root)?;
+            workspace_root.pop();
+        }
 
         let workspace = WorkspaceContext {
             workspace,
@@ -382,10 +1055,78 @@ impl BuildContext {
         })
     }
 
-    /// Load an existing build directory with a given workspace
-    pub fn load(workspace: &WorkspaceContext, path: impl AsRef<Path>) -> Result<Self> {
-        let workspace = workspace.clone();
-        let mut build_root = path.as_ref().to_owned();
+    /// Adopt an existing build directory that was configured without s4
+    ///
+    /// The platform, variation, and architecture are read from the `CMakeCache.txt` of the build
+    /// directory and the build is added to the builds of the workspace. They must name a known
+    /// platform, variation, and architecture, and the closest known platform is suggested if not.
+    pub fn adopt(
+        config: &Config,
+        workspace: &WorkspaceContext,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let build_root = path.as_ref().to_owned();
+        if build_root.join(Build::FILENAME).exists() {
+            bail!("{} is already a build directory", build_root.display());
+        }
+
+        let cache_path = build_root.join(Build::CMAKE_CACHE);
+        if !cache_path.is_file() {
+            bail!("No CMake cache in {}", build_root.display());
+        }
+        let cache = cmake_cache_entries(&cache_path)?;
+        let entry = |variable: &str| cache.get(variable).filter(|value| !value.is_empty());
+
+        let architecture = entry(Build::CACHE_ARCH_VARIABLE)
+            .ok_or_else(|| {
+                format_err!(
+                    "CMake cache does not record the architecture ({})",
+                    Build::CACHE_ARCH_VARIABLE
+                )
+            })?
+            .parse()?;
+        let cmake_platform = entry(Build::CACHE_PLATFORM_VARIABLE);
+        let kernel_platform = entry(Build::CACHE_KERNEL_PLATFORM_VARIABLE)
+            .or(cmake_platform)
+            .ok_or_else(|| {
+                format_err!(
+                    "CMake cache does not record the platform ({} or {})",
+                    Build::CACHE_KERNEL_PLATFORM_VARIABLE,
+                    Build::CACHE_PLATFORM_VARIABLE
+                )
+            })?;
+
+        // Variations are configured by setting the platform to the name of the variation
+        let variation = cmake_platform
+            .filter(|platform| *platform != kernel_platform)
+            .map(|platform| VariationId::from(platform.as_str()));
+        let platform = PlatformId::from(kernel_platform.as_str());
+        config.platform_setting(
+            workspace.project(),
+            &platform,
+            variation.as_ref(),
+            architecture,
+        )?;
+
+        let mut setting = Setting::default();
+        setting.set_kernel_platform(kernel_platform);
+        setting.set_platform(cmake_platform.unwrap_or(kernel_platform));
+
+        Self::new_in(
+            workspace,
+            platform,
+            variation,
+            architecture,
+            setting,
+            build_root,
+            true,
+        )
+    }
+
+    /// Load an existing build directory with a given workspace
+    pub fn load(workspace: &WorkspaceContext, path: impl AsRef<Path>) -> Result<Self> {
+        let workspace = workspace.clone();
+        let mut build_root = path.as_ref().to_owned();
 
         build_root.push(Build::FILENAME);
         let build = toml_load(&build_root)?;
@@ -398,6 +1139,142 @@ impl BuildContext {
         })
     }
 
+    /// Consume the build context, keeping only the context of its workspace
+    pub fn into_workspace(self) -> WorkspaceContext {
+        self.workspace
+    }
+
+    /// Rename any deprecated flags in the build setting and save the result
+    pub fn migrate(&mut self, config: &Config) -> Result<()> {
+        let renamed = config.migrate_setting(&mut self.build.setting);
+        if !renamed.is_empty() {
+            self.save()?;
+        }
+        for (old, new) in renamed {
+            eprintln!(
+                "Warning: flag {} has been renamed to {}; updated {}",
+                old,
+                new,
+                self.build_root.join(Build::FILENAME).display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Hash of the setting as it would be used to configure the build
+    pub fn setting_hash(&self) -> Result<String> {
+        toml_hash(&self.configure_setting()?)
+    }
+
+    /// The setting used to configure the build
+    ///
+    /// This is the stored setting with any local overrides from the build directory merged on top.
+    /// The local overrides are never written back to the managed build file.
+    pub fn configure_setting(&self) -> Result<Setting> {
+        let mut setting = self.build.setting.clone();
+        setting.merge(self.local_setting()?);
+        Ok(setting)
+    }
+
+    /// The local overrides from the build directory (empty if there are none)
+    fn local_setting(&self) -> Result<Setting> {
+        let local = self.build_root.join(Build::LOCAL_FILENAME);
+        if local.is_file() {
+            let local: LocalBuild = toml_load(local)?;
+            Ok(local.setting)
+        } else {
+            Ok(Setting::default())
+        }
+    }
+
+    /// Ensure that the local overrides do not override any locked flags
+    pub fn check_local_overrides(&self, config: &Config) -> Result<()> {
+        config
+            .check_overrides(self.project(), &self.local_setting()?)
+            .map_err(|error| format_err!("{}: {}", Build::LOCAL_FILENAME, error))
+    }
+
+    /// Check if the setting has changed since the build directory was last configured
+    pub fn needs_reconfigure(&self) -> Result<bool> {
+        let path = self.build_root.join(Build::CONFIGURED_FILENAME);
+        if !path.is_file() {
+            return Ok(true);
+        }
+        Ok(read_to_string(path)?.trim() != self.setting_hash()?)
+    }
+
+    /// Record that the build directory has been configured with the current setting
+    pub fn mark_configured(&self) -> Result<()> {
+        write(
+            self.build_root.join(Build::CONFIGURED_FILENAME),
+            self.setting_hash()?,
+        )?;
+        Ok(())
+    }
+
+    /// Remove the CMake cache so that the next configure starts from scratch
+    ///
+    /// Build outputs are kept so that unchanged targets need not be rebuilt.
+    pub fn clear_cmake_cache(&self) -> Result<()> {
+        for file in &[Build::CMAKE_CACHE, Build::CONFIGURED_FILENAME] {
+            match remove_file(self.build_root.join(file)) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+        let cmake_files = self.build_root.join(Build::CMAKE_FILES);
+        if cmake_files.is_dir() {
+            remove_dir_all(cmake_files)?;
+        }
+        Ok(())
+    }
+
+    /// Find the most recently modified run log of the build
+    pub fn latest_run_log(&self) -> Result<PathBuf> {
+        let directory = self.build_root.join(Build::RUN_LOG_DIR);
+        let mut logs = Vec::new();
+        if directory.is_dir() {
+            for entry in read_dir(&directory)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    logs.push((metadata.modified()?, entry.path()));
+                }
+            }
+        }
+
+        logs.into_iter()
+            .max()
+            .map(|(_, path)| path)
+            .ok_or_else(|| format_err!("No run logs found in {}", directory.display()))
+    }
+
+    /// Path of a new log to capture the serial output of a run of the build
+    pub fn new_run_log(&self) -> Result<PathBuf> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        Ok(self
+            .build_root
+            .join(Build::RUN_LOG_DIR)
+            .join(format!("{}.log", now.as_secs())))
+    }
+
+    /// Open the most recent serial log captured when the build was run
+    pub fn open_serial_log(&self) -> Result<(PathBuf, File)> {
+        let path = self.latest_run_log()?;
+        let file = File::open(&path)
+            .map_err(|e| format_err!("Could not open {}: {}", path.display(), e))?;
+        Ok((path, file))
+    }
+
+    /// Path of the seL4 artifact cache as mounted in docker
+    pub fn docker_cache_dir(&self, mode: CacheMode) -> PathBuf {
+        let root = match mode {
+            CacheMode::Shared => Project::WORKSPACE_DOCKER_DIR,
+            CacheMode::Isolated => Project::BUILD_DOCKER_DIR,
+        };
+        Path::new(root).join(CACHE_SUBDIR)
+    }
+
     pub fn build_root(&self) -> &Path {
         &self.build_root
     }
@@ -410,6 +1287,137 @@ impl BuildContext {
         Ok(command)
     }
 
+    /// Run ninja without a TTY and capture its output
+    fn ninja_output(&self, apps: &Apps, args: &[&str]) -> Result<String> {
+        let mut command = self
+            .docker(apps)?
+            .keep_on_failure(false)
+            .tty(false)
+            .work_dir(Project::BUILD_DOCKER_DIR)?
+            .run("ninja");
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+
+        let output = command.output()?;
+        if !output.status.success() {
+            bail!("ninja {} failed", args.join(" "));
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Targets that ninja can build in the build directory
+    pub fn ninja_targets(&self, apps: &Apps) -> Result<Vec<String>> {
+        Ok(parse_ninja_targets(
+            &self.ninja_output(apps, &["-t", "targets"])?,
+        ))
+    }
+
+    /// Check if ninja has nothing to build
+    pub fn is_up_to_date(&self, apps: &Apps) -> Result<bool> {
+        Ok(ninja_has_no_work(&self.ninja_output(apps, &["-n"])?))
+    }
+
+    /// Build the build directory with ninja
+    ///
+    /// If a source revision is recorded for the build, it is checked out first.
+    pub fn build(&mut self, apps: &Apps, config: &Config) -> Result<()> {
+        self.checkout_source(apps, config)?;
+        self.check_image_digest(apps)?;
+        apps.check_free_space(&self.build_root)?;
+        apps.run_stage(Stage::Build, &mut self.ninja(apps)?)?;
+        if apps.is_dry_run() {
+            return Ok(());
+        }
+        self.mark_built()
+    }
+
+    /// Record that the build directory was successfully built now
+    pub fn mark_built(&mut self) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        self.build.last_built = Some(now.as_secs());
+        self.save()
+    }
+
+    /// Digest of the docker image the build was created with (if known)
+    pub fn image_digest(&self) -> Option<&str> {
+        self.build.image_digest.as_deref()
+    }
+
+    /// Record the digest of the docker image the first time it is known, and warn if it changes
+    ///
+    /// Nothing is recorded or checked in a dry run.
+    pub fn check_image_digest(&mut self, apps: &Apps) -> Result<()> {
+        if apps.is_dry_run() {
+            return Ok(());
+        }
+        let digest = match apps.resolve_image_digest()? {
+            Some(digest) => digest,
+            None => return Ok(()),
+        };
+        if let Some(recorded) = self.record_image_digest(digest.clone())? {
+            eprintln!(
+                "Warning: docker image {} is now {} but the build was created with {}; set docker-image to {} to build with the same image",
+                apps.defaults().docker_image(),
+                digest,
+                recorded,
+                recorded
+            );
+        }
+        Ok(())
+    }
+
+    /// Record the digest of the docker image unless one is already recorded
+    ///
+    /// Returns the recorded digest if it differs from the given digest.
+    fn record_image_digest(&mut self, digest: String) -> Result<Option<String>> {
+        match &self.build.image_digest {
+            Some(recorded) if *recorded != digest => Ok(Some(recorded.clone())),
+            Some(_) => Ok(None),
+            None => {
+                self.build.image_digest = Some(digest);
+                self.save()?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Time of the last successful build (if any)
+    pub fn last_built(&self) -> Option<SystemTime> {
+        self.build
+            .last_built
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Check if the sources have changed since the last successful build
+    ///
+    /// The source directory of the project and every project checked out by repo (such as the
+    /// kernel) are checked. Builds that have never been built are always stale.
+    pub fn is_stale(&self, config: &Config) -> Result<bool> {
+        let last_built = match self.build.last_built {
+            Some(last_built) => last_built,
+            None => return Ok(true),
+        };
+
+        let mut sources = vec![config
+            .project(self.project())
+            .source_dir(self, config.defaults())?];
+        sources.extend(self.workspace.repo_projects()?);
+
+        let mut newest = None;
+        for source in sources {
+            let source = self.workspace_root().join(source);
+            if source.is_dir() {
+                newest = newest.max(newest_modification(&source)?);
+            }
+        }
+        Ok(match newest {
+            Some(modified) => modified.duration_since(UNIX_EPOCH)?.as_secs() > last_built,
+            None => false,
+        })
+    }
+
     pub fn setting(&self) -> &Setting {
         &self.build.setting
     }
@@ -433,6 +1441,134 @@ impl BuildContext {
         &self.build.platform
     }
 
+    /// A short identifier of the build configuration
+    ///
+    /// This has the form `project/platform[:variation]/architecture@hash` where the hash is of
+    /// the build setting, distinguishing builds of the same platform with different settings.
+    pub fn describe(&self) -> String {
+        Self::description(
+            self.project(),
+            self.platform(),
+            self.variation(),
+            self.architecture(),
+            &self.build.setting,
+        )
+    }
+
+    /// Describe a build configuration that need not exist yet (see `describe`)
+    fn description(
+        project: &ProjectId,
+        platform: &PlatformId,
+        variation: Option<&VariationId>,
+        architecture: Sel4Architecture,
+        setting: &Setting,
+    ) -> String {
+        let mut description = format!("{}/{}", project.as_ref(), platform.as_ref());
+        if let Some(variation) = variation {
+            description.push(':');
+            description.push_str(variation.as_ref());
+        }
+        description.push('/');
+        description.push_str(&architecture.to_string());
+        if let Ok(hash) = toml_hash(setting) {
+            description.push('@');
+            description.push_str(&hash[..Self::DESCRIBE_HASH_LENGTH]);
+        }
+        description
+    }
+
+    /// Number of hex digits of the setting hash included when describing a build
+    const DESCRIBE_HASH_LENGTH: usize = 8;
+
+    /// Revision of the project source to check out before building (if any)
+    pub fn source_revision(&self) -> Option<&str> {
+        self.build.source_revision.as_deref()
+    }
+
+    pub fn set_source_revision(&mut self, revision: Option<String>) {
+        self.build.source_revision = revision;
+    }
+
+    /// Tags used to group builds
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.build.tags.iter().map(String::as_str)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.build.tags.contains(tag)
+    }
+
+    /// Add a tag to the build, returning `false` if it was already tagged
+    pub fn add_tag(&mut self, tag: impl Into<String>) -> bool {
+        self.build.tags.insert(tag.into())
+    }
+
+    /// Remove a tag from the build, returning `false` if it was not tagged
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        self.build.tags.remove(tag)
+    }
+
+    /// Compare the configuration of this build with another
+    ///
+    /// The comparison reports whether both builds target the same platform and architecture along
+    /// with every flag that would configure CMake differently.
+    pub fn compare(&self, other: &BuildContext) -> BuildComparison {
+        let target = |build: &BuildContext| {
+            let mut platform = build.platform().as_ref().to_string();
+            if let Some(variation) = build.variation() {
+                platform.push(':');
+                platform.push_str(variation.as_ref());
+            }
+            platform
+        };
+        BuildComparison {
+            names: (self.describe(), other.describe()),
+            platforms: (target(self), target(other)),
+            architectures: (self.architecture(), other.architecture()),
+            differences: self.setting().difference(other.setting()),
+        }
+    }
+
+    /// Check out the recorded revision of the project source
+    ///
+    /// The source checkout is shared between all builds in the workspace, so this changes the
+    /// source used by every other build.
+    pub fn checkout_source(&self, apps: &Apps, config: &Config) -> Result<()> {
+        if let Some(mut command) = self.checkout_command(apps, config)? {
+            eprintln!(
+                "Warning: checking out {} changes the source shared by all builds in {}",
+                self.source_revision().unwrap_or_default(),
+                self.workspace_root().display()
+            );
+            if !apps.status(&mut command)?.success() {
+                bail!("Failed to check out source revision");
+            }
+        }
+        Ok(())
+    }
+
+    fn checkout_command(&self, apps: &Apps, config: &Config) -> Result<Option<Command>> {
+        let revision = match self.source_revision() {
+            Some(revision) => revision,
+            None => return Ok(None),
+        };
+
+        let mut command = apps.repo();
+        command
+            .arg("forall")
+            .arg(
+                config
+                    .project(self.project())
+                    .source_dir(self, config.defaults())?,
+            )
+            .arg("-c")
+            .arg("git")
+            .arg("checkout")
+            .arg(revision);
+        command.current_dir(self.workspace_root());
+        Ok(Some(command))
+    }
+
     pub fn variation(&self) -> Option<&VariationId> {
         self.build.variation.as_ref()
     }
@@ -441,16 +1577,39 @@ impl BuildContext {
         self.build.architecture
     }
 
-    pub fn kernel_image_path(&self) -> Result<PathBuf> {
-        self.in_image_dir(format!("kernel-{}", self.plat_image_name()))
+    /// Path of the kernel image relative to the build directory
+    pub fn kernel_image_path(&self, images_dir: impl AsRef<Path>) -> Result<PathBuf> {
+        self.in_image_dir(images_dir, format!("kernel-{}", self.plat_image_name()))
     }
 
-    pub fn image_path(&self, root_server: impl AsRef<str>) -> Result<PathBuf> {
-        self.in_image_dir(format!(
-            "{}-image-{}",
-            root_server.as_ref(),
-            self.plat_image_name()
-        ))
+    /// Path of the root server image relative to the build directory
+    ///
+    /// The image name is given by `pattern` (see `Project::image_name_pattern`).
+    pub fn image_path(
+        &self,
+        images_dir: impl AsRef<Path>,
+        pattern: &str,
+        root_server: impl AsRef<str>,
+    ) -> Result<PathBuf> {
+        let (prefix, suffix) = self.image_name_parts(pattern)?;
+        self.in_image_dir(
+            images_dir,
+            format!("{}{}{}", prefix, root_server.as_ref(), suffix),
+        )
+    }
+
+    /// Text either side of the root server name in an image name for this build
+    fn image_name_parts(&self, pattern: &str) -> Result<(String, String)> {
+        let pattern = pattern.replace("{plat}", &self.plat_image_name());
+        match pattern.split_once("{rootserver}") {
+            Some((prefix, suffix)) if !suffix.contains("{rootserver}") => {
+                Ok((prefix.to_owned(), suffix.to_owned()))
+            }
+            _ => bail!(
+                "Image name pattern {} must contain {{rootserver}} exactly once",
+                pattern
+            ),
+        }
     }
 
     fn plat_image_name(&self) -> String {
@@ -460,64 +1619,198 @@ impl BuildContext {
         }
     }
 
-    fn in_image_dir(&self, filename: impl AsRef<Path>) -> Result<PathBuf> {
-        let mut path = PathBuf::new();
-        path.push("images");
-        path.push(filename);
+    fn in_image_dir(
+        &self,
+        images_dir: impl AsRef<Path>,
+        filename: impl AsRef<Path>,
+    ) -> Result<PathBuf> {
+        let path = images_dir.as_ref().join(filename);
+        if self.build_root.join(&path).exists() {
+            Ok(path)
+        } else {
+            bail!("Image file missing: {}", path.display())
+        }
+    }
 
-        in_dir(&self.build_root, || {
-            if path.exists() {
-                Ok(path)
-            } else {
-                bail!("Image file missing: {}", path.display())
-            }
-        })
+    /// Check that the build directory is consistent with its workspace
+    ///
+    /// This checks that the source directory still exists, that the CMake cache was generated
+    /// from that source directory, and that the expected images have been built.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.verify_images_in(
+            self.inferred_source().ok(),
+            Path::new(Project::IMAGES_DIR),
+            Project::IMAGE_NAME_PATTERN,
+        )
     }
 
-    pub fn inferred_root_server(&self) -> Result<String> {
-        in_dir(&self.build_root, || {
-            if Path::new("images").is_dir() {
-                let image_tail = format!("-image-{}", self.plat_image_name());
-                for file in read_dir("images")? {
-                    let file = file?;
-                    if let Some(name) = file.file_name().to_str() {
-                        if name.ends_with(&image_tail) {
-                            return Ok(name[..name.len() - image_tail.len()].to_owned());
-                        }
+    fn verify_images_in(
+        &self,
+        source: Option<PathBuf>,
+        images_dir: &Path,
+        pattern: &str,
+    ) -> Result<VerifyReport> {
+        let mut issues = Vec::new();
+
+        match &source {
+            Some(source) if self.workspace_root().join(source).is_dir() => {}
+            Some(source) => issues.push(VerifyIssue::MissingSource(source.clone())),
+            None => issues.push(VerifyIssue::UnknownSource),
+        }
+
+        match self.cmake_cache_source()? {
+            Some(found) => {
+                if let Some(source) = &source {
+                    let expected = Path::new(Project::WORKSPACE_DOCKER_DIR).join(source);
+                    if found != expected {
+                        issues.push(VerifyIssue::CacheSourceMismatch { expected, found });
                     }
                 }
-                bail!("no rootserver image in images directory")
-            } else {
-                bail!("images directory is missing")
             }
-        })
+            None => issues.push(VerifyIssue::MissingCMakeCache),
+        }
+
+        if self.architecture().architecture() == crate::X86 {
+            if let Err(error) = self.kernel_image_path(images_dir) {
+                issues.push(VerifyIssue::MissingImage(error.to_string()));
+            }
+        }
+        if let Err(error) = self.inferred_root_server(images_dir, pattern) {
+            issues.push(VerifyIssue::MissingImage(error.to_string()));
+        }
+
+        Ok(VerifyReport { issues })
+    }
+
+    /// Verify the build directory and check that its setting only uses known flags
+    ///
+    /// Images are expected in the images directory configured for the project.
+    pub fn verify_against(&self, config: &Config) -> Result<VerifyReport> {
+        let project = config.project(self.project());
+        let mut report = self.verify_images_in(
+            project.source_dir(self, config.defaults()).ok(),
+            project.images_dir(),
+            project.image_name_pattern(),
+        )?;
+
+        let unknown = config.unknown_flags(self.setting(), &self.easy_settings(config)?);
+        if !unknown.is_empty() {
+            report.issues.push(VerifyIssue::UnknownFlags(unknown));
+        }
+
+        Ok(report)
+    }
+
+    /// Source directory recorded in the CMake cache (if the build has been configured)
+    fn cmake_cache_source(&self) -> Result<Option<PathBuf>> {
+        let path = self.build_root.join(Build::CMAKE_CACHE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        Ok(cmake_cache_entries(&path)?
+            .remove("CMAKE_HOME_DIRECTORY")
+            .map(PathBuf::from))
+    }
+
+    /// Infer the name of the root server from the images in the build directory
+    ///
+    /// Image names are matched against `pattern` (see `Project::image_name_pattern`).
+    pub fn inferred_root_server(
+        &self,
+        images_dir: impl AsRef<Path>,
+        pattern: &str,
+    ) -> Result<String> {
+        let images = self.build_root.join(images_dir.as_ref());
+        if !images.is_dir() {
+            bail!("{} directory is missing", images_dir.as_ref().display());
+        }
+
+        let (prefix, suffix) = self.image_name_parts(pattern)?;
+        for file in read_dir(&images)? {
+            let file = file?;
+            if let Some(name) = file.file_name().to_str() {
+                let root_server = name
+                    .strip_prefix(&prefix)
+                    .and_then(|name| name.strip_suffix(&suffix));
+                if let Some(root_server) = root_server.filter(|name| !name.is_empty()) {
+                    return Ok(root_server.to_owned());
+                }
+            }
+        }
+        bail!(
+            "no rootserver image in {} directory",
+            images_dir.as_ref().display()
+        )
     }
 }
 
-/// Workspace directory for a project
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+/// Read the variables in a CMake cache file
+///
+/// Entries have the form `NAME:TYPE=VALUE`; the types are discarded.
+fn cmake_cache_entries(path: &Path) -> Result<BTreeMap<String, String>> {
+    let mut entries = BTreeMap::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            let name = name.split_once(':').map_or(name, |(name, _type)| name);
+            entries.insert(name.to_owned(), value.to_owned());
+        }
+    }
+    Ok(entries)
+}
+
+/// Parse the targets listed by `ninja -t targets`
+fn parse_ninja_targets(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.rsplit_once(": "))
+        .map(|(target, _rule)| target.trim().to_owned())
+        .collect()
+}
+
+/// Check the output of a ninja dry run for an indication that nothing needs to be built
+fn ninja_has_no_work(output: &str) -> bool {
+    output
+        .lines()
+        .any(|line| line.trim() == "ninja: no work to do.")
+}
+
+/// Portable definition of a workspace and its builds
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
-pub struct Workspace {
+pub struct WorkspaceExport {
     /// Project associated with workspace
     project: ProjectId,
     /// Build directories
-    builds: BTreeSet<PathBuf>,
+    #[serde(default, rename = "build")]
+    builds: Vec<BuildExport>,
 }
 
-impl Workspace {
-    /// Filename used to indicate a workspace directory
-    const FILENAME: &'static str = ".s4-workspace.toml";
-
-    /// Hint file used to indicate the location of the project source directory
-    const EASY_SETTINGS: &'static str = "easy-settings.cmake";
+impl WorkspaceExport {
+    /// Ensure that the builds of an export stay within the workspace and are valid for the
+    /// configuration
+    fn validate(&self, config: &Config) -> Result<()> {
+        config.find_project(&self.project)?;
+        for build in &self.builds {
+            build
+                .validate(config, &self.project)
+                .map_err(|e| format_err!("Build {}: {}", build.path.display(), e))?;
+        }
+        Ok(())
+    }
 }
 
-/// Build directory configuration
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+/// Portable definition of a build directory
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
-pub struct Build {
-    /// Root directory of workspace
-    workspace_root: PathBuf,
+pub struct BuildExport {
+    /// Path of the build directory relative to the workspace
+    #[serde(rename = "build-path")]
+    path: PathBuf,
     /// Configured platform
     #[serde(rename = "build-platform")]
     platform: PlatformId,
@@ -531,28 +1824,1602 @@ pub struct Build {
     /// Configured architecture
     #[serde(rename = "build-architecture")]
     architecture: Sel4Architecture,
+    /// Revision of the project source to check out before building (if any)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "build-source-revision"
+    )]
+    source_revision: Option<String>,
+    /// Digest of the docker image the build was created with (if known)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "build-image-digest"
+    )]
+    image_digest: Option<String>,
+    /// Tags used to group builds
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeSet::is_empty",
+        rename = "build-tags"
+    )]
+    tags: BTreeSet<String>,
     /// Settings for the build directory
     #[serde(flatten)]
     setting: Setting,
 }
 
-impl Build {
-    /// Filename used to indicate a build directory
-    pub const FILENAME: &'static str = ".s4-build.toml";
+impl BuildExport {
+    /// Ensure that the build stays within the workspace and is valid for the configuration
+    fn validate(&self, config: &Config, project: &ProjectId) -> Result<()> {
+        let inside = self
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !inside || self.path.components().next().is_none() {
+            bail!("The build path must be a directory inside the workspace");
+        }
 
-    fn new(
-        workspace_root: PathBuf,
-        platform: PlatformId,
-        variation: Option<VariationId>,
-        architecture: Sel4Architecture,
-        setting: Setting,
+        let base = config.platform_setting(
+            project,
+            &self.platform,
+            self.variation.as_ref(),
+            self.architecture,
+        )?;
+        let overrides: Setting = self
+            .setting
+            .flags()
+            .filter(|(id, value)| base.get(id) != Some(value))
+            .map(|(id, value)| (id.clone(), value.clone()))
+            .collect();
+        config.check_overrides(project, &overrides)?;
+        config.check_setting(&self.setting)
+    }
+}
+
+/// Combinations of builds created by `WorkspaceContext::create_matrix`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MatrixSpec {
+    platforms: Vec<PlatformChoice>,
+    architectures: Vec<Sel4Architecture>,
+    presets: Vec<String>,
+}
+
+impl MatrixSpec {
+    /// Prefix of the directories of builds created from a matrix
+    const DIRECTORY_PREFIX: &'static str = "build-";
+
+    /// Describe a matrix of builds
+    ///
+    /// Each combination is built once with each preset, or once without a preset if there are no
+    /// presets.
+    pub fn new(
+        platforms: Vec<PlatformChoice>,
+        architectures: Vec<Sel4Architecture>,
+        presets: Vec<String>,
     ) -> Self {
-        Build {
-            workspace_root,
-            platform,
-            variation,
-            architecture,
-            setting,
+        MatrixSpec {
+            platforms,
+            architectures,
+            presets,
         }
     }
+
+    /// Name of the directory of a build with a given description
+    fn directory_name(description: &str) -> String {
+        let name = description
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect::<String>();
+        format!("{}{}", Self::DIRECTORY_PREFIX, name)
+    }
+}
+
+/// Result of verifying a build directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Whether the build directory passed all checks
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn issues(&self) -> impl Iterator<Item = &VerifyIssue> {
+        self.issues.iter()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "Build directory is consistent");
+        }
+
+        write!(f, "Build directory has {} issue(s):", self.issues.len())?;
+        for issue in &self.issues {
+            write!(f, "\n    {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem found when verifying a build directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyIssue {
+    /// The source directory could not be inferred from the workspace
+    UnknownSource,
+    /// The source directory no longer exists in the workspace
+    MissingSource(PathBuf),
+    /// The build directory has not been configured by CMake
+    MissingCMakeCache,
+    /// The CMake cache was generated from a different source directory
+    CacheSourceMismatch { expected: PathBuf, found: PathBuf },
+    /// An expected image file has not been built
+    MissingImage(String),
+    /// The setting uses flags that are not defined by the configuration or easy settings
+    UnknownFlags(Vec<FlagId>),
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyIssue::UnknownSource => write!(f, "Could not infer source directory"),
+            VerifyIssue::MissingSource(source) => {
+                write!(f, "Source directory {} is missing", source.display())
+            }
+            VerifyIssue::MissingCMakeCache => write!(f, "CMake cache is missing"),
+            VerifyIssue::CacheSourceMismatch { expected, found } => write!(
+                f,
+                "CMake cache source is {} but expected {}",
+                found.display(),
+                expected.display()
+            ),
+            VerifyIssue::MissingImage(message) => write!(f, "{}", message),
+            VerifyIssue::UnknownFlags(flags) => {
+                let flags: Vec<_> = flags.iter().map(FlagId::to_string).collect();
+                write!(f, "Unknown flags in setting: {}", flags.join(", "))
+            }
+        }
+    }
+}
+
+/// Side by side comparison of two builds
+#[derive(Debug, Clone)]
+pub struct BuildComparison {
+    names: (String, String),
+    platforms: (String, String),
+    architectures: (Sel4Architecture, Sel4Architecture),
+    differences: Vec<(FlagId, Option<Value>, Option<Value>)>,
+}
+
+impl BuildComparison {
+    /// Whether both builds target the same platform and variation
+    pub fn same_platform(&self) -> bool {
+        self.platforms.0 == self.platforms.1
+    }
+
+    pub fn same_architecture(&self) -> bool {
+        self.architectures.0 == self.architectures.1
+    }
+
+    /// Flags that differ between the builds with the value from each build, if it is set
+    pub fn differences(&self) -> &[(FlagId, Option<Value>, Option<Value>)] {
+        &self.differences
+    }
+}
+
+impl fmt::Display for BuildComparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rows = vec![
+            (String::new(), self.names.0.clone(), self.names.1.clone()),
+            (
+                "platform".to_string(),
+                self.platforms.0.clone(),
+                self.platforms.1.clone(),
+            ),
+            (
+                "architecture".to_string(),
+                self.architectures.0.to_string(),
+                self.architectures.1.to_string(),
+            ),
+        ];
+        let value = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => "unset".to_string(),
+        };
+        rows.extend(
+            self.differences
+                .iter()
+                .map(|(flag, left, right)| (flag.to_string(), value(left), value(right))),
+        );
+
+        let label_width = rows
+            .iter()
+            .map(|(label, _, _)| label.len())
+            .max()
+            .unwrap_or(0);
+        let left_width = rows
+            .iter()
+            .map(|(_, left, _)| left.len())
+            .max()
+            .unwrap_or(0);
+        for (index, (label, left, right)) in rows.into_iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            let line = format!(
+                "{:label_width$}  {:left_width$}  {}",
+                label,
+                left,
+                right,
+                label_width = label_width,
+                left_width = left_width
+            );
+            write!(f, "{}", line.trim_end())?;
+        }
+        if self.differences.is_empty() {
+            write!(f, "\nSettings are equivalent")?;
+        }
+        Ok(())
+    }
+}
+
+/// Workspace directory for a project
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Workspace {
+    /// Project associated with workspace
+    project: ProjectId,
+    /// Build directories
+    builds: BTreeSet<PathBuf>,
+}
+
+impl Workspace {
+    /// Filename used to indicate a workspace directory
+    const FILENAME: &'static str = ".s4-workspace.toml";
+
+    /// Directory used by repo to track the checkout
+    const REPO_DIR: &'static str = ".repo";
+
+    /// File in the repo directory listing the directory of each project that is checked out
+    const PROJECT_LIST: &'static str = "project.list";
+
+    /// Hint file used to indicate the location of the project source directory
+    const EASY_SETTINGS: &'static str = "easy-settings.cmake";
+
+    /// File in the kernel sources that records the kernel version
+    const KERNEL_VERSION: &'static str = "kernel/VERSION";
+
+    /// Git ignore file in the root of the workspace
+    const GITIGNORE: &'static str = ".gitignore";
+
+    /// Patterns for the cache and build output directories of a workspace
+    const GITIGNORE_ENTRIES: &'static [&'static str] = &["/.sel4_cache/", "/build*/"];
+}
+
+/// Build directory configuration
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Build {
+    /// Root directory of workspace
+    workspace_root: PathBuf,
+    /// Configured platform
+    #[serde(rename = "build-platform")]
+    platform: PlatformId,
+    /// Configure variation (if any)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "build-variation"
+    )]
+    variation: Option<VariationId>,
+    /// Configured architecture
+    #[serde(rename = "build-architecture")]
+    architecture: Sel4Architecture,
+    /// Revision of the project source to check out before building (if any)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "build-source-revision"
+    )]
+    source_revision: Option<String>,
+    /// Time of the last successful build in seconds since the Unix epoch (if any)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "build-last-built"
+    )]
+    last_built: Option<u64>,
+    /// Digest of the docker image the build was created with (if known)
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "build-image-digest"
+    )]
+    image_digest: Option<String>,
+    /// Tags used to group builds
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeSet::is_empty",
+        rename = "build-tags"
+    )]
+    tags: BTreeSet<String>,
+    /// Settings for the build directory
+    #[serde(flatten)]
+    setting: Setting,
+}
+
+/// Local setting overrides for a build directory
+#[derive(Debug, Clone, Default, Deserialize)]
+struct LocalBuild {
+    #[serde(flatten)]
+    setting: Setting,
+}
+
+impl Build {
+    /// Filename used to indicate a build directory
+    pub const FILENAME: &'static str = ".s4-build.toml";
+
+    /// Filename of local setting overrides that are not managed by s4
+    pub const LOCAL_FILENAME: &'static str = ".s4-build.local.toml";
+
+    /// Filename used to record the hash of the setting last used to configure the build
+    const CONFIGURED_FILENAME: &'static str = ".s4-configured";
+
+    /// Cache file generated by CMake when a build is configured
+    const CMAKE_CACHE: &'static str = "CMakeCache.txt";
+
+    /// Directory of generated CMake state that depends on the cache
+    const CMAKE_FILES: &'static str = "CMakeFiles";
+
+    /// Directory of the serial logs captured when the build is run
+    pub const RUN_LOG_DIR: &'static str = ".s4-logs";
+
+    /// CMake cache variables used to adopt an existing build directory
+    const CACHE_ARCH_VARIABLE: &'static str = "KernelSel4Arch";
+    const CACHE_KERNEL_PLATFORM_VARIABLE: &'static str = "KernelPlatform";
+    const CACHE_PLATFORM_VARIABLE: &'static str = "PLATFORM";
+
+    fn new(
+        workspace_root: PathBuf,
+        platform: PlatformId,
+        variation: Option<VariationId>,
+        architecture: Sel4Architecture,
+        setting: Setting,
+    ) -> Self {
+        Build {
+            workspace_root,
+            platform,
+            variation,
+            architecture,
+            source_revision: None,
+            last_built: None,
+            image_digest: None,
+            tags: BTreeSet::new(),
+            setting,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Defaults;
+
+    fn test_build(name: &str) -> BuildContext {
+        let root = test_dir(name);
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        BuildContext::create(
+            &Config::builtin().unwrap(),
+            &workspace,
+            "odroidc2".into(),
+            None,
+            crate::AArch64,
+            Setting::default(),
+            root.join("build"),
+            false,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn build_into_workspace() {
+        let build = test_build("into-workspace");
+        let build_root = build.build_root().canonicalize().unwrap();
+        let workspace_root = build.workspace_root().to_owned();
+
+        let workspace = build.into_workspace();
+        assert_eq!(workspace.workspace_root(), workspace_root);
+        assert_eq!(workspace.project(), &ProjectId::from("sel4test"));
+        let builds = workspace.builds().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].build_root().canonicalize().unwrap(), build_root);
+    }
+
+    #[test]
+    fn renamed_flags_are_migrated_on_load() {
+        let mut build = test_build("migrate-on-load");
+        build.build.setting.set_bool("old-flag", true);
+        build.save().unwrap();
+
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [flag-rename]
+                old-flag = "new-flag"
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let context = context_at(build.build_root(), None, &config)
+            .unwrap()
+            .unwrap();
+        assert!(context.maybe_build_root().is_some());
+
+        let saved = BuildContext::load(build.workspace(), build.build_root()).unwrap();
+        assert_eq!(saved.setting().get(&"old-flag".into()), None);
+        assert_eq!(
+            saved.setting().get(&"new-flag".into()),
+            Some(&crate::Value::Boolean(true))
+        );
+    }
+
+    #[test]
+    fn ephemeral_build_is_not_kept() {
+        let root = test_dir("ephemeral");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let config = Config::builtin().unwrap();
+        let ephemeral = || {
+            workspace
+                .ephemeral_build(
+                    &config,
+                    "odroidc2".into(),
+                    None,
+                    crate::AArch64,
+                    Setting::default(),
+                )
+                .unwrap()
+        };
+
+        let build = ephemeral();
+        let other = ephemeral();
+        let build_root = build.build_root().to_owned();
+        assert_ne!(build_root, other.build_root());
+        assert!(build_root.join(Build::FILENAME).is_file());
+        assert_eq!(build.platform(), &PlatformId::from("odroidc2"));
+
+        let reloaded = WorkspaceContext::load(&root).unwrap();
+        assert_eq!(reloaded.builds().count(), 0);
+
+        drop(build);
+        assert!(!build_root.exists());
+        assert!(other.build_root().exists());
+    }
+
+    #[test]
+    fn describe_build() {
+        let mut build = test_build("describe");
+        let description = build.describe();
+        let hash = &toml_hash(build.setting()).unwrap()[..8];
+        assert_eq!(description, format!("sel4test/odroidc2/aarch64@{}", hash));
+
+        build.build.variation = Some("smp4".into());
+        assert!(build
+            .describe()
+            .starts_with("sel4test/odroidc2:smp4/aarch64@"));
+
+        build.build.variation = None;
+        build.setting_mut().set_bool("mcs", true);
+        assert!(build.describe().starts_with("sel4test/odroidc2/aarch64@"));
+        assert_ne!(build.describe(), description);
+    }
+
+    #[test]
+    fn adopt_build_from_cmake_cache() {
+        let root = test_dir("adopt");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let build_root = root.join("existing");
+        create_dir_all(&build_root).unwrap();
+        write(
+            build_root.join(Build::CMAKE_CACHE),
+            "# This is the CMakeCache file.\n\
+             //Platform to build for\n\
+             KernelPlatform:STRING=bcm2837\n\
+             PLATFORM:STRING=rpi3\n\
+             KernelSel4Arch:STRING=aarch64\n\
+             CMAKE_HOME_DIRECTORY:INTERNAL=/workspace/projects/sel4test\n",
+        )
+        .unwrap();
+
+        let config = Config::builtin().unwrap();
+        let build = BuildContext::adopt(&config, &workspace, &build_root).unwrap();
+        assert_eq!(build.platform(), &PlatformId::from("bcm2837"));
+        assert_eq!(build.variation(), Some(&VariationId::from("rpi3")));
+        assert_eq!(build.architecture(), crate::AArch64);
+        assert_eq!(
+            build.setting().flag(&"kernel-platform".into()),
+            Value::Text("bcm2837".into())
+        );
+        assert_eq!(
+            build.setting().flag(&"platform".into()),
+            Value::Text("rpi3".into())
+        );
+        assert_eq!(
+            build.cmake_cache_source().unwrap(),
+            Some(PathBuf::from("/workspace/projects/sel4test"))
+        );
+
+        let reloaded = WorkspaceContext::load(&root).unwrap();
+        let builds = reloaded.builds().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(builds.len(), 1);
+        assert_eq!(builds[0].build, build.build);
+
+        assert!(BuildContext::adopt(&config, &workspace, &build_root).is_err());
+        assert!(BuildContext::adopt(&config, &workspace, root.join("missing")).is_err());
+    }
+
+    #[test]
+    fn adopt_checks_cached_platform() {
+        let root = test_dir("adopt-unknown");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let config = Config::builtin().unwrap();
+        let adopt = |name: &str, cache: &str| {
+            let build_root = root.join(name);
+            create_dir_all(&build_root).unwrap();
+            write(build_root.join(Build::CMAKE_CACHE), cache).unwrap();
+            let error = BuildContext::adopt(&config, &workspace, &build_root)
+                .err()
+                .unwrap();
+            assert!(!build_root.join(Build::FILENAME).exists());
+            error.to_string()
+        };
+
+        let error = adopt(
+            "variation",
+            "KernelPlatform:STRING=bcm2837\nPLATFORM:STRING=rpi4\nKernelSel4Arch:STRING=aarch64\n",
+        );
+        assert!(error.starts_with("No such platform variation rpi4 for platform bcm2837"));
+
+        let error = adopt(
+            "platform",
+            "KernelPlatform:STRING=odroidc3\nKernelSel4Arch:STRING=aarch64\n",
+        );
+        assert!(error.starts_with("No such platform odroidc3"), "{}", error);
+        assert!(error.contains("odroidc2"), "{}", error);
+    }
+
+    #[test]
+    fn gitignore_entries_are_appended() {
+        let root = test_dir("gitignore");
+        create_dir_all(&root).unwrap();
+        let gitignore = root.join(Workspace::GITIGNORE);
+        write(&gitignore, "*.swp\n/.sel4_cache/").unwrap();
+
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, true).unwrap();
+        workspace.update_gitignore().unwrap();
+        assert_eq!(
+            read_to_string(&gitignore).unwrap(),
+            "*.swp\n/.sel4_cache/\n/build*/\n"
+        );
+    }
+
+    #[test]
+    fn compare_builds() {
+        let build = test_build("compare-left");
+        let mut other = test_build("compare-right");
+        other.setting_mut().set_bool("mcs", true);
+
+        let comparison = build.compare(&other);
+        assert!(comparison.same_platform());
+        assert!(comparison.same_architecture());
+        assert_eq!(
+            comparison.differences(),
+            &[("mcs".into(), None, Some(Value::Boolean(true)))][..]
+        );
+
+        let table = comparison.to_string();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[3].split_whitespace().collect::<Vec<_>>(),
+            ["mcs", "unset", "true"]
+        );
+        assert_eq!(lines[1].find("odroidc2"), lines[3].find("unset"));
+
+        assert!(build.compare(&build).differences().is_empty());
+    }
+
+    #[test]
+    fn create_build_matrix() {
+        let root = test_dir("matrix");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [preset.mcs]
+                mcs = true
+                "#,
+            )
+            .unwrap(),
+        );
+
+        let spec = MatrixSpec::new(
+            vec!["odroidc2".parse().unwrap(), "hikey".parse().unwrap()],
+            vec![crate::AArch32, crate::AArch64],
+            vec!["mcs".into()],
+        );
+        let builds = workspace.create_matrix(&config, &spec).unwrap();
+        assert_eq!(builds.len(), 3);
+        for build in &builds {
+            assert_eq!(build.setting().flag(&"mcs".into()), Value::Boolean(true));
+            assert_eq!(
+                build.build_root().file_name().unwrap().to_str().unwrap(),
+                MatrixSpec::directory_name(&build.describe())
+            );
+        }
+
+        let workspace = WorkspaceContext::load(&root).unwrap();
+        assert_eq!(workspace.builds().count(), 3);
+
+        // Creating the matrix again reuses the existing builds
+        let again = workspace.create_matrix(&config, &spec).unwrap();
+        assert_eq!(
+            again
+                .iter()
+                .map(BuildContext::build_root)
+                .collect::<Vec<_>>(),
+            builds
+                .iter()
+                .map(BuildContext::build_root)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(WorkspaceContext::load(&root).unwrap().builds().count(), 3);
+
+        let unknown = MatrixSpec::new(
+            vec!["odroidc2".parse().unwrap()],
+            vec![crate::AArch64],
+            vec!["mcs-smp".into()],
+        );
+        assert!(workspace.create_matrix(&config, &unknown).is_err());
+    }
+
+    #[test]
+    fn missing_builds_are_registered() {
+        let build = test_build("registered");
+        let workspace = build.workspace();
+        let missing = workspace.workspace_root().join("missing");
+        let mut builds = workspace.clone();
+        builds.workspace.builds.insert("missing".into());
+
+        let registered = builds
+            .registered_builds()
+            .map(|build| {
+                let (path, build) = build.unwrap();
+                (path, build.is_some())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            registered,
+            [(build.build_root().to_owned(), true), (missing, false)]
+        );
+        assert_eq!(builds.builds().count(), 1);
+
+        let config = Config::builtin().unwrap();
+        let project = config.project(build.project());
+        assert!(!project.images_built(&build));
+        create_dir_all(build.build_root().join("images")).unwrap();
+        write(
+            build
+                .build_root()
+                .join("images/sel4test-driver-image-arm-odroidc2"),
+            "",
+        )
+        .unwrap();
+        assert!(project.images_built(&build));
+    }
+
+    #[test]
+    fn builds_filtered_by_tag() {
+        let root = test_dir("tags");
+        let mut workspace =
+            WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let config = Config::builtin().unwrap();
+        for (name, tags) in &[
+            ("release", &["release"][..]),
+            ("both", &["release", "ci"][..]),
+            ("experiment", &["experiment"][..]),
+        ] {
+            let mut build = BuildContext::create(
+                &config,
+                &workspace,
+                "odroidc2".into(),
+                None,
+                crate::AArch64,
+                Setting::default(),
+                root.join(name),
+                false,
+            )
+            .unwrap();
+            for tag in *tags {
+                build.add_tag(*tag);
+            }
+            build.save().unwrap();
+            workspace = WorkspaceContext::load(&root).unwrap();
+        }
+
+        let tagged = |workspace: &WorkspaceContext, tag| {
+            workspace
+                .tagged_builds(tag)
+                .map(|build| build.unwrap().build_root().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tagged(&workspace, "release"),
+            [root.join("both"), root.join("release")]
+        );
+        assert_eq!(tagged(&workspace, "ci"), [root.join("both")]);
+        assert!(tagged(&workspace, "nightly").is_empty());
+
+        let build = BuildContext::load(&workspace, root.join("both")).unwrap();
+        assert_eq!(build.tags().collect::<Vec<_>>(), ["ci", "release"]);
+        assert_eq!(
+            build.setting(),
+            &config
+                .platform_setting(&"sel4test".into(), &"odroidc2".into(), None, crate::AArch64)
+                .unwrap()
+        );
+
+        assert_eq!(
+            workspace.remove_tagged("experiment").unwrap(),
+            [root.join("experiment")]
+        );
+        assert!(!root.join("experiment").exists());
+        let workspace = WorkspaceContext::load(&root).unwrap();
+        assert_eq!(workspace.builds().count(), 2);
+    }
+
+    #[test]
+    fn sel4_version_from_kernel_sources() {
+        let root = test_dir("sel4-version");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        assert_eq!(workspace.sel4_version().unwrap(), None);
+
+        create_dir_all(root.join("kernel")).unwrap();
+        write(root.join(Workspace::KERNEL_VERSION), "\n13.0.0-dev\n").unwrap();
+        assert_eq!(
+            workspace.sel4_version().unwrap().as_deref(),
+            Some("13.0.0-dev")
+        );
+
+        write(root.join(Workspace::KERNEL_VERSION), "").unwrap();
+        assert!(workspace.sel4_version().is_err());
+    }
+
+    #[test]
+    fn create_only_into_empty_directories() {
+        let root = test_dir("create-empty");
+        let config = Config::builtin().unwrap();
+
+        let new = root.join("new-workspace");
+        let empty = root.join("empty-workspace");
+        let full = root.join("full-workspace");
+        create_dir(&empty).unwrap();
+        create_dir(&full).unwrap();
+        write(full.join("notes.txt"), "").unwrap();
+
+        let workspace = WorkspaceContext::create("sel4test".into(), &new, false, false).unwrap();
+        WorkspaceContext::create("sel4test".into(), &empty, false, false).unwrap();
+        assert!(WorkspaceContext::create("sel4test".into(), &full, false, false).is_err());
+        WorkspaceContext::create("sel4test".into(), &full, true, false).unwrap();
+
+        let create = |path: PathBuf, force: bool| {
+            BuildContext::create(
+                &config,
+                &workspace,
+                "odroidc2".into(),
+                None,
+                crate::AArch64,
+                Setting::default(),
+                path,
+                force,
+            )
+        };
+        create_dir(new.join("empty-build")).unwrap();
+        create_dir(new.join("full-build")).unwrap();
+        write(new.join("full-build").join("CMakeCache.txt"), "").unwrap();
+
+        create(new.join("new-build"), false).unwrap();
+        create(new.join("empty-build"), false).unwrap();
+        assert!(create(new.join("full-build"), false).is_err());
+        create(new.join("full-build"), true).unwrap();
+    }
+
+    #[test]
+    fn failed_build_creation_removes_new_directory() {
+        let root = test_dir("failed-create");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let config = Config::builtin().unwrap();
+        let create = |path: PathBuf| {
+            BuildContext::create(
+                &config,
+                &workspace,
+                "no-such-platform".into(),
+                None,
+                crate::AArch64,
+                Setting::default(),
+                path,
+                false,
+            )
+        };
+
+        assert!(create(root.join("new").join("build")).is_err());
+        assert!(!root.join("new").exists());
+
+        create_dir(root.join("existing")).unwrap();
+        assert!(create(root.join("existing")).is_err());
+        assert!(root.join("existing").is_dir());
+    }
+
+    #[test]
+    fn fresh_configure_clears_cmake_cache() {
+        let build = test_build("fresh");
+        let root = build.build_root();
+        write(root.join(Build::CMAKE_CACHE), "PLATFORM:STRING=odroidc2\n").unwrap();
+        create_dir_all(root.join(Build::CMAKE_FILES).join("3.20.0")).unwrap();
+        create_dir_all(root.join("images")).unwrap();
+        write(
+            root.join("images")
+                .join("sel4test-driver-image-arm-odroidc2"),
+            "",
+        )
+        .unwrap();
+        build.mark_configured().unwrap();
+        assert!(!build.needs_reconfigure().unwrap());
+
+        build.clear_cmake_cache().unwrap();
+        assert!(!root.join(Build::CMAKE_CACHE).exists());
+        assert!(!root.join(Build::CMAKE_FILES).exists());
+        assert!(root
+            .join("images")
+            .join("sel4test-driver-image-arm-odroidc2")
+            .exists());
+        assert!(root.join(Build::FILENAME).exists());
+        assert!(build.needs_reconfigure().unwrap());
+
+        // Clearing an already clear build does nothing
+        build.clear_cmake_cache().unwrap();
+    }
+
+    #[test]
+    fn newest_run_log_is_selected() {
+        let build = test_build("run-logs");
+        let error = build.latest_run_log().unwrap_err();
+        assert!(error.to_string().contains("No run logs found"));
+
+        let logs = build.build_root().join(Build::RUN_LOG_DIR);
+        create_dir_all(&logs).unwrap();
+        let now = SystemTime::now();
+        for (name, age) in &[("a.log", 30), ("b.log", 10), ("c.log", 20)] {
+            let path = logs.join(name);
+            write(&path, name).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(*age))
+                .unwrap();
+        }
+        create_dir_all(logs.join("newer-directory")).unwrap();
+
+        assert_eq!(build.latest_run_log().unwrap(), logs.join("b.log"));
+        let (path, mut file) = build.open_serial_log().unwrap();
+        assert_eq!(path, logs.join("b.log"));
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut file, &mut contents).unwrap();
+        assert_eq!(contents, "b.log");
+    }
+
+    #[test]
+    fn first_image_digest_is_kept() {
+        let mut build = test_build("image-digest");
+        assert_eq!(build.image_digest(), None);
+
+        let first = "trustworthysystems/sel4@sha256:aaaa".to_owned();
+        assert_eq!(build.record_image_digest(first.clone()).unwrap(), None);
+        assert_eq!(build.record_image_digest(first.clone()).unwrap(), None);
+
+        let reloaded = BuildContext::load(build.workspace(), build.build_root()).unwrap();
+        assert_eq!(reloaded.image_digest(), Some(first.as_str()));
+
+        let second = "trustworthysystems/sel4@sha256:bbbb".to_owned();
+        assert_eq!(
+            build.record_image_digest(second).unwrap(),
+            Some(first.clone())
+        );
+        assert_eq!(build.image_digest(), Some(first.as_str()));
+    }
+
+    #[test]
+    fn saving_build_keeps_comments() {
+        let mut build = test_build("build-comments");
+        let path = build.build_root().join(Build::FILENAME);
+        let original = read_to_string(&path).unwrap();
+        write(
+            &path,
+            format!("# Build for the odroid on my desk\n{}", original),
+        )
+        .unwrap();
+
+        build.mark_built().unwrap();
+        let saved = read_to_string(&path).unwrap();
+        assert!(saved.starts_with("# Build for the odroid on my desk\n"));
+        assert!(saved.contains("build-last-built = "));
+        assert_eq!(
+            BuildContext::load(build.workspace(), build.build_root())
+                .unwrap()
+                .last_built(),
+            build.last_built()
+        );
+    }
+
+    #[test]
+    fn modified_source_is_stale() {
+        let mut build = test_build("stale");
+        let source = build.workspace_root().join("projects").join("sel4test");
+        create_dir_all(&source).unwrap();
+        write(source.join("easy-settings.cmake"), "").unwrap();
+        std::os::unix::fs::symlink(
+            "projects/sel4test/easy-settings.cmake",
+            build.workspace_root().join(Workspace::EASY_SETTINGS),
+        )
+        .unwrap();
+        let config = Config::builtin().unwrap();
+        assert!(build.is_stale(&config).unwrap());
+
+        build.mark_built().unwrap();
+        assert!(build.last_built().is_some());
+        assert!(!build.is_stale(&config).unwrap());
+        let workspace = WorkspaceContext::load(build.workspace_root()).unwrap();
+        assert!(workspace.stale_builds(&config).unwrap().is_empty());
+
+        let main = File::create(source.join("main.c")).unwrap();
+        main.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(build.is_stale(&config).unwrap());
+        let stale = workspace.stale_builds(&config).unwrap();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].build_root(), build.build_root());
+    }
+
+    #[test]
+    fn modified_kernel_is_stale() {
+        let mut build = test_build("stale-kernel");
+        let root = build.workspace_root().to_owned();
+        let source = root.join("projects").join("sel4test");
+        let kernel = root.join("kernel");
+        create_dir_all(&source).unwrap();
+        create_dir_all(&kernel).unwrap();
+        let repo = root.join(Workspace::REPO_DIR);
+        create_dir_all(&repo).unwrap();
+        write(
+            repo.join(Workspace::PROJECT_LIST),
+            "kernel\nprojects/sel4test\ntools/missing\n",
+        )
+        .unwrap();
+        let config = Config::builtin().unwrap();
+        assert_eq!(
+            build.workspace().repo_projects().unwrap(),
+            [
+                PathBuf::from("kernel"),
+                PathBuf::from("projects/sel4test"),
+                PathBuf::from("tools/missing")
+            ]
+        );
+
+        build.mark_built().unwrap();
+        assert!(!build.is_stale(&config).unwrap());
+
+        let header = File::create(kernel.join("kernel.h")).unwrap();
+        header
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert!(build.is_stale(&config).unwrap());
+    }
+
+    #[test]
+    fn easy_settings_are_read_from_project_source() {
+        let build = test_build("easy-settings-source");
+        let source = build.workspace_root().join("projects").join("sel4test");
+        create_dir_all(&source).unwrap();
+        write(
+            source.join("easy-settings.cmake"),
+            "set(LibSel4TestPrinter OFF CACHE BOOL \"Print test results\")\n",
+        )
+        .unwrap();
+
+        let config = Config::builtin().unwrap();
+        let flags = build.easy_settings(&config).unwrap();
+        assert!(flags.get(&"lib-sel4-test-printer".into()).is_some());
+        assert!(build.is_stale(&config).unwrap());
+    }
+
+    #[test]
+    fn reconfigure_only_when_setting_changes() {
+        let mut build = test_build("reconfigure");
+        assert!(build.needs_reconfigure().unwrap());
+
+        build.mark_configured().unwrap();
+        assert!(!build.needs_reconfigure().unwrap());
+
+        build.setting_mut().set_bool("mcs", true);
+        assert!(build.needs_reconfigure().unwrap());
+
+        build.mark_configured().unwrap();
+        assert!(!build.needs_reconfigure().unwrap());
+    }
+
+    #[test]
+    fn isolated_cache_is_per_build() {
+        let build = test_build("cache-mode");
+        assert_eq!(
+            build.docker_cache_dir(CacheMode::Shared),
+            Path::new("/workspace/.sel4_cache")
+        );
+        assert_eq!(
+            build.docker_cache_dir(CacheMode::Isolated),
+            Path::new("/build/.sel4_cache")
+        );
+    }
+
+    #[test]
+    fn closest_root_is_discovered() {
+        let build = test_build("discovery");
+        let nested = build.build_root().join("kernel").join("src");
+        create_dir_all(&nested).unwrap();
+
+        assert_eq!(
+            discover_context_root(&nested, None).unwrap(),
+            build.build_root()
+        );
+
+        // A directory that is no longer a build directory is skipped
+        std::fs::remove_file(build.build_root().join(Build::FILENAME)).unwrap();
+        assert_eq!(
+            discover_context_root(&nested, None).unwrap(),
+            build.workspace_root()
+        );
+    }
+
+    #[test]
+    fn discovered_root_is_cached() {
+        let build = test_build("discovery-cache");
+        let cache = test_dir("discovery-cache-file").join("roots.toml");
+        let nested = build.build_root().join("kernel");
+        let deeper = nested.join("src").join("arch");
+        create_dir_all(&deeper).unwrap();
+
+        assert_eq!(
+            discover_context_root(&nested, Some(&cache)).unwrap(),
+            build.build_root()
+        );
+        let mut cached: DiscoveryCache = toml_load(&cache).unwrap();
+        assert_eq!(cached.roots[&nested], build.build_root());
+
+        // A second discovery from the same subtree stops at the cached directory and reuses its
+        // root, which is shown here by caching a different (but still valid) root
+        cached
+            .roots
+            .insert(nested.clone(), build.workspace_root().to_owned());
+        toml_save(&cached, &cache).unwrap();
+        assert_eq!(
+            discover_context_root(&deeper, Some(&cache)).unwrap(),
+            build.workspace_root()
+        );
+        let cached: DiscoveryCache = toml_load(&cache).unwrap();
+        assert_eq!(cached.roots[&deeper], build.workspace_root());
+
+        // A cached root that is no longer a workspace or build directory is not reused
+        std::fs::remove_file(build.workspace_root().join(Workspace::FILENAME)).unwrap();
+        assert_eq!(
+            discover_context_root(&deeper, Some(&cache)).unwrap(),
+            build.build_root()
+        );
+    }
+
+    #[test]
+    fn architecture_specific_mounts() {
+        let build = test_build("arch-mounts");
+        let aarch64 = test_dir("arch-mounts-aarch64");
+        let riscv64 = test_dir("arch-mounts-riscv64");
+        let defaults: Defaults = toml::from_str(&format!(
+            r#"
+            [architecture-mount.aarch64]
+            "/sysroot" = "{}"
+
+            [architecture-mount.riscv64]
+            "/sysroot" = "{}"
+            "#,
+            aarch64.display(),
+            riscv64.display()
+        ))
+        .unwrap();
+        let apps = Apps::mock(&defaults, crate::Docker);
+
+        let command = build.docker(&apps).unwrap().run("true");
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let volume = |host: &Path| format!("{}:/sysroot:z", host.canonicalize().unwrap().display());
+        assert!(args.contains(&volume(&aarch64)));
+        assert!(!args.contains(&volume(&riscv64)));
+    }
+
+    #[test]
+    fn force_create_in_non_empty_directory() {
+        let root = test_dir("force-non-empty");
+        write(root.join("existing"), "").unwrap();
+
+        assert!(WorkspaceContext::create("sel4test".into(), &root, false, false).is_err());
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        assert!(root.join(Workspace::FILENAME).is_file());
+
+        let build_root = root.join("build");
+        create_dir_all(&build_root).unwrap();
+        write(build_root.join("existing"), "").unwrap();
+
+        let config = Config::builtin().unwrap();
+        let create = |force| {
+            BuildContext::create(
+                &config,
+                &workspace,
+                "odroidc2".into(),
+                None,
+                crate::AArch64,
+                Setting::default(),
+                &build_root,
+                force,
+            )
+        };
+        assert!(create(false).is_err());
+        create(true).unwrap();
+        assert!(build_root.join(Build::FILENAME).is_file());
+    }
+
+    #[test]
+    fn force_create_over_file_fails() {
+        let root = test_dir("force-over-file");
+        let path = root.join("file");
+        write(&path, "").unwrap();
+
+        let error = WorkspaceContext::create("sel4test".into(), &path, true, false)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("already exists"));
+    }
+
+    #[test]
+    fn verify_reports_mismatched_cache_source() {
+        let build = test_build("verify");
+        let source = build.workspace_root().join("projects").join("sel4test");
+        create_dir_all(&source).unwrap();
+        write(source.join("easy-settings.cmake"), "").unwrap();
+        std::os::unix::fs::symlink(
+            "projects/sel4test/easy-settings.cmake",
+            build.workspace_root().join(Workspace::EASY_SETTINGS),
+        )
+        .unwrap();
+        write(
+            build.build_root().join(Build::CMAKE_CACHE),
+            "CMAKE_HOME_DIRECTORY:INTERNAL=/workspace/projects/other\n",
+        )
+        .unwrap();
+
+        let report = build.verify().unwrap();
+        assert!(!report.is_ok());
+        assert!(report.issues().any(|issue| *issue
+            == VerifyIssue::CacheSourceMismatch {
+                expected: "/workspace/projects/sel4test".into(),
+                found: "/workspace/projects/other".into(),
+            }));
+        assert!(!report
+            .issues()
+            .any(|issue| matches!(issue, VerifyIssue::MissingSource(_))));
+    }
+
+    #[test]
+    fn parallel_builds_match_sequential() {
+        let root = test_dir("parallel-builds");
+        let mut workspace =
+            WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let config = Config::builtin().unwrap();
+        for index in 0..12 {
+            BuildContext::create(
+                &config,
+                &workspace,
+                "odroidc2".into(),
+                None,
+                crate::AArch64,
+                Setting::default(),
+                root.join(format!("build-{}", index)),
+                false,
+            )
+            .unwrap();
+            workspace = WorkspaceContext::load(&root).unwrap();
+        }
+
+        let sequential = workspace.builds().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(sequential.len(), 12);
+        assert_eq!(workspace.builds_parallel().unwrap(), sequential);
+    }
+
+    #[test]
+    fn verify_reports_unknown_flags() {
+        let mut build = test_build("verify-unknown");
+        build.setting_mut().set_bool("mcs", true);
+        build.setting_mut().set_bool("bogus-flag", true);
+
+        let report = build.verify_against(&Config::builtin().unwrap()).unwrap();
+        let unknown = report
+            .issues()
+            .find_map(|issue| match issue {
+                VerifyIssue::UnknownFlags(flags) => Some(flags),
+                _ => None,
+            })
+            .unwrap();
+        assert!(unknown.contains(&"bogus-flag".into()));
+        assert!(!unknown.contains(&"mcs".into()));
+    }
+
+    #[test]
+    fn workspace_without_repo_is_not_synced() {
+        let root = test_dir("synced");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        assert!(!workspace.is_synced());
+        assert!(workspace.check_synced().is_err());
+
+        let repo = root.join(Workspace::REPO_DIR);
+        create_dir_all(&repo).unwrap();
+        write(repo.join("manifest.xml"), "").unwrap();
+        assert!(workspace.is_synced());
+
+        // A dangling easy settings link indicates the source was not checked out
+        std::os::unix::fs::symlink(
+            "projects/sel4test/easy-settings.cmake",
+            root.join(Workspace::EASY_SETTINGS),
+        )
+        .unwrap();
+        assert!(!workspace.is_synced());
+    }
+
+    #[test]
+    fn sync_runs_repo_sync_in_workspace() {
+        let root = test_dir("sync");
+        let workspace = WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, crate::Docker);
+
+        let command = workspace.sync_command(&apps);
+        assert_eq!(command.get_program(), apps.repo_path());
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["sync"]);
+        assert_eq!(command.get_current_dir(), Some(root.as_path()));
+    }
+
+    #[test]
+    fn locked_flags_are_not_overridden_locally() {
+        let build = test_build("local-locked");
+        let mut config = Config::builtin().unwrap();
+        config.merge(toml::from_str("locked = [\"fastpath\"]").unwrap());
+
+        let local = build.build_root().join(Build::LOCAL_FILENAME);
+        write(&local, "mcs = true\n").unwrap();
+        build.check_local_overrides(&config).unwrap();
+
+        write(&local, "fastpath = false\n").unwrap();
+        let error = build.check_local_overrides(&config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            ".s4-build.local.toml: Flag fastpath is locked and can not be overridden"
+        );
+    }
+
+    #[test]
+    fn local_overrides_apply_without_being_saved() {
+        let build = test_build("local-overrides");
+        let config = Config::builtin().unwrap();
+        let managed = build.build_root().join(Build::FILENAME);
+        let stored = read_to_string(&managed).unwrap();
+        let hash = build.setting_hash().unwrap();
+
+        write(
+            build.build_root().join(Build::LOCAL_FILENAME),
+            "mcs = true\n",
+        )
+        .unwrap();
+        let setting = build.configure_setting().unwrap();
+        assert!(config
+            .cmake_arg_strings(&setting)
+            .contains(&"-DMCS=ON".to_owned()));
+        assert!(!config
+            .cmake_arg_strings(build.setting())
+            .contains(&"-DMCS=ON".to_owned()));
+        assert_ne!(build.setting_hash().unwrap(), hash);
+
+        build.save().unwrap();
+        assert_eq!(read_to_string(&managed).unwrap(), stored);
+    }
+
+    #[test]
+    fn equivalent_builds_are_found() {
+        let root = test_dir("equivalent");
+        let config = Config::builtin().unwrap();
+        let create = |name: &str, setting: Setting| {
+            let workspace = WorkspaceContext::load(&root).unwrap();
+            BuildContext::create(
+                &config,
+                &workspace,
+                "odroidc2".into(),
+                None,
+                crate::AArch64,
+                setting,
+                root.join(name),
+                false,
+            )
+            .unwrap()
+        };
+        WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+
+        let mut mcs = Setting::default();
+        mcs.set_bool("mcs", true);
+        let first = create("first", mcs.clone());
+        let second = create("second", mcs);
+        let mut smp = Setting::default();
+        smp.set_bool("smp", true);
+        let different = create("different", smp);
+
+        let workspace = WorkspaceContext::load(&root).unwrap();
+        let equivalent = workspace.find_equivalent(&second.build);
+        assert!(equivalent.contains(&first.build_root().to_owned()));
+        assert!(equivalent.contains(&second.build_root().to_owned()));
+        assert!(!equivalent.contains(&different.build_root().to_owned()));
+    }
+
+    #[test]
+    fn checkout_recorded_source_revision() {
+        let mut build = test_build("checkout");
+        let source = build.workspace_root().join("projects").join("sel4test");
+        create_dir_all(&source).unwrap();
+        write(source.join("easy-settings.cmake"), "").unwrap();
+        std::os::unix::fs::symlink(
+            "projects/sel4test/easy-settings.cmake",
+            build.workspace_root().join(Workspace::EASY_SETTINGS),
+        )
+        .unwrap();
+        let defaults = Defaults::default();
+        let apps = Apps::mock(&defaults, crate::Docker);
+
+        let config = Config::builtin().unwrap();
+        assert!(build.checkout_command(&apps, &config).unwrap().is_none());
+
+        build.set_source_revision(Some("12.0.0".to_owned()));
+        build.save().unwrap();
+        let workspace = WorkspaceContext::load(build.workspace_root()).unwrap();
+        let build = BuildContext::load(&workspace, build.build_root()).unwrap();
+        assert_eq!(build.source_revision(), Some("12.0.0"));
+
+        let command = build.checkout_command(&apps, &config).unwrap().unwrap();
+        assert_eq!(command.get_program(), apps.repo_path());
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "forall",
+                "projects/sel4test",
+                "-c",
+                "git",
+                "checkout",
+                "12.0.0"
+            ]
+        );
+        assert_eq!(command.get_current_dir(), Some(build.workspace_root()));
+    }
+
+    #[test]
+    fn export_and_import_workspace() {
+        let root = test_dir("export");
+        let config = Config::builtin().unwrap();
+        WorkspaceContext::create("sel4test".into(), &root, true, false).unwrap();
+        for (name, flag) in &[("mcs", "mcs"), ("smp", "smp")] {
+            let mut setting = Setting::default();
+            setting.set_bool(*flag, true);
+            BuildContext::create(
+                &config,
+                &WorkspaceContext::load(&root).unwrap(),
+                "odroidc2".into(),
+                None,
+                crate::AArch64,
+                setting,
+                root.join(name),
+                false,
+            )
+            .unwrap();
+        }
+        let workspace = WorkspaceContext::load(&root).unwrap();
+
+        let export = workspace.export().unwrap();
+        let export: WorkspaceExport = toml::from_str(&toml::to_string(&export).unwrap()).unwrap();
+        assert_eq!(export.builds.len(), 2);
+
+        let imported_root = test_dir("import");
+        std::fs::remove_dir(&imported_root).unwrap();
+        let imported = WorkspaceContext::import(&config, &export, &imported_root).unwrap();
+        assert_eq!(imported.project(), workspace.project());
+
+        let original = workspace.builds().collect::<Result<Vec<_>>>().unwrap();
+        let imported = WorkspaceContext::load(&imported_root)
+            .unwrap()
+            .builds()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let builds = |contexts: Vec<BuildContext>| {
+            contexts
+                .into_iter()
+                .map(|context| context.build)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(builds(imported), builds(original));
+    }
+
+    #[test]
+    fn import_rejects_unsafe_or_invalid_builds() {
+        let config = Config::builtin().unwrap();
+        let export: WorkspaceExport = toml::from_str(
+            r#"
+            project = "sel4test"
+
+            [[build]]
+            build-path = "build"
+            build-platform = "odroidc2"
+            build-architecture = "aarch64"
+            "#,
+        )
+        .unwrap();
+        export.validate(&config).unwrap();
+
+        let rejected = |change: &dyn Fn(&mut BuildExport)| {
+            let mut export = export.clone();
+            change(&mut export.builds[0]);
+            let root = test_dir("import-rejected");
+            std::fs::remove_dir(&root).unwrap();
+            let error = WorkspaceContext::import(&config, &export, &root).unwrap_err();
+            assert!(!root.exists());
+            error.to_string()
+        };
+
+        let error = rejected(&|build| build.path = "../escape".into());
+        assert!(error.contains("inside the workspace"), "{}", error);
+        let error = rejected(&|build| build.path = "/tmp/escape".into());
+        assert!(error.contains("inside the workspace"), "{}", error);
+        let error = rejected(&|build| build.platform = "odroidc3".into());
+        assert!(error.contains("odroidc3"), "{}", error);
+        let error = rejected(&|build| build.variation = Some("smp64".into()));
+        assert!(error.contains("smp64"), "{}", error);
+        let error = rejected(&|build| build.setting.set_text("riscv-code-model", "large"));
+        assert!(error.contains("riscv-code-model"), "{}", error);
+    }
+
+    #[test]
+    fn images_in_configured_directory() {
+        let build = test_build("images-dir");
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [project.sel4test]
+                repository = "seL4/sel4test-manifest"
+                command-line = []
+                images-directory = "out/images"
+                "#,
+            )
+            .unwrap(),
+        );
+        let project = config.project(build.project());
+        let images_dir = project.images_dir();
+        assert_eq!(images_dir, Path::new("out/images"));
+
+        let images = build.build_root().join("out").join("images");
+        create_dir_all(&images).unwrap();
+        write(images.join("sel4test-driver-image-arm-odroidc2"), "").unwrap();
+
+        assert_eq!(
+            build
+                .inferred_root_server(images_dir, project.image_name_pattern())
+                .unwrap(),
+            "sel4test-driver"
+        );
+        assert_eq!(
+            build
+                .image_path(images_dir, project.image_name_pattern(), "sel4test-driver")
+                .unwrap(),
+            Path::new("out/images/sel4test-driver-image-arm-odroidc2")
+        );
+        assert!(build
+            .inferred_root_server(Project::IMAGES_DIR, project.image_name_pattern())
+            .is_err());
+    }
+
+    #[test]
+    fn custom_image_name_pattern() {
+        let build = test_build("image-pattern");
+        let mut config = Config::builtin().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [project.sel4test]
+                repository = "seL4/sel4test-manifest"
+                command-line = []
+                image-name-pattern = "{plat}.{rootserver}.elf"
+                "#,
+            )
+            .unwrap(),
+        );
+        let project = config.project(build.project());
+        let pattern = project.image_name_pattern();
+
+        let images = build.build_root().join(Project::IMAGES_DIR);
+        create_dir_all(&images).unwrap();
+        write(images.join("sel4test-driver-image-arm-odroidc2"), "").unwrap();
+        write(images.join("arm-odroidc2.sel4bench.elf"), "").unwrap();
+
+        assert_eq!(
+            build
+                .inferred_root_server(Project::IMAGES_DIR, pattern)
+                .unwrap(),
+            "sel4bench"
+        );
+        assert_eq!(
+            build
+                .image_path(Project::IMAGES_DIR, pattern, "sel4bench")
+                .unwrap(),
+            Path::new("images/arm-odroidc2.sel4bench.elf")
+        );
+        assert!(build
+            .inferred_root_server(Project::IMAGES_DIR, "{plat}.elf")
+            .is_err());
+    }
+
+    #[test]
+    fn ninja_targets_output() {
+        let output = "\
+            kernel.elf: phony\n\
+            images/sel4test-driver-image-arm-odroidc2: CUSTOM_COMMAND\n\
+            all: phony\n\
+            clean: CLEAN\n";
+        assert_eq!(
+            parse_ninja_targets(output),
+            [
+                "kernel.elf",
+                "images/sel4test-driver-image-arm-odroidc2",
+                "all",
+                "clean"
+            ]
+        );
+
+        assert!(ninja_has_no_work("ninja: no work to do.\n"));
+        assert!(!ninja_has_no_work(
+            "[1/2] Building C object kernel.c.obj\n[2/2] Linking kernel.elf\n"
+        ));
+    }
 }